@@ -0,0 +1,79 @@
+//! Encoding a broadcast for its recipients, once per recipient as before
+//! `Frame` and once per broadcast with it. Run with `cargo bench --bench wire`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use chat_engine::api::chat::wire::{Frame, WireFormat};
+use serde_json::{json, Value};
+use warp::filters::ws::Message;
+
+const RECIPIENTS: [usize; 3] = [10, 100, 1000];
+const BROADCASTS: usize = 200;
+
+fn message() -> Value {
+    json!({
+        "type": "MESSAGE",
+        "message": "The deploy of the release candidate is done, please check the dashboards and report anything unusual in this room.",
+        "sender": "5b0a3c5e-8f0e-4c0e-9b7a-0c1d2e3f4a5b",
+        "room": "8f7c2b7e-3d2a-4f5e-9a61-0c6b1d2e3f40",
+        "id": "0e6f7a8b-9c0d-4e1f-8a2b-3c4d5e6f7a8b",
+        "seq": 4242,
+        "timestamp": 1_760_000_000_000u64,
+        "reactions": {
+            "+1": [
+                "1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d",
+                "2b3c4d5e-6f7a-4b8c-9d0e-1f2a3b4c5d6e"
+            ]
+        }
+    })
+}
+
+/// What every recipient did before frames were shared.
+fn encode_per_recipient(value: &Value, format: WireFormat) -> Message {
+    match format {
+        WireFormat::Json => Message::text(value.to_string()),
+        WireFormat::MessagePack => Message::binary(rmp_serde::to_vec_named(value).unwrap()),
+    }
+}
+
+fn measure(mut broadcast: impl FnMut()) -> Duration {
+    // Warm up allocator and caches
+    broadcast();
+    let start = Instant::now();
+    for _ in 0..BROADCASTS {
+        broadcast();
+    }
+    start.elapsed() / BROADCASTS as u32
+}
+
+fn main() {
+    let value = message();
+    for format in [WireFormat::Json, WireFormat::MessagePack] {
+        for recipients in RECIPIENTS {
+            let before = measure(|| {
+                for _ in 0..recipients {
+                    black_box(encode_per_recipient(black_box(&value), format));
+                }
+            });
+            let after = measure(|| {
+                let frame = Frame::new(value.clone());
+                for _ in 0..recipients {
+                    black_box(frame.to_message(format).unwrap());
+                }
+            });
+            println!(
+                "{:<12} {:>5} recipients  per recipient: {:>8.0?} -> {:>8.0?} ({:.1}x), broadcasts/s: {:>8.0} -> {:>8.0}",
+                format.protocol(),
+                recipients,
+                before / recipients as u32,
+                after / recipients as u32,
+                before.as_secs_f64() / after.as_secs_f64(),
+                1.0 / before.as_secs_f64(),
+                1.0 / after.as_secs_f64(),
+            );
+        }
+    }
+}
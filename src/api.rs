@@ -1,2 +1,42 @@
+use serde_json::json;
+use warp::{
+    http::StatusCode,
+    reply::{json, with_status, Reply, Response},
+};
+
+use self::chat::room::RoomError;
+
+pub mod admin;
+pub mod admission;
 pub mod chat;
+pub mod events;
+pub mod origin;
+pub mod upload;
+pub mod webhook;
 pub mod websocket;
+
+pub(crate) fn room_error_reply(error: RoomError) -> Response {
+    let status = match error {
+        RoomError::NotFound
+        | RoomError::AttachmentNotFound
+        | RoomError::MessageNotFound
+        | RoomError::ReportNotFound => StatusCode::NOT_FOUND,
+        RoomError::NotMember
+        | RoomError::Forbidden
+        | RoomError::TooManyRooms
+        | RoomError::PasswordRequired
+        | RoomError::InvalidPassword
+        | RoomError::Banned => StatusCode::FORBIDDEN,
+        RoomError::PasswordLocked | RoomError::TooManyReports => StatusCode::TOO_MANY_REQUESTS,
+        RoomError::Full => StatusCode::CONFLICT,
+        RoomError::Closed => StatusCode::GONE,
+        RoomError::InvalidRequest | RoomError::UnknownCommand => StatusCode::BAD_REQUEST,
+        RoomError::MessageRejected => StatusCode::UNPROCESSABLE_ENTITY,
+        RoomError::StorageFailure => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_reply(error.code(), status)
+}
+
+pub(crate) fn error_reply(error: &str, status: StatusCode) -> Response {
+    with_status(json(&json!({ "error": error })), status).into_response()
+}
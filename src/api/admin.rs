@@ -0,0 +1,394 @@
+use std::{
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use warp::{
+    http::{Method, StatusCode},
+    path::FullPath,
+    reject::{self, Reject, Rejection},
+    reply::{json, with_status, Reply, Response},
+    Filter,
+};
+
+use super::{
+    chat::{
+        audit::{AuditEntry, AuditQuery, ADMIN_ACTOR},
+        report::{ReportQuery, ReportResolution},
+        room::RoomError,
+        search::SearchQuery,
+        webhook::{IncomingWebhook, OutgoingWebhook},
+        ChatManager,
+    },
+    error_reply, room_error_reply,
+};
+
+const UNAUTHORIZED_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+/// Unauthorized requests are recorded at most once per
+/// `UNAUTHORIZED_INTERVAL`, so that they cannot flood the audit log.
+#[derive(Default)]
+struct UnauthorizedRequests {
+    // When the last one was recorded, and how many were left out since
+    state: StdMutex<(Option<Instant>, u64)>,
+}
+
+impl UnauthorizedRequests {
+    /// The number of requests left out since the previous recorded one when
+    /// this one is to be recorded.
+    fn record(&self) -> Option<u64> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (recorded_at, left_out) = &mut *state;
+        if recorded_at.is_some_and(|recorded_at| recorded_at.elapsed() < UNAUTHORIZED_INTERVAL) {
+            *left_out += 1;
+            return None;
+        }
+        *recorded_at = Some(Instant::now());
+        Some(std::mem::take(left_out))
+    }
+}
+
+/// Compares digests of both values, so that the time taken depends neither
+/// on where they differ nor on the length of the token.
+fn token_matches(token: &str, authorization: &str) -> bool {
+    let token = Sha256::digest(token.as_bytes());
+    let authorization = Sha256::digest(authorization.as_bytes());
+    token
+        .iter()
+        .zip(authorization.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// Routes under `/admin`, all of them requiring `Authorization: Bearer <token>`.
+/// Every request is recorded in the audit log, unauthorized ones once a
+/// minute with the number of those left out.
+pub fn admin_filter(
+    chat_manager: Arc<ChatManager>,
+    token: Option<String>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let audit_manager = Arc::clone(&chat_manager);
+    let request_manager = Arc::clone(&chat_manager);
+    let token = Arc::new(token.map(|token| format!("Bearer {}", token)));
+    let unauthorized = Arc::new(UnauthorizedRequests::default());
+    let authorized = warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |method: Method, path: FullPath, authorization: Option<String>| {
+                let token = Arc::clone(&token);
+                let unauthorized = Arc::clone(&unauthorized);
+                let chat_manager = Arc::clone(&audit_manager);
+                async move {
+                    match (token.as_deref(), authorization) {
+                        (Some(token), Some(authorization))
+                            if token_matches(token, &authorization) =>
+                        {
+                            Ok(())
+                        }
+                        // Nothing to protect when the admin api is disabled
+                        (None, _) => Err(reject::custom(Unauthorized)),
+                        (Some(_), authorization) => {
+                            if let Some(left_out) = unauthorized.record() {
+                                println!(
+                                    "admin api unauthorized request (authorization header: {}, {} more not logged)",
+                                    authorization.is_some(),
+                                    left_out
+                                );
+                                let mut entry = AuditEntry::new(
+                                    ADMIN_ACTOR.to_string(),
+                                    "ADMIN_UNAUTHORIZED",
+                                    None,
+                                    Some(format!("{} {}", method, path.as_str())),
+                                );
+                                entry.details = json!({ "left_out": left_out });
+                                chat_manager.audit(entry);
+                            }
+                            Err(reject::custom(Unauthorized))
+                        }
+                    }
+                }
+            },
+        )
+        .untuple_one();
+    let admin = warp::any().map(move || Arc::clone(&chat_manager));
+
+    let search = admin
+        .clone()
+        .and(warp::path("search"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<SearchQuery>())
+        .then(search);
+
+    let clients = admin
+        .clone()
+        .and(warp::path("clients"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .then(clients_list);
+
+    let moderation = admin
+        .clone()
+        .and(warp::path("moderation"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .then(moderation_list);
+
+    let reports = admin
+        .clone()
+        .and(warp::path("reports"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<ReportQuery>())
+        .then(reports_list);
+    let report_resolve = admin
+        .clone()
+        .and(warp::path!("reports" / Uuid))
+        .and(warp::post())
+        .and(warp::body::json())
+        .then(report_resolve);
+
+    let audit = admin
+        .clone()
+        .and(warp::path("audit"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<AuditQuery>())
+        .then(audit_list);
+
+    let webhooks = admin
+        .clone()
+        .and(warp::path("webhooks"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .then(webhooks_list);
+    let outgoing_add = admin
+        .clone()
+        .and(warp::path!("webhooks" / "outgoing"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .then(outgoing_add);
+    let outgoing_remove = admin
+        .clone()
+        .and(warp::path!("webhooks" / "outgoing" / Uuid))
+        .and(warp::delete())
+        .then(outgoing_remove);
+    let incoming_add = admin
+        .clone()
+        .and(warp::path!("webhooks" / "incoming"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .then(incoming_add);
+    let incoming_remove = admin
+        .and(warp::path!("webhooks" / "incoming" / String))
+        .and(warp::delete())
+        .then(incoming_remove);
+
+    let routes = search
+        .or(clients)
+        .unify()
+        .or(moderation)
+        .unify()
+        .or(reports)
+        .unify()
+        .or(report_resolve)
+        .unify()
+        .or(audit)
+        .unify()
+        .or(webhooks)
+        .unify()
+        .or(outgoing_add)
+        .unify()
+        .or(outgoing_remove)
+        .unify()
+        .or(incoming_add)
+        .unify()
+        .or(incoming_remove)
+        .unify();
+
+    // Only wraps authorized requests, the log also sees rejections which
+    // would include every request falling through to the next routes
+    let audit_log = warp::log::custom(move |info| {
+        let mut entry = AuditEntry::new(
+            ADMIN_ACTOR.to_string(),
+            "ADMIN_API",
+            None,
+            Some(format!("{} {}", info.method(), info.path())),
+        );
+        entry.details = json!({ "status": info.status().as_u16() });
+        request_manager.audit(entry);
+    });
+    warp::path("admin")
+        .and(authorized)
+        .and(routes.with(audit_log))
+}
+
+async fn search(chat_manager: Arc<ChatManager>, query: SearchQuery) -> Response {
+    let results = chat_manager.search(&query).await;
+    json(&json!({ "results": results })).into_response()
+}
+
+async fn clients_list(chat_manager: Arc<ChatManager>) -> Response {
+    let clients = chat_manager.get_clients_stats().await;
+    json(&json!({ "clients": clients })).into_response()
+}
+
+async fn moderation_list(chat_manager: Arc<ChatManager>) -> Response {
+    let records = chat_manager.get_moderation_records().await;
+    json(&json!({ "records": records })).into_response()
+}
+
+async fn reports_list(chat_manager: Arc<ChatManager>, query: ReportQuery) -> Response {
+    let reports = chat_manager.get_reports(query.status).await;
+    json(&json!({ "reports": reports })).into_response()
+}
+
+async fn report_resolve(
+    chat_manager: Arc<ChatManager>,
+    id: Uuid,
+    resolution: ReportResolution,
+) -> Response {
+    match chat_manager
+        .resolve_report(&id, resolution.action, resolution.note)
+        .await
+    {
+        Ok(report) => {
+            println!(
+                "admin api closed report {} with {:?}",
+                id, resolution.action
+            );
+            json(&report).into_response()
+        }
+        Err(e) => room_error_reply(e),
+    }
+}
+
+async fn audit_list(chat_manager: Arc<ChatManager>, query: AuditQuery) -> Response {
+    match chat_manager.query_audit(&query).await {
+        Ok(entries) => json(&json!({ "entries": entries })).into_response(),
+        Err(e) => {
+            println!("admin api audit query error {}", e);
+            room_error_reply(RoomError::StorageFailure)
+        }
+    }
+}
+
+async fn webhooks_list(chat_manager: Arc<ChatManager>) -> Response {
+    let webhooks = chat_manager.get_webhooks();
+    let outgoing: Vec<_> = webhooks
+        .get_outgoing_list()
+        .await
+        .iter()
+        .map(|webhook| webhook.to_value())
+        .collect();
+    let incoming: Vec<_> = webhooks
+        .get_incoming_list()
+        .await
+        .iter()
+        .map(|webhook| webhook.to_value())
+        .collect();
+    json(&json!({ "outgoing": outgoing, "incoming": incoming })).into_response()
+}
+
+async fn outgoing_add(chat_manager: Arc<ChatManager>, webhook: OutgoingWebhook) -> Response {
+    println!("admin api added outgoing webhook {}", webhook.id);
+    let value = webhook.to_value();
+    chat_manager.get_webhooks().add_outgoing(webhook).await;
+    with_status(json(&value), StatusCode::CREATED).into_response()
+}
+
+async fn outgoing_remove(chat_manager: Arc<ChatManager>, id: Uuid) -> Response {
+    if chat_manager.get_webhooks().remove_outgoing(&id).await {
+        println!("admin api removed outgoing webhook {}", id);
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        error_reply("WEBHOOK_NOT_FOUND", StatusCode::NOT_FOUND)
+    }
+}
+
+async fn incoming_add(chat_manager: Arc<ChatManager>, webhook: IncomingWebhook) -> Response {
+    if chat_manager.get_room(&webhook.room_id).await.is_none() {
+        return room_error_reply(RoomError::NotFound);
+    }
+    println!(
+        "admin api added incoming webhook {} for room {}",
+        webhook.name, webhook.room_id
+    );
+    let value = webhook.to_value();
+    chat_manager.get_webhooks().add_incoming(webhook).await;
+    with_status(json(&value), StatusCode::CREATED).into_response()
+}
+
+async fn incoming_remove(chat_manager: Arc<ChatManager>, token: String) -> Response {
+    if chat_manager.get_webhooks().remove_incoming(&token).await {
+        println!("admin api removed incoming webhook");
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        error_reply("WEBHOOK_NOT_FOUND", StatusCode::NOT_FOUND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::config::ChatConfig;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn audit_requires_the_admin_token() {
+        let manager = Arc::new(ChatManager::new(ChatConfig::default()).await);
+        let filter = admin_filter(Arc::clone(&manager), Some("secret".to_string()));
+        for authorization in [None, Some("Bearer wrong"), Some("secret")] {
+            let mut request = warp::test::request().path("/admin/audit");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let rejection = request.filter(&filter).await.err().expect("rejected");
+            assert!(rejection.find::<Unauthorized>().is_some());
+        }
+        // Without a token the api is disabled
+        let disabled = admin_filter(Arc::clone(&manager), None);
+        let rejection = warp::test::request()
+            .path("/admin/audit")
+            .header("authorization", "Bearer secret")
+            .filter(&disabled)
+            .await
+            .err()
+            .expect("rejected");
+        assert!(rejection.find::<Unauthorized>().is_some());
+
+        manager.audit(AuditEntry::new(
+            "alice".to_string(),
+            "ROOM_KICK",
+            None,
+            Some("bob".to_string()),
+        ));
+        for _ in 0..100 {
+            let response = warp::test::request()
+                .path("/admin/audit?action=ROOM_KICK")
+                .header("authorization", "Bearer secret")
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: Value = serde_json::from_slice(response.body()).unwrap();
+            if let Some(entry) = body["entries"].get(0) {
+                assert_eq!(entry["actor"], "alice");
+                assert_eq!(entry["target"], "bob");
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("entry not listed");
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use warp::{
+    http::StatusCode,
+    reject::{self, Reject, Rejection},
+    Filter,
+};
+
+use crate::config::ConnectionConfig;
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Peer address of a connection accepted outside of `warp::serve`, which
+/// `warp::addr::remote` cannot see.
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddr(pub SocketAddr);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionRejected {
+    ServerFull,
+    TooManyConnections,
+    RateLimited,
+}
+
+impl Reject for ConnectionRejected {}
+
+impl ConnectionRejected {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConnectionRejected::ServerFull => "SERVER_FULL",
+            ConnectionRejected::TooManyConnections => "TOO_MANY_CONNECTIONS",
+            ConnectionRejected::RateLimited => "RATE_LIMITED",
+        }
+    }
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ConnectionRejected::ServerFull => StatusCode::SERVICE_UNAVAILABLE,
+            ConnectionRejected::TooManyConnections | ConnectionRejected::RateLimited => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+        }
+    }
+}
+
+struct AdmissionState {
+    total: usize,
+    connections: HashMap<IpAddr, usize>,
+    /// Start of the current rate window and connections opened since
+    attempts: HashMap<IpAddr, (Instant, u32)>,
+    pruned: Instant,
+}
+
+/// Counts the WebSocket and event stream connections, globally and per IP
+/// address, and decides whether a new one is accepted.
+pub struct Admission {
+    config: ConnectionConfig,
+    state: StdMutex<AdmissionState>,
+}
+
+impl Admission {
+    pub fn new(config: ConnectionConfig) -> Self {
+        Self {
+            config,
+            state: StdMutex::new(AdmissionState {
+                total: 0,
+                connections: HashMap::new(),
+                attempts: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+    /// The client address, taken from `X-Forwarded-For` when the peer is a
+    /// trusted proxy: the rightmost address that is not a trusted proxy.
+    pub fn client_ip(
+        &self,
+        peer: Option<SocketAddr>,
+        forwarded_for: Option<&str>,
+    ) -> Option<IpAddr> {
+        let trusted = &self.config.trusted_proxies;
+        let mut ip = peer?.ip();
+        if !trusted.contains(&ip) {
+            return Some(ip);
+        }
+        if let Some(forwarded_for) = forwarded_for {
+            for entry in forwarded_for.rsplit(',') {
+                match entry.trim().parse::<IpAddr>() {
+                    Ok(address) => {
+                        ip = address;
+                        if !trusted.contains(&address) {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+        Some(ip)
+    }
+    /// Admits a connection from `ip`, which is counted until the returned
+    /// permit is dropped. Connections with an unknown address are only
+    /// subject to the global limit.
+    pub fn admit(
+        admission: &Arc<Admission>,
+        ip: Option<IpAddr>,
+    ) -> Result<ConnectionPermit, ConnectionRejected> {
+        let config = &admission.config;
+        let mut state = admission
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = Instant::now();
+        if now.duration_since(state.pruned) >= RATE_WINDOW {
+            state
+                .attempts
+                .retain(|_, (start, _)| now.duration_since(*start) < RATE_WINDOW);
+            state.pruned = now;
+        }
+        if let Some(ip) = ip {
+            if config.rate_per_minute > 0 {
+                let (start, count) = state.attempts.entry(ip).or_insert((now, 0));
+                if now.duration_since(*start) >= RATE_WINDOW {
+                    *start = now;
+                    *count = 0;
+                }
+                *count += 1;
+                if *count > config.rate_per_minute {
+                    return Err(ConnectionRejected::RateLimited);
+                }
+            }
+            if config.max_per_ip > 0
+                && state.connections.get(&ip).copied().unwrap_or(0) >= config.max_per_ip
+            {
+                return Err(ConnectionRejected::TooManyConnections);
+            }
+        }
+        if config.max_connections > 0 && state.total >= config.max_connections {
+            return Err(ConnectionRejected::ServerFull);
+        }
+        state.total += 1;
+        if let Some(ip) = ip {
+            *state.connections.entry(ip).or_insert(0) += 1;
+        }
+        Ok(ConnectionPermit {
+            admission: Arc::clone(admission),
+            ip,
+        })
+    }
+    fn release(&self, ip: Option<IpAddr>) {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.total = state.total.saturating_sub(1);
+        if let Some(ip) = ip {
+            if let Some(count) = state.connections.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    state.connections.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// An admitted connection, released when dropped.
+pub struct ConnectionPermit {
+    admission: Arc<Admission>,
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionPermit {
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.admission.release(self.ip);
+    }
+}
+
+/// Admits the connection or rejects the request before it is upgraded.
+pub fn admission_filter(
+    admission: Arc<Admission>,
+) -> impl Filter<Extract = (ConnectionPermit,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<RemoteAddr>())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and_then(
+            move |remote: Option<SocketAddr>,
+                  accepted: Option<RemoteAddr>,
+                  forwarded_for: Option<String>| {
+                let admission = Arc::clone(&admission);
+                async move {
+                    let peer = remote.or(accepted.map(|RemoteAddr(address)| address));
+                    let ip = admission.client_ip(peer, forwarded_for.as_deref());
+                    Admission::admit(&admission, ip).map_err(|rejected| {
+                        match ip {
+                            Some(ip) => println!("{} connection rejected {}", ip, rejected.code()),
+                            None => println!("connection rejected {}", rejected.code()),
+                        }
+                        reject::custom(rejected)
+                    })
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: &str = "10.0.0.1";
+
+    fn admission(config: ConnectionConfig) -> Arc<Admission> {
+        Arc::new(Admission::new(ConnectionConfig {
+            trusted_proxies: vec![PROXY.parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            ..config
+        }))
+    }
+
+    fn peer(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 40000))
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    fn assert_rejected(
+        result: Result<ConnectionPermit, ConnectionRejected>,
+        expected: ConnectionRejected,
+    ) {
+        match result {
+            Err(rejected) => assert_eq!(rejected, expected),
+            Ok(_) => panic!("admitted, expected {}", expected.code()),
+        }
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_forward_an_address() {
+        let admission = admission(ConnectionConfig::default());
+        assert_eq!(
+            admission.client_ip(peer("203.0.113.9"), Some("198.51.100.1")),
+            ip("203.0.113.9")
+        );
+        assert_eq!(admission.client_ip(None, Some("198.51.100.1")), None);
+    }
+
+    #[test]
+    fn the_rightmost_untrusted_hop_is_the_client() {
+        let admission = admission(ConnectionConfig::default());
+        // The client prepended a spoofed address of its own
+        assert_eq!(
+            admission.client_ip(peer(PROXY), Some("1.2.3.4, 198.51.100.1, 10.0.0.2")),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            admission.client_ip(peer(PROXY), Some("10.0.0.2")),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            admission.client_ip(peer(PROXY), Some("1.2.3.4, garbage")),
+            ip(PROXY)
+        );
+        assert_eq!(admission.client_ip(peer(PROXY), None), ip(PROXY));
+    }
+
+    #[test]
+    fn dropping_permits_releases_connections() {
+        let admission = admission(ConnectionConfig {
+            max_connections: 2,
+            max_per_ip: 1,
+            rate_per_minute: 0,
+            ..Default::default()
+        });
+        let first = Admission::admit(&admission, ip("198.51.100.1")).unwrap();
+        assert_eq!(first.ip(), ip("198.51.100.1"));
+        assert_rejected(
+            Admission::admit(&admission, ip("198.51.100.1")),
+            ConnectionRejected::TooManyConnections,
+        );
+        let second = Admission::admit(&admission, None).unwrap();
+        assert_rejected(
+            Admission::admit(&admission, ip("198.51.100.2")),
+            ConnectionRejected::ServerFull,
+        );
+
+        drop(first);
+        let first = Admission::admit(&admission, ip("198.51.100.1")).unwrap();
+        drop(second);
+        drop(first);
+        let state = admission.state.lock().unwrap();
+        assert_eq!(state.total, 0);
+        assert!(state.connections.is_empty());
+    }
+
+    #[test]
+    fn connection_attempts_are_rate_limited() {
+        let admission = admission(ConnectionConfig {
+            rate_per_minute: 2,
+            ..Default::default()
+        });
+        for _ in 0..2 {
+            Admission::admit(&admission, ip("198.51.100.1")).unwrap();
+        }
+        assert_rejected(
+            Admission::admit(&admission, ip("198.51.100.1")),
+            ConnectionRejected::RateLimited,
+        );
+        assert!(Admission::admit(&admission, ip("198.51.100.2")).is_ok());
+    }
+}
@@ -1,29 +1,376 @@
-use std::sync::Arc;
+use std::{io, net::IpAddr, sync::Arc};
 
-use futures_util::{future::join_all, stream::SplitSink};
+use serde_json::{json, Value};
+
+use futures_util::future::join_all;
+use tokio::sync::mpsc;
 use uuid::Uuid;
-use warp::filters::ws::{Message, WebSocket};
 
-use self::{client::WebSocketClient, engine::ChatEngine, room::WebSocketRoom};
+use crate::config::ChatConfig;
+
+use self::{
+    attachment::{Attachment, BlobStore},
+    audit::{AuditEntry, AuditQuery, AuditStore, ADMIN_ACTOR},
+    backplane::Backplane,
+    client::{ClientSender, WebSocketClient},
+    command::CommandHandler,
+    engine::ChatEngine,
+    filter::{MessageFilter, ModerationRecord},
+    report::{Report, ReportAction, ReportStatus},
+    room::{RoomError, RoomOptions, WebSocketRoom},
+    search::SearchQuery,
+    snapshot::SnapshotStore,
+    webhook::WebhookDispatcher,
+    wire::Frame,
+};
 
+pub mod attachment;
+pub mod audit;
+pub mod backplane;
 pub mod client;
+pub mod command;
 mod engine;
+pub mod filter;
+pub mod message;
+mod password;
+pub mod queue;
+pub mod report;
 pub mod room;
+pub mod search;
+pub mod snapshot;
+mod token;
+pub mod webhook;
+pub mod wire;
 
 pub struct ChatManager {
     engine: Arc<ChatEngine>,
 }
 impl ChatManager {
+    pub async fn new(config: ChatConfig) -> Self {
+        Self::with_engine(ChatEngine::new(config)).await
+    }
+    pub async fn with_blob_store(config: ChatConfig, blob_store: Arc<dyn BlobStore>) -> Self {
+        Self::with_engine(ChatEngine::with_blob_store(config, blob_store)).await
+    }
+    /// Shares rooms with the other engines connected to `backplane`.
+    pub async fn with_backplane(config: ChatConfig, backplane: Arc<dyn Backplane>) -> Self {
+        let mut engine = ChatEngine::new(config);
+        engine.set_backplane(backplane);
+        Self::with_engine(engine).await
+    }
+    /// Restores rooms from `snapshot_store` at startup and saves them to it.
+    pub async fn with_snapshot_store(
+        config: ChatConfig,
+        snapshot_store: Arc<dyn SnapshotStore>,
+    ) -> Self {
+        let mut engine = ChatEngine::new(config);
+        engine.set_snapshot_store(snapshot_store);
+        Self::with_engine(engine).await
+    }
+    /// Records the audit log to `audit_store` in place of the configured store.
+    pub async fn with_audit_store(config: ChatConfig, audit_store: Arc<dyn AuditStore>) -> Self {
+        let mut engine = ChatEngine::new(config);
+        engine.set_audit_store(audit_store);
+        Self::with_engine(engine).await
+    }
+    async fn with_engine(engine: ChatEngine) -> Self {
+        let engine = Arc::new(engine);
+        engine.get_audit().start();
+        ChatEngine::start_backplane(&engine);
+        let grace = engine.get_config().snapshot.restore_grace();
+        let mut restored = match engine.load_snapshot().await {
+            Some(snapshot) => snapshot.rooms,
+            None => Vec::new(),
+        };
+        for room in engine.get_config().rooms.clone() {
+            // Persistent rooms come from the configuration, the snapshot only
+            // keeps their id when none is configured, their topic and roles
+            let snapshot = restored
+                .iter()
+                .position(|snapshot| {
+                    snapshot.persistent
+                        && match room.id {
+                            Some(id) => snapshot.id == id,
+                            None => snapshot.name.as_deref() == Some(room.name.as_str()),
+                        }
+                })
+                .map(|index| restored.remove(index));
+            let options = RoomOptions {
+                id: room.id.or(snapshot.as_ref().map(|snapshot| snapshot.id)),
+                name: Some(room.name),
+                persistent: true,
+                read_receipts: room.read_receipts,
+                max_members: room.max_members,
+                password: None,
+            };
+            let room = WebSocketRoom::create_room(&engine, &Uuid::nil(), options).await;
+            if let Some(snapshot) = snapshot {
+                room.restore(&snapshot, grace).await;
+            }
+            println!(
+                "{} created persistent room {}",
+                room.get_id(),
+                room.get_name().unwrap_or_default()
+            );
+        }
+        for snapshot in restored.into_iter().filter(|snapshot| !snapshot.persistent) {
+            let options = RoomOptions {
+                id: Some(snapshot.id),
+                name: snapshot.name.clone(),
+                persistent: false,
+                read_receipts: snapshot.read_receipts,
+                max_members: snapshot.max_members,
+                password: snapshot.password.clone(),
+            };
+            let room = WebSocketRoom::create_room(&engine, &snapshot.creator, options).await;
+            room.restore(&snapshot, grace).await;
+            println!("{} restored room from snapshot", room.get_id());
+        }
+        ChatEngine::start_snapshots(&engine);
+        ChatManager { engine }
+    }
+    /// Saves every room to the snapshot store, if one is configured.
+    pub async fn save_snapshot(&self) {
+        self.engine.save_snapshot().await;
+    }
+    pub fn get_config(&self) -> &ChatConfig {
+        self.engine.get_config()
+    }
+    pub async fn get_room(&self, room_id: &Uuid) -> Option<Arc<WebSocketRoom>> {
+        self.engine.get_room(room_id).await
+    }
+    pub fn get_webhooks(&self) -> &WebhookDispatcher {
+        self.engine.get_webhooks()
+    }
+    /// Connected clients with the state of their send queue.
+    pub async fn get_clients_stats(&self) -> Vec<Value> {
+        let mut stats = Vec::new();
+        for client in self.engine.get_clients_list().await {
+            stats.push(json!({
+                "client_id": client.get_id().to_string(),
+                "rooms": client.get_client_rooms().await.len(),
+                "queue": client.get_queue_stats()
+            }));
+        }
+        stats
+    }
+    /// Adds a `/name` command available in every room, replacing a built-in
+    /// command with the same name.
+    pub async fn register_command(&self, handler: Arc<dyn CommandHandler>) {
+        self.engine.get_commands().register(handler).await;
+    }
+    /// Adds a filter run on every message after the configured ones.
+    pub async fn add_message_filter(&self, filter: Arc<dyn MessageFilter>) {
+        self.engine.get_filters().add(filter).await;
+    }
+    /// Messages rejected, redacted or flagged by a filter, most recent first.
+    pub async fn get_moderation_records(&self) -> Vec<ModerationRecord> {
+        self.engine.get_filters().get_records().await
+    }
+    /// Adds an entry to the audit log, without waiting for it to be stored.
+    pub fn audit(&self, entry: AuditEntry) {
+        self.engine.audit(entry);
+    }
+    /// Audit log entries matching `query`, most recent first.
+    pub async fn query_audit(&self, query: &AuditQuery) -> io::Result<Vec<AuditEntry>> {
+        self.engine.get_audit().query(query).await
+    }
+    /// Reports with the given status, all of them when unset.
+    pub async fn get_reports(&self, status: Option<ReportStatus>) -> Vec<Report> {
+        self.engine.get_reports(status).await
+    }
+    /// Closes an open report after applying `action` to its room, the room
+    /// moderators are told about it.
+    pub async fn resolve_report(
+        &self,
+        report_id: &Uuid,
+        action: ReportAction,
+        note: Option<String>,
+    ) -> Result<Report, RoomError> {
+        let report = self
+            .engine
+            .get_report(report_id)
+            .await
+            .ok_or(RoomError::ReportNotFound)?;
+        if report.status != ReportStatus::Open {
+            return Err(RoomError::InvalidRequest);
+        }
+        let room = self.engine.get_room(&report.room_id).await;
+        match action {
+            ReportAction::Dismiss | ReportAction::Resolve => {}
+            ReportAction::DeleteMessage => {
+                let message_id = report.message_id.ok_or(RoomError::InvalidRequest)?;
+                room.as_ref()
+                    .ok_or(RoomError::NotFound)?
+                    .delete_message(&message_id, None)
+                    .await?;
+            }
+            ReportAction::Ban => {
+                let client_id = report.client_id.ok_or(RoomError::InvalidRequest)?;
+                room.as_ref()
+                    .ok_or(RoomError::NotFound)?
+                    .ban(&client_id, None)
+                    .await;
+            }
+        }
+        let report = self
+            .engine
+            .close_report(report_id, action, note)
+            .await
+            .ok_or(RoomError::ReportNotFound)?;
+        println!(
+            "{} report {} closed with {:?}",
+            report.room_id, report.id, action
+        );
+        let mut entry = AuditEntry::new(
+            ADMIN_ACTOR.to_string(),
+            "REPORT_RESOLVED",
+            Some(report.room_id),
+            Some(report.id.to_string()),
+        );
+        entry.details = json!({ "action": action, "note": report.note });
+        self.engine.audit(entry);
+        if let Some(room) = room {
+            room.send_to_moderators(json!({
+                "type": "EVENT",
+                "event": {
+                    "type": "REPORT_RESOLVED",
+                    "room_id": report.room_id.to_string(),
+                    "report": report
+                }
+            }))
+            .await;
+        }
+        Ok(report)
+    }
+    /// Posts `data` as a message from the incoming webhook registered with `token`.
+    pub async fn post_incoming_webhook(&self, token: &str, data: Value) -> Result<(), RoomError> {
+        let webhook = self
+            .engine
+            .get_webhooks()
+            .get_incoming(token)
+            .await
+            .ok_or(RoomError::NotFound)?;
+        if !data.is_object() {
+            return Err(RoomError::InvalidRequest);
+        }
+        let room = self
+            .engine
+            .get_room(&webhook.room_id)
+            .await
+            .ok_or(RoomError::NotFound)?;
+        let mut data = data;
+        data["type"] = json!("MESSAGE");
+        data["webhook"] = json!(webhook.name);
+        println!(
+            "{} incoming webhook {} message",
+            room.get_id(),
+            webhook.name
+        );
+        room.exec(&json!({ "action": "BROADCAST", "data": data }), None)
+            .await
+    }
+    /// `ip` is the address the client connected from, password attempts
+    /// are counted per address when it is known.
     pub async fn create_client(
         &self,
-        sender: SplitSink<WebSocket, Message>,
+        sender: ClientSender,
+        ip: Option<IpAddr>,
     ) -> Arc<WebSocketClient> {
-        ChatEngine::create_client(&self.engine, sender).await
+        ChatEngine::create_client(&self.engine, sender, ip).await
+    }
+    pub async fn create_session(
+        &self,
+        ip: Option<IpAddr>,
+    ) -> (Uuid, Arc<WebSocketClient>, mpsc::Receiver<Arc<Frame>>) {
+        ChatEngine::create_session(&self.engine, ip).await
+    }
+    pub async fn get_session_client(&self, session_id: &Uuid) -> Option<Arc<WebSocketClient>> {
+        self.engine.get_session_client(session_id).await
+    }
+    pub async fn remove_session(&self, session_id: &Uuid) {
+        self.engine.remove_session(session_id).await;
     }
     pub async fn remove_client(&self, client_id: &uuid::Uuid) {
         self.engine.remove_client(client_id).await;
     }
 
+    /// Secret sent to a client in its `CLIENT_JOIN` event, which HTTP routes
+    /// acting on its behalf require instead of its public id.
+    pub fn get_client_token(&self, client_id: &Uuid) -> String {
+        self.engine.get_tokens().issue(client_id)
+    }
+    /// The client a token from `get_client_token` was issued to.
+    pub fn verify_client_token(&self, token: &str) -> Option<Uuid> {
+        self.engine.get_tokens().verify(token)
+    }
+    /// Searches every room, access control is left to the caller.
+    pub async fn search(&self, query: &SearchQuery) -> Vec<Value> {
+        self.engine.search(query, None).await
+    }
+    pub async fn upload_attachment(
+        &self,
+        client_id: &Uuid,
+        room_id: &Uuid,
+        name: String,
+        content_type: String,
+        data: Vec<u8>,
+    ) -> Result<Attachment, RoomError> {
+        let room = self
+            .engine
+            .get_room(room_id)
+            .await
+            .ok_or(RoomError::NotFound)?;
+        if !room.has_client(client_id).await {
+            return Err(RoomError::NotMember);
+        }
+        let attachment = Attachment::new(room_id, client_id, name, content_type, &data);
+        self.engine
+            .get_blob_store()
+            .put(&attachment.id, data)
+            .await
+            .map_err(|e| {
+                println!("{} attachment store error {}", attachment.id, e);
+                RoomError::StorageFailure
+            })?;
+        println!(
+            "{} client {} uploaded attachment {} ({} bytes)",
+            room_id, client_id, attachment.id, attachment.size
+        );
+        self.engine.attachment_add(attachment.clone()).await;
+        Ok(attachment)
+    }
+    /// Attachments can only be downloaded by current members of their room.
+    pub async fn download_attachment(
+        &self,
+        client_id: &Uuid,
+        attachment_id: &Uuid,
+    ) -> Result<(Attachment, Vec<u8>), RoomError> {
+        let attachment = self
+            .engine
+            .get_attachment(attachment_id)
+            .await
+            .ok_or(RoomError::AttachmentNotFound)?;
+        let room = self
+            .engine
+            .get_room(&attachment.room_id)
+            .await
+            .ok_or(RoomError::AttachmentNotFound)?;
+        if !room.has_client(client_id).await {
+            return Err(RoomError::NotMember);
+        }
+        let data = self
+            .engine
+            .get_blob_store()
+            .get(attachment_id)
+            .await
+            .map_err(|e| {
+                println!("{} attachment read error {}", attachment_id, e);
+                RoomError::StorageFailure
+            })?;
+        Ok((attachment, data))
+    }
+
     pub async fn create_room(&self, clients: Vec<&Uuid>) -> Option<Arc<WebSocketRoom>> {
         let mut clients: Vec<Arc<WebSocketClient>> = join_all(
             clients
@@ -36,21 +383,39 @@ impl ChatManager {
         .collect();
 
         if let Some(creator) = clients.pop() {
-            let room = WebSocketRoom::create_room(&self.engine, creator.get_id()).await;
+            let room =
+                WebSocketRoom::create_room(&self.engine, creator.get_id(), RoomOptions::default())
+                    .await;
             println!("DEBUG created room {}", room.get_id());
-            creator.join_room(room.get_id()).await;
+            if let Err(error) = creator.join_room(room.get_id()).await {
+                println!(
+                    "{} creator client {} failed to join {}",
+                    room.get_id(),
+                    creator.get_id(),
+                    error.code()
+                );
+                room.close_if_unused().await;
+                return None;
+            }
             println!(
                 "DEBUG creator client {} joined room {}",
                 creator.get_id(),
                 room.get_id()
             );
             for client in clients {
-                client.join_room(room.get_id()).await;
-                println!(
-                    "DEBUG client {} joined room {}",
-                    client.get_id(),
-                    room.get_id()
-                );
+                match client.join_room(room.get_id()).await {
+                    Ok(()) => println!(
+                        "DEBUG client {} joined room {}",
+                        client.get_id(),
+                        room.get_id()
+                    ),
+                    Err(error) => println!(
+                        "{} client {} failed to join {}",
+                        room.get_id(),
+                        client.get_id(),
+                        error.code()
+                    ),
+                }
             }
             return Some(room);
         }
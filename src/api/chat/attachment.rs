@@ -0,0 +1,92 @@
+use std::{io, path::PathBuf};
+
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use uuid::Uuid;
+
+use super::message::now_millis;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub uploader: Uuid,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the content
+    pub checksum: String,
+    pub created_at: u64,
+}
+
+impl Attachment {
+    pub fn new(
+        room_id: &Uuid,
+        uploader: &Uuid,
+        name: String,
+        content_type: String,
+        data: &[u8],
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            room_id: *room_id,
+            uploader: *uploader,
+            name,
+            content_type,
+            size: data.len() as u64,
+            checksum: hex::encode(Sha256::digest(data)),
+            created_at: now_millis(),
+        }
+    }
+    pub fn to_value(&self) -> Value {
+        json!({
+            "id": self.id.to_string(),
+            "room_id": self.room_id.to_string(),
+            "uploader": self.uploader.to_string(),
+            "name": self.name,
+            "content_type": self.content_type,
+            "size": self.size,
+            "checksum": self.checksum,
+            "created_at": self.created_at
+        })
+    }
+}
+
+/// Storage backend for attachment content, metadata is kept by the engine.
+pub trait BlobStore: Send + Sync {
+    fn put<'a>(&'a self, id: &'a Uuid, data: Vec<u8>) -> BoxFuture<'a, io::Result<()>>;
+    fn get<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, io::Result<Vec<u8>>>;
+    fn delete<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// Stores every blob as a file named after its id inside `directory`.
+pub struct LocalBlobStore {
+    directory: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+    fn path(&self, id: &Uuid) -> PathBuf {
+        self.directory.join(id.to_string())
+    }
+}
+
+impl BlobStore for LocalBlobStore {
+    fn put<'a>(&'a self, id: &'a Uuid, data: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            fs::create_dir_all(&self.directory).await?;
+            fs::write(self.path(id), data).await
+        })
+    }
+    fn get<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        Box::pin(async move { fs::read(self.path(id)).await })
+    }
+    fn delete<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { fs::remove_file(self.path(id)).await })
+    }
+}
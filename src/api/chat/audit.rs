@@ -0,0 +1,466 @@
+use std::{
+    collections::VecDeque,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::Duration,
+};
+
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    select, spawn,
+    sync::{
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+        RwLock,
+    },
+    time::interval,
+};
+use uuid::Uuid;
+
+use crate::config::AuditConfig;
+
+use super::message::now_millis;
+
+const DEFAULT_RESULTS: usize = 100;
+const MAX_RESULTS: usize = 1000;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Actor of entries recorded on behalf of the admin API.
+pub const ADMIN_ACTOR: &str = "admin";
+/// Actor of entries the server records on its own, such as idle rooms
+/// being removed.
+pub const SERVER_ACTOR: &str = "server";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    pub timestamp: u64,
+    /// Client id, `admin` or `server`
+    pub actor: String,
+    /// Event type (`ROOM_JOIN`, `ROOM_KICK`, `ADMIN_API`...)
+    pub action: String,
+    /// What the action applies to: a client id, a report id, a request...
+    pub target: Option<String>,
+    pub room_id: Option<Uuid>,
+    #[serde(default)]
+    pub details: Value,
+}
+
+impl AuditEntry {
+    pub fn new(actor: String, action: &str, room_id: Option<Uuid>, target: Option<String>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            timestamp: now_millis(),
+            actor,
+            action: action.to_string(),
+            target,
+            room_id,
+            details: Value::Null,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub room_id: Option<Uuid>,
+    /// Inclusive lower bound in milliseconds since the epoch
+    pub from: Option<u64>,
+    /// Inclusive upper bound in milliseconds since the epoch
+    pub to: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.actor
+            .as_ref()
+            .is_none_or(|actor| actor == &entry.actor)
+            && self
+                .action
+                .as_ref()
+                .is_none_or(|action| action == &entry.action)
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|target| Some(target) == entry.target.as_ref())
+            && self
+                .room_id
+                .is_none_or(|room_id| Some(room_id) == entry.room_id)
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp <= to)
+    }
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_RESULTS).min(MAX_RESULTS)
+    }
+    /// Matching entries of `entries`, given oldest first, most recent first.
+    fn apply<'a>(
+        &self,
+        entries: impl DoubleEndedIterator<Item = &'a AuditEntry>,
+    ) -> Vec<AuditEntry> {
+        entries
+            .rev()
+            .filter(|entry| self.matches(entry))
+            .take(self.limit())
+            .cloned()
+            .collect()
+    }
+}
+
+/// Storage backend of the audit log. Entries are only ever appended, and
+/// removed once older than the retention period.
+pub trait AuditStore: Send + Sync {
+    fn append<'a>(&'a self, entries: &'a [AuditEntry]) -> BoxFuture<'a, io::Result<()>>;
+    /// Matching entries, most recent first.
+    fn query<'a>(&'a self, query: &'a AuditQuery) -> BoxFuture<'a, io::Result<Vec<AuditEntry>>>;
+    /// Removes the entries recorded before `timestamp`.
+    fn prune(&self, timestamp: u64) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// Keeps the latest `limit` entries in memory.
+pub struct MemoryAuditStore {
+    entries: RwLock<VecDeque<AuditEntry>>,
+    limit: usize,
+}
+
+impl MemoryAuditStore {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            entries: RwLock::new(VecDeque::new()),
+            limit,
+        }
+    }
+}
+
+impl AuditStore for MemoryAuditStore {
+    fn append<'a>(&'a self, entries: &'a [AuditEntry]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            if self.limit == 0 {
+                return Ok(());
+            }
+            let mut stored = self.entries.write().await;
+            for entry in entries {
+                while stored.len() >= self.limit {
+                    stored.pop_front();
+                }
+                stored.push_back(entry.clone());
+            }
+            Ok(())
+        })
+    }
+    fn query<'a>(&'a self, query: &'a AuditQuery) -> BoxFuture<'a, io::Result<Vec<AuditEntry>>> {
+        Box::pin(async move { Ok(query.apply(self.entries.read().await.iter())) })
+    }
+    fn prune(&self, timestamp: u64) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            self.entries
+                .write()
+                .await
+                .retain(|entry| entry.timestamp >= timestamp);
+            Ok(())
+        })
+    }
+}
+
+/// Appends entries to a JSON lines file, one entry per line. Pruning
+/// rewrites the file without the expired entries.
+pub struct FileAuditStore {
+    path: PathBuf,
+}
+
+impl FileAuditStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+    async fn read_lines(&self) -> io::Result<String> {
+        match fs::read_to_string(&self.path).await {
+            Ok(data) => Ok(data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AuditStore for FileAuditStore {
+    fn append<'a>(&'a self, entries: &'a [AuditEntry]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut data = Vec::new();
+            for entry in entries {
+                serde_json::to_writer(&mut data, entry).map_err(io::Error::other)?;
+                data.push(b'\n');
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            file.write_all(&data).await?;
+            file.flush().await
+        })
+    }
+    fn query<'a>(&'a self, query: &'a AuditQuery) -> BoxFuture<'a, io::Result<Vec<AuditEntry>>> {
+        Box::pin(async move {
+            let data = self.read_lines().await?;
+            let entries: Vec<AuditEntry> = data
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect();
+            Ok(query.apply(entries.iter()))
+        })
+    }
+    fn prune(&self, timestamp: u64) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let data = self.read_lines().await?;
+            let mut kept = String::new();
+            let mut removed = 0;
+            for line in data.lines() {
+                // Lines that cannot be read are kept for whoever inspects the file
+                let expired = serde_json::from_str::<AuditEntry>(line)
+                    .is_ok_and(|entry| entry.timestamp < timestamp);
+                if expired {
+                    removed += 1;
+                } else {
+                    kept.push_str(line);
+                    kept.push('\n');
+                }
+            }
+            if removed == 0 {
+                return Ok(());
+            }
+            let temporary = self.path.with_extension("tmp");
+            fs::write(&temporary, kept).await?;
+            fs::rename(&temporary, &self.path).await?;
+            println!("audit log pruned {} entries", removed);
+            Ok(())
+        })
+    }
+}
+
+/// Records entries through a bounded queue so that recording never waits on
+/// the store, a single worker appends them and prunes expired ones. Entries
+/// dropped while the queue is full are counted, and the count is recorded
+/// as an `AUDIT_DROPPED` entry once there is room again.
+pub struct AuditLog {
+    store: Arc<dyn AuditStore>,
+    queue: Sender<AuditEntry>,
+    dropped: Arc<AtomicU64>,
+    // Taken by `start`, so the worker runs inside the runtime
+    receiver: StdMutex<Option<Receiver<AuditEntry>>>,
+    retention: Duration,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig, store: Arc<dyn AuditStore>) -> Self {
+        let (queue, receiver) = mpsc::channel(config.queue_size.max(1));
+        Self {
+            store,
+            queue,
+            dropped: Arc::new(AtomicU64::new(0)),
+            receiver: StdMutex::new(Some(receiver)),
+            retention: config.retention(),
+        }
+    }
+    pub fn record(&self, entry: AuditEntry) {
+        match self.queue.try_send(entry) {
+            Ok(()) => {}
+            Err(TrySendError::Full(entry)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    println!("audit queue full, dropping {} {}", entry.action, entry.id)
+                }
+            }
+            Err(TrySendError::Closed(entry)) => {
+                println!("audit queue closed, dropping {} {}", entry.action, entry.id)
+            }
+        }
+    }
+    pub async fn query(&self, query: &AuditQuery) -> io::Result<Vec<AuditEntry>> {
+        self.store.query(query).await
+    }
+    pub fn start(&self) {
+        let receiver = match self.receiver.lock() {
+            Ok(mut receiver) => receiver.take(),
+            Err(_) => None,
+        };
+        if let Some(receiver) = receiver {
+            spawn(run_worker(
+                Arc::clone(&self.store),
+                receiver,
+                Arc::clone(&self.dropped),
+                self.retention,
+            ));
+        }
+    }
+}
+
+async fn run_worker(
+    store: Arc<dyn AuditStore>,
+    mut receiver: Receiver<AuditEntry>,
+    dropped: Arc<AtomicU64>,
+    retention: Duration,
+) {
+    let mut prune = interval(PRUNE_INTERVAL);
+    loop {
+        select! {
+            entry = receiver.recv() => {
+                let Some(entry) = entry else {
+                    break;
+                };
+                let mut entries = vec![entry];
+                while let Ok(entry) = receiver.try_recv() {
+                    entries.push(entry);
+                }
+                let count = dropped.swap(0, Ordering::Relaxed);
+                if count > 0 {
+                    println!("audit queue dropped {} entries", count);
+                    let mut entry = AuditEntry::new(SERVER_ACTOR.to_string(), "AUDIT_DROPPED", None, None);
+                    entry.details = json!({ "count": count });
+                    entries.push(entry);
+                }
+                if let Err(e) = store.append(&entries).await {
+                    println!("audit log append error, {} entries lost {}", entries.len(), e);
+                }
+            }
+            _ = prune.tick(), if !retention.is_zero() => {
+                let timestamp = now_millis().saturating_sub(retention.as_millis() as u64);
+                if let Err(e) = store.prune(timestamp).await {
+                    println!("audit log prune error {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(actor: &str, action: &str, timestamp: u64) -> AuditEntry {
+        let mut entry = AuditEntry::new(actor.to_string(), action, None, None);
+        entry.timestamp = timestamp;
+        entry
+    }
+
+    fn actions(entries: &[AuditEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.action.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn memory_store_keeps_the_latest_entries() {
+        let store = MemoryAuditStore::new(2);
+        let entries = [
+            entry("a", "FIRST", 1),
+            entry("a", "SECOND", 2),
+            entry("a", "THIRD", 3),
+        ];
+        store.append(&entries[..1]).await.unwrap();
+        store.append(&entries[1..]).await.unwrap();
+        let stored = store.query(&AuditQuery::default()).await.unwrap();
+        assert_eq!(actions(&stored), ["THIRD", "SECOND"]);
+
+        let store = MemoryAuditStore::new(0);
+        store.append(&entries).await.unwrap();
+        assert!(store
+            .query(&AuditQuery::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn queries_filter_and_limit_entries() {
+        let store = MemoryAuditStore::new(10);
+        let room_id = Uuid::new_v4();
+        let mut kick = entry("alice", "ROOM_KICK", 20);
+        kick.room_id = Some(room_id);
+        kick.target = Some("bob".to_string());
+        store
+            .append(&[
+                entry("alice", "ROOM_JOIN", 10),
+                kick,
+                entry("bob", "ROOM_JOIN", 30),
+                entry("alice", "ROOM_EXIT", 40),
+            ])
+            .await
+            .unwrap();
+        let store = &store;
+        let query = |query: AuditQuery| async move { store.query(&query).await.unwrap() };
+
+        let alice = query(AuditQuery {
+            actor: Some("alice".to_string()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(actions(&alice), ["ROOM_EXIT", "ROOM_KICK", "ROOM_JOIN"]);
+        let joins = query(AuditQuery {
+            action: Some("ROOM_JOIN".to_string()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(joins.len(), 2);
+        for kicks in [
+            AuditQuery {
+                room_id: Some(room_id),
+                ..Default::default()
+            },
+            AuditQuery {
+                target: Some("bob".to_string()),
+                ..Default::default()
+            },
+            AuditQuery {
+                from: Some(20),
+                to: Some(29),
+                ..Default::default()
+            },
+        ] {
+            assert_eq!(actions(&query(kicks).await), ["ROOM_KICK"]);
+        }
+        let latest = query(AuditQuery {
+            limit: Some(1),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(actions(&latest), ["ROOM_EXIT"]);
+    }
+
+    #[tokio::test]
+    async fn pruning_removes_older_entries() {
+        let store = MemoryAuditStore::new(10);
+        store
+            .append(&[entry("a", "OLD", 10), entry("a", "NEW", 20)])
+            .await
+            .unwrap();
+        store.prune(20).await.unwrap();
+        let stored = store.query(&AuditQuery::default()).await.unwrap();
+        assert_eq!(actions(&stored), ["NEW"]);
+    }
+
+    #[tokio::test]
+    async fn the_log_appends_recorded_entries() {
+        let store = Arc::new(MemoryAuditStore::new(10));
+        let log = AuditLog::new(&AuditConfig::default(), store);
+        log.start();
+        // A current timestamp, the worker prunes expired entries on start
+        log.record(AuditEntry::new(
+            "alice".to_string(),
+            "ROOM_JOIN",
+            None,
+            None,
+        ));
+        for _ in 0..100 {
+            if !log.query(&AuditQuery::default()).await.unwrap().is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("entry not appended");
+    }
+}
@@ -0,0 +1,599 @@
+use std::{future::Future, io, sync::Mutex as StdMutex, time::Duration};
+
+use futures_util::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    spawn,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+    },
+    time::timeout,
+};
+use uuid::Uuid;
+
+use super::{message::RoomMessage, room::RoomRole};
+
+const PUBLISH_QUEUE_SIZE: usize = 4096;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest bulk string accepted, the default `proto-max-bulk-len` of Redis
+const MAX_BULK_LENGTH: i64 = 512 * 1024 * 1024;
+
+/// What nodes of a cluster tell each other so that clients connected to
+/// different nodes share rooms.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ClusterEvent {
+    /// Sent by a node once subscribed, the other nodes answer with their rooms,
+    /// members and stored messages
+    NodeUp,
+    RoomCreated {
+        room_id: Uuid,
+        creator: Uuid,
+        name: Option<String>,
+        persistent: bool,
+        read_receipts: bool,
+        #[serde(default)]
+        max_members: Option<usize>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// `None` when the role was taken away
+    RoleChanged {
+        room_id: Uuid,
+        client_id: Uuid,
+        role: Option<RoomRole>,
+    },
+    /// The argon2 hash of the new password, `None` when it was removed
+    RoomPasswordChanged {
+        room_id: Uuid,
+        password: Option<String>,
+    },
+    RoomRemoved {
+        room_id: Uuid,
+    },
+    MemberJoined {
+        room_id: Uuid,
+        client_id: Uuid,
+    },
+    MemberLeft {
+        room_id: Uuid,
+        client_id: Uuid,
+    },
+    /// A value broadcast in a room, delivered to the members on every node
+    RoomBroadcast {
+        room_id: Uuid,
+        value: Value,
+    },
+    /// A message as stored after being sent, edited, deleted, reacted to or
+    /// replied to, so that every node can act on it. Sequence numbers are
+    /// assigned by the node the message was sent to, messages sent to
+    /// different nodes at the same time can share one, and concurrent
+    /// changes to a message from different nodes keep the last one received.
+    MessageStored {
+        message: RoomMessage,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackplaneMessage {
+    pub node_id: Uuid,
+    pub event: ClusterEvent,
+}
+
+impl BackplaneMessage {
+    fn decode(payload: &[u8]) -> Option<Self> {
+        serde_json::from_slice(payload)
+            .map_err(|e| println!("backplane invalid message {}", e))
+            .ok()
+    }
+}
+
+/// Pub/sub channel shared by the nodes of a cluster. Every node receives
+/// every message, including its own.
+pub trait Backplane: Send + Sync {
+    fn publish(&self, message: &BackplaneMessage) -> BoxFuture<'_, io::Result<()>>;
+    fn subscribe(&self) -> BoxFuture<'_, io::Result<BoxStream<'static, BackplaneMessage>>>;
+}
+
+/// Backplane between engines of a single process.
+pub struct LocalBackplane {
+    sender: broadcast::Sender<String>,
+}
+
+impl Default for LocalBackplane {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(1024);
+        Self { sender }
+    }
+}
+
+impl Backplane for LocalBackplane {
+    fn publish(&self, message: &BackplaneMessage) -> BoxFuture<'_, io::Result<()>> {
+        let payload = serde_json::to_string(message).map_err(io::Error::other);
+        Box::pin(async move {
+            // Sending only fails when no engine is subscribed yet
+            let _ = self.sender.send(payload?);
+            Ok(())
+        })
+    }
+    fn subscribe(&self) -> BoxFuture<'_, io::Result<BoxStream<'static, BackplaneMessage>>> {
+        let receiver = self.sender.subscribe();
+        Box::pin(async move {
+            let messages = stream::unfold(receiver, |mut receiver| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(payload) => {
+                            if let Some(message) = BackplaneMessage::decode(payload.as_bytes()) {
+                                return Some((message, receiver));
+                            }
+                        }
+                        Err(RecvError::Lagged(count)) => {
+                            println!("backplane subscriber lagged, {} messages lost", count)
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            });
+            Ok(Box::pin(messages) as BoxStream<'static, BackplaneMessage>)
+        })
+    }
+}
+
+/// Backplane over a Redis pub/sub channel, speaking RESP directly.
+/// Messages are published by a task of their own through a bounded queue,
+/// so a slow or unreachable Redis never holds up the rooms publishing.
+pub struct RedisBackplane {
+    address: String,
+    channel: String,
+    queue: Sender<String>,
+    // Taken by the first publish, so the publisher starts inside the runtime
+    receiver: StdMutex<Option<Receiver<String>>>,
+}
+
+impl RedisBackplane {
+    pub fn new(address: String, channel: String) -> Self {
+        let (queue, receiver) = mpsc::channel(PUBLISH_QUEUE_SIZE);
+        Self {
+            address,
+            channel,
+            queue,
+            receiver: StdMutex::new(Some(receiver)),
+        }
+    }
+    fn start_publisher(&self) {
+        let receiver = match self.receiver.lock() {
+            Ok(mut receiver) => receiver.take(),
+            Err(_) => None,
+        };
+        if let Some(receiver) = receiver {
+            spawn(run_publisher(
+                self.address.clone(),
+                self.channel.clone(),
+                receiver,
+            ));
+        }
+    }
+}
+
+impl Backplane for RedisBackplane {
+    /// Queues the message, failing when the queue is full.
+    fn publish(&self, message: &BackplaneMessage) -> BoxFuture<'_, io::Result<()>> {
+        let payload = serde_json::to_string(message).map_err(io::Error::other);
+        Box::pin(async move {
+            self.start_publisher();
+            match self.queue.try_send(payload?) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => Err(io::Error::other("backplane queue full")),
+                Err(TrySendError::Closed(_)) => Err(io::Error::other("backplane queue closed")),
+            }
+        })
+    }
+    fn subscribe(&self) -> BoxFuture<'_, io::Result<BoxStream<'static, BackplaneMessage>>> {
+        Box::pin(async move {
+            let mut connection = connect(&self.address).await?;
+            with_timeout(write_command(
+                &mut connection,
+                &["SUBSCRIBE", &self.channel],
+            ))
+            .await?;
+            if let Reply::Error(e) = with_timeout(read_reply(&mut connection)).await? {
+                return Err(io::Error::other(e));
+            }
+            let messages = stream::unfold(connection, |mut connection| async move {
+                loop {
+                    match read_reply(&mut connection).await {
+                        Ok(Reply::Array(items)) => match items.as_slice() {
+                            [Reply::Bulk(Some(kind)), _, Reply::Bulk(Some(payload))]
+                                if kind == b"message" =>
+                            {
+                                if let Some(message) = BackplaneMessage::decode(payload) {
+                                    return Some((message, connection));
+                                }
+                            }
+                            _ => {}
+                        },
+                        Ok(_) => {}
+                        Err(e) => {
+                            println!("backplane subscription error {}", e);
+                            return None;
+                        }
+                    }
+                }
+            });
+            Ok(Box::pin(messages) as BoxStream<'static, BackplaneMessage>)
+        })
+    }
+}
+
+/// Publishes the queued payloads one after the other, reconnecting on the
+/// next payload after an error. A payload failing to publish is dropped.
+async fn run_publisher(address: String, channel: String, mut receiver: Receiver<String>) {
+    let mut connection: Option<BufStream<TcpStream>> = None;
+    while let Some(payload) = receiver.recv().await {
+        let result = async {
+            let stream = match connection.as_mut() {
+                Some(stream) => stream,
+                None => connection.insert(connect(&address).await?),
+            };
+            with_timeout(write_command(stream, &["PUBLISH", &channel, &payload])).await?;
+            match with_timeout(read_reply(stream)).await? {
+                Reply::Error(e) => Err(io::Error::other(e)),
+                _ => Ok(()),
+            }
+        }
+        .await;
+        if let Err(e) = result {
+            println!("backplane publish error {}", e);
+            connection = None;
+        }
+    }
+}
+
+async fn connect(address: &str) -> io::Result<BufStream<TcpStream>> {
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "backplane connect timeout"))??;
+    Ok(BufStream::new(stream))
+}
+
+async fn with_timeout<T>(future: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    timeout(IO_TIMEOUT, future)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "backplane timeout"))?
+}
+
+enum Reply {
+    /// Simple string or integer, whose value is never needed
+    Status,
+    Error(String),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+async fn write_command<S: AsyncWrite + Unpin>(stream: &mut S, args: &[&str]) -> io::Result<()> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg.as_bytes());
+        command.extend_from_slice(b"\r\n");
+    }
+    stream.write_all(&command).await?;
+    stream.flush().await
+}
+
+async fn read_line<S: AsyncBufRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn parse_length(value: &str) -> io::Result<i64> {
+    value
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid RESP length"))
+}
+
+/// Reads a reply, arrays are only expected to hold scalars which is all
+/// `PUBLISH` and `SUBSCRIBE` return.
+async fn read_reply<S: AsyncBufRead + Unpin>(stream: &mut S) -> io::Result<Reply> {
+    let line = read_line(stream).await?;
+    if let Some(count) = line.strip_prefix('*') {
+        let count = parse_length(count)?;
+        let mut items = Vec::new();
+        for _ in 0..count.max(0) {
+            let line = read_line(stream).await?;
+            items.push(read_scalar(stream, &line).await?);
+        }
+        return Ok(Reply::Array(items));
+    }
+    read_scalar(stream, &line).await
+}
+
+async fn read_scalar<S: AsyncBufRead + Unpin>(stream: &mut S, line: &str) -> io::Result<Reply> {
+    let value = line.get(1..).unwrap_or_default();
+    match line.chars().next() {
+        Some('+') | Some(':') => Ok(Reply::Status),
+        Some('-') => Ok(Reply::Error(value.to_string())),
+        Some('$') => {
+            let length = parse_length(value)?;
+            if length < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            if length > MAX_BULK_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("RESP bulk string of {} bytes", length),
+                ));
+            }
+            let mut data = vec![0; length as usize + 2];
+            stream.read_exact(&mut data).await?;
+            data.truncate(length as usize);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected RESP reply {}", line),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use futures_util::StreamExt;
+    use tokio::{
+        io::{duplex, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(2);
+
+    fn bulk(value: &[u8]) -> Vec<u8> {
+        let mut bulk = format!("${}\r\n", value.len()).into_bytes();
+        bulk.extend_from_slice(value);
+        bulk.extend_from_slice(b"\r\n");
+        bulk
+    }
+
+    /// Redis stand-in relaying published payloads to its subscribers. The
+    /// first `drop_publishes` publishes close the connection without an answer.
+    struct StandIn {
+        address: String,
+        connections: Arc<AtomicUsize>,
+        // Closes the subscriber connections when sent to
+        close_subscribers: broadcast::Sender<()>,
+    }
+
+    impl StandIn {
+        async fn start(drop_publishes: usize) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let connections = Arc::new(AtomicUsize::new(0));
+            let (close_subscribers, _) = broadcast::channel(1);
+            let (messages, _) = broadcast::channel::<Vec<u8>>(16);
+            let publishes = Arc::new(AtomicUsize::new(0));
+            let accepted = Arc::clone(&connections);
+            let close = close_subscribers.clone();
+            spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    spawn(serve(
+                        BufStream::new(stream),
+                        messages.clone(),
+                        close.subscribe(),
+                        Arc::clone(&publishes),
+                        drop_publishes,
+                    ));
+                }
+            });
+            Self {
+                address,
+                connections,
+                close_subscribers,
+            }
+        }
+        fn backplane(&self) -> RedisBackplane {
+            RedisBackplane::new(self.address.clone(), "chat".to_string())
+        }
+    }
+
+    async fn serve(
+        mut stream: BufStream<TcpStream>,
+        messages: broadcast::Sender<Vec<u8>>,
+        mut close: broadcast::Receiver<()>,
+        publishes: Arc<AtomicUsize>,
+        drop_publishes: usize,
+    ) -> io::Result<()> {
+        loop {
+            let args: Vec<Vec<u8>> = match read_reply(&mut stream).await? {
+                Reply::Array(items) => items
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        Reply::Bulk(arg) => arg,
+                        _ => None,
+                    })
+                    .collect(),
+                _ => return Ok(()),
+            };
+            match args.as_slice() {
+                [command, channel] if command == b"SUBSCRIBE" => {
+                    let mut receiver = messages.subscribe();
+                    let mut reply = b"*3\r\n".to_vec();
+                    reply.extend(bulk(b"subscribe"));
+                    reply.extend(bulk(channel));
+                    reply.extend_from_slice(b":1\r\n");
+                    stream.write_all(&reply).await?;
+                    stream.flush().await?;
+                    loop {
+                        let payload = tokio::select! {
+                            payload = receiver.recv() => payload.unwrap(),
+                            _ = close.recv() => return Ok(()),
+                        };
+                        let mut message = b"*3\r\n".to_vec();
+                        message.extend(bulk(b"message"));
+                        message.extend(bulk(channel));
+                        message.extend(bulk(&payload));
+                        stream.write_all(&message).await?;
+                        stream.flush().await?;
+                    }
+                }
+                [command, _, payload] if command == b"PUBLISH" => {
+                    if publishes.fetch_add(1, Ordering::SeqCst) < drop_publishes {
+                        return Ok(());
+                    }
+                    let count = messages.send(payload.clone()).unwrap_or_default();
+                    stream
+                        .write_all(format!(":{}\r\n", count).as_bytes())
+                        .await?;
+                    stream.flush().await?;
+                }
+                _ => {
+                    stream.write_all(b"-ERR unknown command\r\n").await?;
+                    stream.flush().await?;
+                }
+            }
+        }
+    }
+
+    fn room_removed() -> BackplaneMessage {
+        BackplaneMessage {
+            node_id: Uuid::new_v4(),
+            event: ClusterEvent::RoomRemoved {
+                room_id: Uuid::new_v4(),
+            },
+        }
+    }
+
+    fn room_id(message: &BackplaneMessage) -> Uuid {
+        match message.event {
+            ClusterEvent::RoomRemoved { room_id } => room_id,
+            _ => panic!("unexpected event {:?}", message.event),
+        }
+    }
+
+    async fn next(messages: &mut BoxStream<'static, BackplaneMessage>) -> Option<BackplaneMessage> {
+        timeout(WAIT, messages.next())
+            .await
+            .expect("no backplane message")
+    }
+
+    #[tokio::test]
+    async fn read_reply_parses_replies() {
+        let mut input: &[u8] =
+            b"+OK\r\n:2\r\n-ERR wrong\r\n$5\r\nhe\r\no\r\n$-1\r\n*2\r\n$3\r\nabc\r\n:1\r\n";
+        assert!(matches!(read_reply(&mut input).await, Ok(Reply::Status)));
+        assert!(matches!(read_reply(&mut input).await, Ok(Reply::Status)));
+        assert!(matches!(read_reply(&mut input).await, Ok(Reply::Error(e)) if e == "ERR wrong"));
+        assert!(matches!(
+            read_reply(&mut input).await,
+            Ok(Reply::Bulk(Some(data))) if data == b"he\r\no"
+        ));
+        assert!(matches!(
+            read_reply(&mut input).await,
+            Ok(Reply::Bulk(None))
+        ));
+        match read_reply(&mut input).await {
+            Ok(Reply::Array(items)) => assert!(matches!(
+                items.as_slice(),
+                [Reply::Bulk(Some(data)), Reply::Status] if data == b"abc"
+            )),
+            _ => panic!("array expected"),
+        }
+        let error = read_reply(&mut input).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn read_reply_rejects_invalid_replies() {
+        let mut oversized: &[u8] = b"$536870913\r\n";
+        let error = read_reply(&mut oversized).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let mut length: &[u8] = b"$five\r\n";
+        let error = read_reply(&mut length).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let mut unknown: &[u8] = b"?\r\n";
+        let error = read_reply(&mut unknown).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let mut truncated: &[u8] = b"$10\r\nshort\r\n";
+        let error = read_reply(&mut truncated).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_time_out() {
+        // The other end stays open without ever answering
+        let (stream, _server) = duplex(64);
+        let mut stream = BufReader::new(stream);
+        let error = with_timeout(read_reply(&mut stream)).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn publishes_to_subscribers() {
+        let redis = StandIn::start(0).await;
+        let backplane = redis.backplane();
+        let mut messages = backplane.subscribe().await.unwrap();
+        let message = room_removed();
+        backplane.publish(&message).await.unwrap();
+        let received = next(&mut messages).await.unwrap();
+        assert_eq!(received.node_id, message.node_id);
+        assert_eq!(room_id(&received), room_id(&message));
+    }
+
+    #[tokio::test]
+    async fn publisher_reconnects_after_an_error() {
+        let redis = StandIn::start(1).await;
+        let backplane = redis.backplane();
+        let mut messages = backplane.subscribe().await.unwrap();
+        let dropped = room_removed();
+        let published = room_removed();
+        backplane.publish(&dropped).await.unwrap();
+        backplane.publish(&published).await.unwrap();
+        // The first payload is lost with its connection
+        let received = next(&mut messages).await.unwrap();
+        assert_eq!(room_id(&received), room_id(&published));
+        assert_eq!(redis.connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn subscription_ends_with_its_connection() {
+        let redis = StandIn::start(0).await;
+        let backplane = redis.backplane();
+        let mut messages = backplane.subscribe().await.unwrap();
+        redis.close_subscribers.send(()).unwrap();
+        assert!(next(&mut messages).await.is_none());
+        // The engine subscribes again
+        let mut messages = backplane.subscribe().await.unwrap();
+        let message = room_removed();
+        backplane.publish(&message).await.unwrap();
+        assert_eq!(
+            room_id(&next(&mut messages).await.unwrap()),
+            room_id(&message)
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribe_fails_without_redis() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let backplane = RedisBackplane::new(address, "chat".to_string());
+        assert!(backplane.subscribe().await.is_err());
+    }
+}
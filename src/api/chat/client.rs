@@ -1,5 +1,7 @@
 use std::{
+    borrow::Cow,
     collections::HashSet,
+    net::IpAddr,
     str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -11,20 +13,41 @@ use futures_util::{stream::SplitSink, SinkExt};
 use serde_json::{json, Value};
 use tokio::{
     spawn,
-    sync::{Mutex, RwLock},
+    sync::{mpsc, RwLock},
 };
 use uuid::Uuid;
 use warp::filters::ws::{Message, WebSocket};
 
-use crate::api::chat::room::WebSocketRoom;
+use crate::api::chat::{
+    audit::AuditEntry,
+    command::{parse_command, CommandContext},
+    password::hash_password,
+    queue::SendQueue,
+    room::{RoomError, RoomOptions, WebSocketRoom},
+    search::SearchQuery,
+    wire::{Frame, WireFormat},
+};
+use crate::config::SendQueueConfig;
 
 use super::engine::ChatEngine;
 
+/// Where the events of a client are written to.
+pub enum ClientSender {
+    WebSocket(SplitSink<WebSocket, Message>, WireFormat),
+    /// Server-sent events, the receiving side feeds the `/events` response
+    EventStream(mpsc::Sender<Arc<Frame>>),
+}
+
 pub struct WebSocketClient {
     manager: Weak<ChatEngine>,
-    websocket_sender: Mutex<SplitSink<WebSocket, Message>>,
+    // Frames are written by a task of their own, a stalled connection only
+    // fills its own queue
+    queue: Arc<SendQueue>,
     rooms: RwLock<HashSet<Uuid>>,
     id: Uuid,
+    // Address the client connected from, when known
+    ip: Option<IpAddr>,
+    nick: RwLock<Option<String>>,
     subscribe_rooms: AtomicBool,
     subscribe_rooms_is_running: AtomicBool,
 }
@@ -32,16 +55,21 @@ pub struct WebSocketClient {
 impl WebSocketClient {
     pub(super) fn new(
         manager: Weak<ChatEngine>,
-        websocket_sender: SplitSink<WebSocket, Message>,
+        sender: ClientSender,
+        config: SendQueueConfig,
+        ip: Option<IpAddr>,
     ) -> Self {
         let id = Uuid::new_v4();
-        let websocket_sender = Mutex::new(websocket_sender);
+        let queue = Arc::new(SendQueue::new(config));
+        spawn(write(id, Weak::clone(&manager), Arc::clone(&queue), sender));
         let rooms = RwLock::new(HashSet::new());
         Self {
             manager,
-            websocket_sender,
+            queue,
             rooms,
             id,
+            ip,
+            nick: RwLock::new(None),
             subscribe_rooms: AtomicBool::new(false),
             subscribe_rooms_is_running: AtomicBool::new(false),
         }
@@ -58,9 +86,9 @@ impl WebSocketClient {
                 let client = Arc::downgrade(&client);
                 let mut listener = manager.get_listener().await;
                 spawn(async move {
-                    while let Ok(value) = listener.recv().await {
+                    while let Ok(frame) = listener.recv().await {
                         if let Some(client) = client.upgrade() {
-                            client.send(value).await;
+                            client.send_frame(&frame).await;
                             if !client.subscribe_rooms.load(Ordering::Relaxed) {
                                 break;
                             }
@@ -79,116 +107,312 @@ impl WebSocketClient {
             .store(false, Ordering::Relaxed);
     }
 
-    pub async fn join_room(&self, room_id: &Uuid) {
+    pub async fn join_room(&self, room_id: &Uuid) -> Result<(), RoomError> {
+        self.join_room_with_password(room_id, None).await
+    }
+    /// Fails with `RoomError::TooManyRooms` when the client is already in
+    /// as many rooms as `chat.limits.max_joined_rooms` allows, `password` is
+    /// only needed by password protected rooms.
+    pub async fn join_room_with_password(
+        &self,
+        room_id: &Uuid,
+        password: Option<&str>,
+    ) -> Result<(), RoomError> {
         if let Some(manager) = self.manager.upgrade() {
-            if let Some(room) = manager.get_room(room_id).await {
-                if let Some(client) = manager.get_client(&self.id).await {
-                    let client_id = *client.get_id();
-                    println!("client {} join {}", client_id, room_id);
-                    room.client_add(&client_id).await;
-                    {
-                        self.rooms.write().await.insert(*room_id);
-                    }
-                    let mut listener = room.get_listener().await;
+            let room = manager.get_room(room_id).await.ok_or(RoomError::NotFound)?;
+            if let Some(client) = manager.get_client(&self.id).await {
+                let client_id = *client.get_id();
+                println!("client {} join {}", client_id, room_id);
+                room.check_password(&client, password).await?;
+                {
+                    // Held while joining so concurrent joins cannot both pass the limit
+                    let mut rooms = self.rooms.write().await;
+                    let max_joined_rooms = manager.get_config().limits.max_joined_rooms;
+                    if max_joined_rooms > 0
+                        && !rooms.contains(room_id)
+                        && rooms.len() >= max_joined_rooms
                     {
-                        let room = Arc::downgrade(&room);
-                        let client = Arc::downgrade(&client);
-                        spawn(async move {
-                            while let Ok(value) = listener.recv().await {
-                                println!("{} room to client exec signal {}", client_id, value);
-                                if value["type"] == "EVENT"
-                                    && value["event"]["type"] == "ROOM_EXIT"
-                                    && value["event"]["client_id"] == client_id.to_string()
-                                {
-                                    break;
-                                }
-                                if let Some(room) = room.upgrade() {
-                                    if room.has_client(&client_id).await {
-                                        match client.upgrade() {
-                                            Some(websocket_client) => {
-                                                println!("{} room sending {}", client_id, value);
-                                                websocket_client.send(value).await;
-                                            }
-                                            None => break,
-                                        }
-                                    } else {
-                                        break;
-                                    }
-                                }
-                            }
-                            println!("{} room to client task exit", client_id);
-                        });
+                        return Err(RoomError::TooManyRooms);
                     }
-                    println!("DEBUG {} client joined room {}", client_id, room_id);
-                    room.exec(
-                        &json!({
-                            "action": "BROADCAST",
-                            "data": {
-                                "type": "EVENT",
-                                "event": {
-                                    "type": "ROOM_JOIN",
-                                    "room_id": room_id.to_string(),
-                                    "client_id": client_id.to_string()
-                            } }
-                        }),
-                        Some(&client_id),
-                    )
-                    .await;
+                    room.client_add(&client).await?;
+                    rooms.insert(*room_id);
                 }
+                manager.audit(AuditEntry::new(
+                    client_id.to_string(),
+                    "ROOM_JOIN",
+                    Some(*room_id),
+                    Some(client_id.to_string()),
+                ));
+                println!("DEBUG {} client joined room {}", client_id, room_id);
+                room.broadcast(json!({
+                    "type": "EVENT",
+                    "event": {
+                        "type": "ROOM_JOIN",
+                        "room_id": room_id.to_string(),
+                        "client_id": client_id.to_string()
+                    },
+                    "sender": client_id.to_string(),
+                    "room": room_id.to_string()
+                }))
+                .await;
+                room.send_unread(&client_id).await;
+            }
+        }
+        Ok(())
+    }
+    /// Rejoins the rooms the connection `token` was issued to was in when it
+    /// was lost, picking up its read positions so unread counts survive
+    /// reconnecting.
+    pub async fn resume(&self, token: &str) -> Result<(), RoomError> {
+        let manager = self.manager.upgrade().ok_or(RoomError::NotFound)?;
+        let previous = manager
+            .get_tokens()
+            .verify(token)
+            .filter(|previous| previous != &self.id)
+            .ok_or(RoomError::Forbidden)?;
+        for room_id in manager.get_rooms_list().await {
+            let room = match manager.get_room(&room_id).await {
+                Some(room) => room,
+                None => continue,
+            };
+            if !room.resume_read(&previous, &self.id).await {
+                continue;
+            }
+            println!("client {} resumed {} in {}", self.id, previous, room_id);
+            if let Err(error) = self.join_room(&room_id).await {
+                room.forget_read(&self.id).await;
+                self.send_error("CLIENT_RESUME", error, &room_id).await;
+            }
+        }
+        Ok(())
+    }
+    pub async fn leave_room(&self, room_id: &Uuid) {
+        if let Some(manager) = self.manager.upgrade() {
+            let mut rooms = self.rooms.write().await;
+            if !rooms.remove(room_id) {
+                return;
             }
+            if let Some(room) = manager.get_room(room_id).await {
+                room.remove_client(&self.id).await;
+            }
+            println!("client {} left {}", self.id, room_id);
+            self.send(json!({
+                "type": "EVENT",
+                "event": {
+                    "type": "ROOM_EXIT",
+                    "client_id": self.id.to_string(),
+                    "room_id": room_id.to_string()
+                }
+            }))
+            .await;
         }
     }
     pub fn get_id(&self) -> &Uuid {
         &self.id
     }
+    pub fn get_ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+    pub async fn get_nick(&self) -> Option<String> {
+        self.nick.read().await.clone()
+    }
+    pub async fn set_nick(&self, nick: String) {
+        *self.nick.write().await = Some(nick);
+    }
     pub async fn send(&self, value: Value) {
-        let mut websocket_sender = self.websocket_sender.lock().await;
-        websocket_sender
-            .send(Message::text(value.to_string()))
-            .await
-            .unwrap_or_else(|e| {
-                println!("{} failed to send message {} {}", &self.get_id(), e, value)
-            })
+        self.send_frame(&Arc::new(Frame::new(value))).await;
     }
+    /// Queues a frame possibly shared with other clients, reusing its
+    /// encoding when another recipient already serialized it.
+    pub async fn send_frame(&self, frame: &Arc<Frame>) {
+        if !self.queue.push(Arc::clone(frame)) {
+            println!("{} send queue closed, dropping {}", self.id, frame.kind());
+        }
+    }
+    /// Depth, high water mark and dropped frames of the send queue.
+    pub fn get_queue_stats(&self) -> Value {
+        self.queue.to_value()
+    }
+    /// Runs an action of the client. A `BROADCAST` of a message starting with
+    /// `/` runs the command through the registry, as does `COMMAND` with a
+    /// `name` and `args`. Failures are sent back as an `ERROR` event naming
+    /// the action.
     pub async fn exec(&self, value: &Value) {
-        if let Some(manager) = self.manager.upgrade() {
-            println!("{} client broadcasting {}", self.get_id(), value);
-            if value["action"] == "ROOM_CREATE" {
-                let room = WebSocketRoom::create_room(&manager, &self.id).await;
-                self.join_room(room.get_id()).await;
-            } else if value["action"] == "ROOMS_SUBSCRIBE" {
+        let manager = match self.manager.upgrade() {
+            Some(manager) => manager,
+            None => return,
+        };
+        println!("{} client broadcasting {}", self.get_id(), loggable(value));
+        let command = match value["action"].as_str() {
+            Some("COMMAND") => value["name"]
+                .as_str()
+                .map(|name| (name, value["args"].as_str().unwrap_or_default())),
+            Some("BROADCAST") if value["target"]["type"] == "ROOM" => {
+                value["data"]["message"].as_str().and_then(parse_command)
+            }
+            _ => None,
+        };
+        let action = match command {
+            Some(_) => "COMMAND",
+            None => value["action"].as_str().unwrap_or_default(),
+        };
+        let room_id = match value["target"]["type"].as_str() {
+            Some("ROOM") => value["target"]["id"].as_str(),
+            _ => value["room_id"].as_str(),
+        }
+        .and_then(|s| Uuid::from_str(s).ok());
+        if let Err(error) = self
+            .dispatch(&manager, action, value, command, room_id)
+            .await
+        {
+            self.send_error(action, error, &room_id.unwrap_or_else(Uuid::nil))
+                .await;
+        }
+    }
+
+    async fn dispatch(
+        &self,
+        manager: &Arc<ChatEngine>,
+        action: &str,
+        value: &Value,
+        command: Option<(&str, &str)>,
+        room_id: Option<Uuid>,
+    ) -> Result<(), RoomError> {
+        let room_id = room_id.ok_or(RoomError::InvalidRequest);
+        match action {
+            "ROOM_CREATE" => self.create_room(manager, value).await,
+            "CLIENT_RESUME" => {
+                self.resume(value["token"].as_str().unwrap_or_default())
+                    .await
+            }
+            "ROOMS_SUBSCRIBE" => {
                 self.subscribe_rooms().await;
                 self.send_rooms_list().await;
-            } else if value["action"] == "ROOMS_UNSUBSCRIBE" {
+                Ok(())
+            }
+            "ROOMS_UNSUBSCRIBE" => {
                 self.unsubscribe_rooms().await;
-            } else if value["action"] == "ROOMS_LIST" {
+                Ok(())
+            }
+            "ROOMS_LIST" => {
                 self.send_rooms_list().await;
-            } else if value["action"] == "ROOM_JOIN" {
-                let room_id = value["room_id"]
-                    .as_str()
-                    .and_then(|s| Uuid::from_str(s).ok());
-                if let Some(room_id) = room_id {
-                    println!("ROOM_JOIN {}", room_id);
-                    self.join_room(&room_id).await;
-                }
-            } else if value["action"] == "ROOM_CLIENTS_LIST" {
-                let room_id = value["room_id"]
-                    .as_str()
-                    .and_then(|s| Uuid::from_str(s).ok());
-                if let Some(room_id) = room_id {
-                    self.send_room_clients_list(&room_id).await;
-                }
-            } else if value["target"]["type"] == "ROOM" {
-                let room_id = value["target"]["id"]
-                    .as_str()
-                    .and_then(|s| Uuid::from_str(s).ok());
-                if let Some(room_id) = room_id {
-                    if let Some(room) = manager.get_room(&room_id).await {
-                        room.exec(value, Some(self.get_id())).await;
-                    }
-                }
+                Ok(())
+            }
+            "ROOM_JOIN" => {
+                let room_id = room_id?;
+                println!("ROOM_JOIN {}", room_id);
+                let password = value["password"].as_str();
+                self.join_room_with_password(&room_id, password).await
+            }
+            "ROOM_LEAVE" => {
+                let room_id = room_id?;
+                println!("ROOM_LEAVE {}", room_id);
+                self.leave_room(&room_id).await;
+                Ok(())
+            }
+            "SEARCH" => {
+                let query = serde_json::from_value::<SearchQuery>(value.clone()).map_err(|e| {
+                    println!("{} invalid search {}", self.get_id(), e);
+                    RoomError::InvalidRequest
+                })?;
+                self.send_search_results(&query).await;
+                Ok(())
+            }
+            "ROOM_CLIENTS_LIST" => {
+                self.send_room_clients_list(&room_id?).await;
+                Ok(())
+            }
+            "COMMAND" => {
+                let (name, args) = command.ok_or(RoomError::InvalidRequest)?;
+                let room = manager
+                    .get_room(&room_id?)
+                    .await
+                    .ok_or(RoomError::NotFound)?;
+                self.exec_command(manager, room, name, args).await
             }
+            _ if value["target"]["type"] == "ROOM" => {
+                let room = manager
+                    .get_room(&room_id?)
+                    .await
+                    .ok_or(RoomError::NotFound)?;
+                room.exec(&unescape_command(value), Some(self.get_id()))
+                    .await
+            }
+            _ => Err(RoomError::InvalidRequest),
+        }
+    }
+
+    async fn create_room(&self, manager: &Arc<ChatEngine>, value: &Value) -> Result<(), RoomError> {
+        let limits = &manager.get_config().limits;
+        // Checked before creating the room, which would be left empty
+        // if its creator could not join it
+        let too_many_joined =
+            limits.max_joined_rooms > 0 && self.rooms.read().await.len() >= limits.max_joined_rooms;
+        let too_many_created = limits.max_created_rooms > 0
+            && manager.count_rooms_created_by(&self.id).await >= limits.max_created_rooms;
+        if too_many_joined || too_many_created {
+            return Err(RoomError::TooManyRooms);
         }
+        // Rooms can lower the configured member limit, never raise it
+        let max_members = match (value["max_members"].as_u64(), limits.max_members) {
+            (None | Some(0), _) => None,
+            (Some(requested), 0) => Some(requested as usize),
+            (Some(requested), max_members) => Some((requested as usize).min(max_members)),
+        };
+        let password = match value["password"].as_str() {
+            Some(password) => Some(hash_password(password.to_string()).await?),
+            None => None,
+        };
+        let options = RoomOptions {
+            name: value["name"].as_str().map(str::to_string),
+            read_receipts: value["read_receipts"].as_bool().unwrap_or(true),
+            max_members,
+            password,
+            ..Default::default()
+        };
+        let room = WebSocketRoom::create_room(manager, &self.id, options).await;
+        if let Err(error) = self.join_room(room.get_id()).await {
+            room.close_if_unused().await;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    async fn exec_command(
+        &self,
+        manager: &Arc<ChatEngine>,
+        room: Arc<WebSocketRoom>,
+        name: &str,
+        args: &str,
+    ) -> Result<(), RoomError> {
+        if !room.has_client(&self.id).await {
+            return Err(RoomError::NotMember);
+        }
+        let handler = manager
+            .get_commands()
+            .get(name)
+            .await
+            .ok_or(RoomError::UnknownCommand)?;
+        let client = manager
+            .get_client(&self.id)
+            .await
+            .ok_or(RoomError::NotMember)?;
+        println!("{} client command /{} in {}", self.id, name, room.get_id());
+        let context = CommandContext::new(Arc::clone(manager), client, room, args.to_string());
+        handler.handle(context).await
+    }
+
+    async fn send_error(&self, action: &str, error: RoomError, room_id: &Uuid) {
+        self.send(json!({
+            "type": "EVENT",
+            "event": {
+                "type": "ERROR",
+                "action": action,
+                "error": error.code(),
+                "room_id": room_id.to_string()
+            }
+        }))
+        .await;
     }
 
     async fn send_rooms_list(&self) {
@@ -199,12 +423,38 @@ impl WebSocketClient {
                 .iter()
                 .map(|id| id.to_string())
                 .collect();
+            let mut unread = serde_json::Map::new();
+            for room_id in self.get_client_rooms().await {
+                if let Some(room) = manager.get_room(&room_id).await {
+                    if let Some(count) = room.get_unread(&self.id).await {
+                        unread.insert(room_id.to_string(), json!(count));
+                    }
+                }
+            }
 
             self.send(json!({
                 "type": "EVENT",
                 "event": {
                     "type": "ROOMS_LIST",
-                    "rooms": rooms_list
+                    "rooms": rooms_list,
+                    "unread": unread
+                }
+            }))
+            .await;
+        }
+    }
+
+    /// Results only include rooms the client is currently a member of.
+    async fn send_search_results(&self, query: &SearchQuery) {
+        if let Some(manager) = self.manager.upgrade() {
+            let rooms: HashSet<Uuid> = self.rooms.read().await.clone();
+            let results = manager.search(query, Some(&rooms)).await;
+            self.send(json!({
+                "type": "EVENT",
+                "event": {
+                    "type": "SEARCH_RESULTS",
+                    "query": query.query,
+                    "results": results
                 }
             }))
             .await;
@@ -237,8 +487,144 @@ impl WebSocketClient {
     }
 }
 
+/// Messages starting with `//` are sent with a single leading `/`.
+fn unescape_command(value: &Value) -> Cow<'_, Value> {
+    match value["data"]["message"].as_str() {
+        Some(text) if value["action"] == "BROADCAST" && text.starts_with("//") => {
+            let mut value = value.clone();
+            value["data"]["message"] = json!(text[1..]);
+            Cow::Owned(value)
+        }
+        _ => Cow::Borrowed(value),
+    }
+}
+
+/// The action as logged, without its password, token or the arguments of
+/// a command such as `/join <room_id> <password>`, whether sent as a message
+/// or as a `COMMAND` action.
+fn loggable(value: &Value) -> Cow<'_, Value> {
+    let password = !value["password"].is_null();
+    let token = !value["token"].is_null();
+    let args = value["action"] == "COMMAND" && !value["args"].is_null();
+    let command = value["data"]["message"]
+        .as_str()
+        .and_then(parse_command)
+        .filter(|(_, args)| !args.is_empty());
+    if !password && !token && !args && command.is_none() {
+        return Cow::Borrowed(value);
+    }
+    let mut logged = value.clone();
+    if password {
+        logged["password"] = json!("[redacted]");
+    }
+    if token {
+        logged["token"] = json!("[redacted]");
+    }
+    if args {
+        logged["args"] = json!("[redacted]");
+    }
+    if let Some((name, _)) = command {
+        logged["data"]["message"] = json!(format!("/{} [redacted]", name));
+    }
+    Cow::Owned(logged)
+}
+
+/// Writer task of a client, ends when its queue is closed or on the first
+/// failed write and then disconnects the client.
+async fn write(
+    client_id: Uuid,
+    manager: Weak<ChatEngine>,
+    queue: Arc<SendQueue>,
+    mut sender: ClientSender,
+) {
+    while let Some(frame) = queue.pop().await {
+        let result = match &mut sender {
+            ClientSender::WebSocket(websocket_sender, format) => match frame.to_message(*format) {
+                Ok(message) => websocket_sender
+                    .send(message)
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => {
+                    println!(
+                        "{} failed to encode message {} {}",
+                        client_id,
+                        e,
+                        frame.kind()
+                    );
+                    Ok(())
+                }
+            },
+            ClientSender::EventStream(event_sender) => event_sender
+                .send(Arc::clone(&frame))
+                .await
+                .map_err(|_| "event stream closed".to_string()),
+        };
+        if let Err(e) = result {
+            println!(
+                "{} failed to send message {} {}",
+                client_id,
+                e,
+                frame.kind()
+            );
+            queue.close();
+            break;
+        }
+    }
+    if let ClientSender::WebSocket(websocket_sender, _) = &mut sender {
+        websocket_sender.close().await.unwrap_or_else(|e| {
+            println!("{} failed to close socket {}", client_id, e);
+        });
+    }
+    if let Some(manager) = manager.upgrade() {
+        manager.remove_client(&client_id).await;
+    }
+    println!("{} client writer task exit", client_id);
+}
+
 impl Drop for WebSocketClient {
     fn drop(&mut self) {
+        self.queue.close();
         println!("{} client dropped", self.get_id());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::loggable;
+
+    #[test]
+    fn loggable_redacts_secrets() {
+        let join = json!({ "action": "ROOM_JOIN", "room_id": "1", "password": "secret" });
+        assert_eq!(loggable(&join)["password"], "[redacted]");
+        assert_eq!(loggable(&join)["room_id"], "1");
+
+        let resume = json!({ "action": "CLIENT_RESUME", "token": "secret" });
+        assert_eq!(loggable(&resume)["token"], "[redacted]");
+
+        let command = json!({ "action": "COMMAND", "name": "join", "args": "1 secret" });
+        assert_eq!(loggable(&command)["args"], "[redacted]");
+        assert_eq!(loggable(&command)["name"], "join");
+
+        let message = json!({
+            "action": "BROADCAST",
+            "data": { "type": "MESSAGE", "message": "/join 1 secret" }
+        });
+        assert_eq!(loggable(&message)["data"]["message"], "/join [redacted]");
+    }
+
+    #[test]
+    fn loggable_keeps_other_actions() {
+        let message = json!({
+            "action": "BROADCAST",
+            "data": { "type": "MESSAGE", "message": "hello" }
+        });
+        assert_eq!(*loggable(&message), message);
+        let command = json!({ "action": "COMMAND", "name": "help" });
+        assert_eq!(*loggable(&command), command);
+        // Only the arguments of commands are secret
+        let other = json!({ "action": "SEARCH", "args": "visible" });
+        assert_eq!(*loggable(&other), other);
+    }
+}
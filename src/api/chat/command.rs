@@ -0,0 +1,508 @@
+use std::{collections::BTreeMap, str::FromStr, sync::Arc};
+
+use futures_util::future::BoxFuture;
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use super::{
+    audit::AuditEntry,
+    backplane::ClusterEvent,
+    client::WebSocketClient,
+    engine::ChatEngine,
+    room::{RoomError, RoomRole, WebSocketRoom},
+};
+
+/// Everything a command handler can act on: the client that typed the
+/// command, the room it was typed in and the text after the command name.
+pub struct CommandContext {
+    pub client: Arc<WebSocketClient>,
+    pub room: Arc<WebSocketRoom>,
+    pub args: String,
+    engine: Arc<ChatEngine>,
+}
+
+impl CommandContext {
+    pub(super) fn new(
+        engine: Arc<ChatEngine>,
+        client: Arc<WebSocketClient>,
+        room: Arc<WebSocketRoom>,
+        args: String,
+    ) -> Self {
+        Self {
+            client,
+            room,
+            args,
+            engine,
+        }
+    }
+    /// Sends `value` to the client that typed the command only.
+    pub async fn reply(&self, value: Value) {
+        self.client.send(value).await;
+    }
+    /// Sends `value` to every member of the room.
+    pub async fn broadcast(&self, value: Value) {
+        self.room.broadcast(value).await;
+    }
+    pub async fn get_client(&self, client_id: &Uuid) -> Option<Arc<WebSocketClient>> {
+        self.engine.get_client(client_id).await
+    }
+    pub async fn is_moderator(&self) -> bool {
+        self.room.is_moderator(self.client.get_id()).await
+    }
+    async fn require_moderator(&self) -> Result<(), RoomError> {
+        if self.is_moderator().await {
+            Ok(())
+        } else {
+            Err(RoomError::Forbidden)
+        }
+    }
+    fn client_arg(&self) -> Result<Uuid, RoomError> {
+        self.args
+            .split_whitespace()
+            .next()
+            .and_then(|s| Uuid::from_str(s).ok())
+            .ok_or(RoomError::InvalidRequest)
+    }
+}
+
+/// A server side handler for messages starting with `/name`.
+pub trait CommandHandler: Send + Sync {
+    fn name(&self) -> &str;
+    fn usage(&self) -> &str;
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>>;
+}
+
+pub struct CommandRegistry {
+    handlers: RwLock<BTreeMap<String, Arc<dyn CommandHandler>>>,
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        let builtins: [Arc<dyn CommandHandler>; 11] = [
+            Arc::new(Help),
+            Arc::new(Nick),
+            Arc::new(Topic),
+            Arc::new(Invite),
+            Arc::new(Kick),
+            Arc::new(Op),
+            Arc::new(Deop),
+            Arc::new(Me),
+            Arc::new(Who),
+            Arc::new(Join),
+            Arc::new(Leave),
+        ];
+        let handlers = builtins
+            .into_iter()
+            .map(|handler| (handler.name().to_string(), handler))
+            .collect();
+        Self {
+            handlers: RwLock::new(handlers),
+        }
+    }
+}
+
+impl CommandRegistry {
+    /// Registers a handler, replacing any handler with the same name.
+    pub async fn register(&self, handler: Arc<dyn CommandHandler>) {
+        self.handlers
+            .write()
+            .await
+            .insert(handler.name().to_string(), handler);
+    }
+    pub async fn get(&self, name: &str) -> Option<Arc<dyn CommandHandler>> {
+        self.handlers.read().await.get(name).cloned()
+    }
+    pub async fn get_usage_list(&self) -> Vec<String> {
+        self.handlers
+            .read()
+            .await
+            .values()
+            .map(|handler| handler.usage().to_string())
+            .collect()
+    }
+}
+
+/// Splits `/name args` into its name and arguments, `None` if `text` is not a
+/// command. A leading `//` escapes a message that should start with `/`.
+pub fn parse_command(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_end_matches('\n').strip_prefix('/')?;
+    if text.starts_with('/') {
+        return None;
+    }
+    let (name, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    Some((name, args.trim()))
+}
+
+fn event(room: &WebSocketRoom, event_type: &str, mut event: Value) -> Value {
+    event["type"] = json!(event_type);
+    event["room_id"] = json!(room.get_id().to_string());
+    json!({ "type": "EVENT", "event": event })
+}
+
+fn role_name(role: Option<RoomRole>) -> &'static str {
+    match role {
+        Some(RoomRole::Owner) => "OWNER",
+        Some(RoomRole::Moderator) => "MODERATOR",
+        None => "MEMBER",
+    }
+}
+
+struct Help;
+
+impl CommandHandler for Help {
+    fn name(&self) -> &str {
+        "help"
+    }
+    fn usage(&self) -> &str {
+        "/help"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(async move {
+            let commands = context.engine.get_commands().get_usage_list().await;
+            context
+                .reply(event(
+                    &context.room,
+                    "COMMAND_HELP",
+                    json!({ "commands": commands }),
+                ))
+                .await;
+            Ok(())
+        })
+    }
+}
+
+struct Nick;
+
+impl CommandHandler for Nick {
+    fn name(&self) -> &str {
+        "nick"
+    }
+    fn usage(&self) -> &str {
+        "/nick <name>"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(async move {
+            let nick = context.args.trim();
+            if nick.is_empty() || nick.chars().count() > 32 || nick.contains(char::is_whitespace) {
+                return Err(RoomError::InvalidRequest);
+            }
+            context.client.set_nick(nick.to_string()).await;
+            let client_id = context.client.get_id().to_string();
+            for room_id in context.client.get_client_rooms().await {
+                if let Some(room) = context.engine.get_room(&room_id).await {
+                    room.broadcast(event(
+                        &room,
+                        "NICK_CHANGE",
+                        json!({ "client_id": client_id, "nick": nick }),
+                    ))
+                    .await;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+struct Topic;
+
+impl CommandHandler for Topic {
+    fn name(&self) -> &str {
+        "topic"
+    }
+    fn usage(&self) -> &str {
+        "/topic [text]"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(async move {
+            if context.args.is_empty() {
+                let topic = context.room.get_topic().await;
+                context
+                    .reply(event(
+                        &context.room,
+                        "ROOM_TOPIC",
+                        json!({ "topic": topic }),
+                    ))
+                    .await;
+                return Ok(());
+            }
+            context.require_moderator().await?;
+            context.room.set_topic(Some(context.args.clone())).await;
+            context
+                .broadcast(event(
+                    &context.room,
+                    "ROOM_TOPIC",
+                    json!({
+                        "topic": context.args,
+                        "client_id": context.client.get_id().to_string()
+                    }),
+                ))
+                .await;
+            Ok(())
+        })
+    }
+}
+
+struct Invite;
+
+impl CommandHandler for Invite {
+    fn name(&self) -> &str {
+        "invite"
+    }
+    fn usage(&self) -> &str {
+        "/invite <client_id>"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(async move {
+            let invitee = context
+                .get_client(&context.client_arg()?)
+                .await
+                .ok_or(RoomError::InvalidRequest)?;
+            invitee
+                .send(event(
+                    &context.room,
+                    "ROOM_INVITE",
+                    json!({
+                        "client_id": context.client.get_id().to_string(),
+                        "name": context.room.get_name()
+                    }),
+                ))
+                .await;
+            Ok(())
+        })
+    }
+}
+
+struct Kick;
+
+impl CommandHandler for Kick {
+    fn name(&self) -> &str {
+        "kick"
+    }
+    fn usage(&self) -> &str {
+        "/kick <client_id> [reason]"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(async move {
+            context.require_moderator().await?;
+            let target_id = context.client_arg()?;
+            if !context.room.has_client(&target_id).await {
+                return Err(RoomError::NotMember);
+            }
+            if context.room.get_role(&target_id).await == Some(RoomRole::Owner) {
+                return Err(RoomError::Forbidden);
+            }
+            let reason = context
+                .args
+                .split_once(char::is_whitespace)
+                .map(|(_, reason)| reason.trim().to_string());
+            let mut entry = AuditEntry::new(
+                context.client.get_id().to_string(),
+                "ROOM_KICK",
+                Some(*context.room.get_id()),
+                Some(target_id.to_string()),
+            );
+            entry.details = json!({ "reason": reason });
+            context.engine.audit(entry);
+            context
+                .broadcast(event(
+                    &context.room,
+                    "ROOM_KICK",
+                    json!({
+                        "client_id": target_id.to_string(),
+                        "moderator_id": context.client.get_id().to_string(),
+                        "reason": reason
+                    }),
+                ))
+                .await;
+            match context.get_client(&target_id).await {
+                Some(target) => target.leave_room(context.room.get_id()).await,
+                None => context.room.remove_client(&target_id).await,
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Gives a member of the room the moderator role, or takes it away. Only the
+/// owner of the room can, and the owner keeps its role.
+async fn set_moderator(context: CommandContext, moderator: bool) -> Result<(), RoomError> {
+    let owner_id = context.client.get_id();
+    if context.room.get_role(owner_id).await != Some(RoomRole::Owner) {
+        return Err(RoomError::Forbidden);
+    }
+    let target_id = context.client_arg()?;
+    if !context.room.get_clients_list().await.contains(&target_id) {
+        return Err(RoomError::NotMember);
+    }
+    if context.room.get_role(&target_id).await == Some(RoomRole::Owner) {
+        return Err(RoomError::Forbidden);
+    }
+    let role = moderator.then_some(RoomRole::Moderator);
+    context.room.set_role(&target_id, role).await;
+    let mut entry = AuditEntry::new(
+        owner_id.to_string(),
+        "ROOM_ROLE",
+        Some(*context.room.get_id()),
+        Some(target_id.to_string()),
+    );
+    entry.details = json!({ "role": role_name(role) });
+    context.engine.audit(entry);
+    context
+        .engine
+        .publish(ClusterEvent::RoleChanged {
+            room_id: *context.room.get_id(),
+            client_id: target_id,
+            role,
+        })
+        .await;
+    context
+        .broadcast(event(
+            &context.room,
+            "ROOM_ROLE",
+            json!({
+                "client_id": target_id.to_string(),
+                "role": role_name(role),
+                "owner_id": owner_id.to_string()
+            }),
+        ))
+        .await;
+    Ok(())
+}
+
+struct Op;
+
+impl CommandHandler for Op {
+    fn name(&self) -> &str {
+        "op"
+    }
+    fn usage(&self) -> &str {
+        "/op <client_id>"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(set_moderator(context, true))
+    }
+}
+
+struct Deop;
+
+impl CommandHandler for Deop {
+    fn name(&self) -> &str {
+        "deop"
+    }
+    fn usage(&self) -> &str {
+        "/deop <client_id>"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(set_moderator(context, false))
+    }
+}
+
+struct Me;
+
+impl CommandHandler for Me {
+    fn name(&self) -> &str {
+        "me"
+    }
+    fn usage(&self) -> &str {
+        "/me <action>"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(async move {
+            if context.args.is_empty() {
+                return Err(RoomError::InvalidRequest);
+            }
+            context
+                .room
+                .exec(
+                    &json!({
+                        "action": "BROADCAST",
+                        "data": {
+                            "type": "MESSAGE",
+                            "message": context.args,
+                            "emote": true
+                        }
+                    }),
+                    Some(context.client.get_id()),
+                )
+                .await
+        })
+    }
+}
+
+struct Who;
+
+impl CommandHandler for Who {
+    fn name(&self) -> &str {
+        "who"
+    }
+    fn usage(&self) -> &str {
+        "/who"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(async move {
+            let mut clients = Vec::new();
+            for client_id in context.room.get_clients_list().await {
+                let nick = match context.get_client(&client_id).await {
+                    Some(client) => client.get_nick().await,
+                    None => None,
+                };
+                clients.push(json!({
+                    "client_id": client_id.to_string(),
+                    "nick": nick,
+                    "role": role_name(context.room.get_role(&client_id).await)
+                }));
+            }
+            context
+                .reply(event(
+                    &context.room,
+                    "ROOM_WHO",
+                    json!({ "clients": clients }),
+                ))
+                .await;
+            Ok(())
+        })
+    }
+}
+
+struct Join;
+
+impl CommandHandler for Join {
+    fn name(&self) -> &str {
+        "join"
+    }
+    fn usage(&self) -> &str {
+        "/join <room_id> [password]"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(async move {
+            let mut args = context.args.splitn(2, char::is_whitespace);
+            let room_id = args
+                .next()
+                .and_then(|s| Uuid::from_str(s).ok())
+                .ok_or(RoomError::InvalidRequest)?;
+            let password = args.next().map(str::trim).filter(|s| !s.is_empty());
+            context
+                .client
+                .join_room_with_password(&room_id, password)
+                .await
+        })
+    }
+}
+
+struct Leave;
+
+impl CommandHandler for Leave {
+    fn name(&self) -> &str {
+        "leave"
+    }
+    fn usage(&self) -> &str {
+        "/leave"
+    }
+    fn handle(&self, context: CommandContext) -> BoxFuture<'_, Result<(), RoomError>> {
+        Box::pin(async move {
+            context.client.leave_room(context.room.get_id()).await;
+            Ok(())
+        })
+    }
+}
@@ -1,52 +1,250 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
 
-use futures_util::stream::SplitSink;
+use futures_util::StreamExt;
 use serde_json::{json, Value};
-use tokio::sync::{
-    broadcast::{self, Receiver, Sender},
-    Mutex, RwLock,
+use tokio::{
+    spawn,
+    sync::{
+        broadcast::{self, Receiver, Sender},
+        mpsc, Mutex, RwLock,
+    },
+    time::sleep,
 };
 use uuid::Uuid;
-use warp::filters::ws::{Message, WebSocket};
 
-use super::{client::WebSocketClient, room::WebSocketRoom};
+use crate::config::ChatConfig;
+
+use super::{
+    attachment::{Attachment, BlobStore, LocalBlobStore},
+    audit::{AuditEntry, AuditLog, AuditStore, FileAuditStore, MemoryAuditStore, SERVER_ACTOR},
+    backplane::{Backplane, BackplaneMessage, ClusterEvent, RedisBackplane},
+    client::{ClientSender, WebSocketClient},
+    command::CommandRegistry,
+    filter::FilterChain,
+    message::now_millis,
+    report::{Report, ReportAction, ReportQueue, ReportStatus},
+    room::{RoomError, RoomOptions, WebSocketRoom},
+    search::{SearchDocument, SearchIndex, SearchQuery},
+    snapshot::{FileSnapshotStore, Snapshot, SnapshotStore},
+    token::ClientTokens,
+    webhook::WebhookDispatcher,
+    wire::Frame,
+};
+
+const SESSION_QUEUE_SIZE: usize = 256;
+const BACKPLANE_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 pub(super) struct ChatEngine {
     rooms: RwLock<HashMap<Uuid, Arc<WebSocketRoom>>>,
     clients: RwLock<HashMap<Uuid, Arc<WebSocketClient>>>,
-    sender: Mutex<Sender<Value>>,
+    // Session id of event stream clients to their client id
+    sessions: RwLock<HashMap<Uuid, Uuid>>,
+    attachments: RwLock<HashMap<Uuid, Attachment>>,
+    search: RwLock<SearchIndex>,
+    sender: Mutex<Sender<Arc<Frame>>>,
+    blob_store: Arc<dyn BlobStore>,
+    webhooks: WebhookDispatcher,
+    commands: CommandRegistry,
+    filters: FilterChain,
+    reports: RwLock<ReportQueue>,
+    audit: AuditLog,
+    tokens: ClientTokens,
+    // Shares rooms with the other nodes of a cluster, single node when unset
+    backplane: Option<Arc<dyn Backplane>>,
+    node_id: Uuid,
+    snapshot_store: Option<Arc<dyn SnapshotStore>>,
+    config: ChatConfig,
 }
 
 impl Default for ChatEngine {
     fn default() -> Self {
+        Self::new(ChatConfig::default())
+    }
+}
+
+impl ChatEngine {
+    pub(super) fn new(config: ChatConfig) -> Self {
+        let blob_store = Arc::new(LocalBlobStore::new(config.uploads.directory.clone()));
+        Self::with_blob_store(config, blob_store)
+    }
+    pub(super) fn with_blob_store(config: ChatConfig, blob_store: Arc<dyn BlobStore>) -> Self {
         let (sender, _) = broadcast::channel(1);
+        let backplane = config.cluster.redis.clone().map(|address| {
+            Arc::new(RedisBackplane::new(address, config.cluster.channel.clone()))
+                as Arc<dyn Backplane>
+        });
+        let snapshot_store = config
+            .snapshot
+            .path
+            .clone()
+            .map(|path| Arc::new(FileSnapshotStore::new(path)) as Arc<dyn SnapshotStore>);
+        let audit_store: Arc<dyn AuditStore> = match config.audit.path.clone() {
+            Some(path) => Arc::new(FileAuditStore::new(path)),
+            None => Arc::new(MemoryAuditStore::new(config.audit.memory_limit)),
+        };
         Self {
             clients: RwLock::new(HashMap::new()),
+            sessions: RwLock::new(HashMap::new()),
             rooms: RwLock::new(HashMap::new()),
+            attachments: RwLock::new(HashMap::new()),
+            search: RwLock::new(SearchIndex::new(config.search_limit)),
             sender: Mutex::new(sender),
+            blob_store,
+            webhooks: WebhookDispatcher::new(config.webhooks.clone()),
+            commands: CommandRegistry::default(),
+            filters: FilterChain::new(&config.moderation),
+            reports: RwLock::new(ReportQueue::new(
+                config.moderation.report_limit,
+                config.moderation.max_open_reports,
+            )),
+            audit: AuditLog::new(&config.audit, audit_store),
+            tokens: ClientTokens::new(),
+            backplane,
+            node_id: Uuid::new_v4(),
+            snapshot_store,
+            config,
         }
     }
-}
-
-impl ChatEngine {
+    pub(super) fn set_backplane(&mut self, backplane: Arc<dyn Backplane>) {
+        self.backplane = Some(backplane);
+    }
+    pub(super) fn set_snapshot_store(&mut self, snapshot_store: Arc<dyn SnapshotStore>) {
+        self.snapshot_store = Some(snapshot_store);
+    }
+    pub(super) fn set_audit_store(&mut self, audit_store: Arc<dyn AuditStore>) {
+        self.audit = AuditLog::new(&self.config.audit, audit_store);
+    }
+    pub(super) fn get_config(&self) -> &ChatConfig {
+        &self.config
+    }
+    pub(super) fn get_blob_store(&self) -> &Arc<dyn BlobStore> {
+        &self.blob_store
+    }
+    pub(super) fn get_webhooks(&self) -> &WebhookDispatcher {
+        &self.webhooks
+    }
+    pub(super) fn get_commands(&self) -> &CommandRegistry {
+        &self.commands
+    }
+    pub(super) fn get_filters(&self) -> &FilterChain {
+        &self.filters
+    }
+    pub(super) fn get_audit(&self) -> &AuditLog {
+        &self.audit
+    }
+    pub(super) fn audit(&self, entry: AuditEntry) {
+        self.audit.record(entry);
+    }
+    pub(super) fn get_tokens(&self) -> &ClientTokens {
+        &self.tokens
+    }
+    /// Files a report, returning whether it is new or the reporter already
+    /// had an open report about the same target.
+    pub(super) async fn file_report(&self, report: Report) -> Result<(Report, bool), RoomError> {
+        self.reports.write().await.file(report)
+    }
+    pub(super) async fn get_report(&self, report_id: &Uuid) -> Option<Report> {
+        self.reports.read().await.get(report_id).cloned()
+    }
+    pub(super) async fn get_reports(&self, status: Option<ReportStatus>) -> Vec<Report> {
+        self.reports.read().await.list(status)
+    }
+    pub(super) async fn close_report(
+        &self,
+        report_id: &Uuid,
+        action: ReportAction,
+        note: Option<String>,
+    ) -> Option<Report> {
+        self.reports.write().await.close(report_id, action, note)
+    }
+    pub(super) async fn get_attachment(&self, attachment_id: &Uuid) -> Option<Attachment> {
+        self.attachments.read().await.get(attachment_id).cloned()
+    }
+    pub(super) async fn attachment_add(&self, attachment: Attachment) {
+        self.attachments
+            .write()
+            .await
+            .insert(attachment.id, attachment);
+    }
+    pub(super) async fn index_message(&self, document: SearchDocument) {
+        self.search.write().await.insert(document);
+    }
+    pub(super) async fn index_update(&self, message_id: &Uuid, text: String) {
+        self.search.write().await.update_text(message_id, text);
+    }
+    pub(super) async fn index_remove(&self, message_id: &Uuid) {
+        self.search.write().await.remove(message_id);
+    }
+    pub(super) async fn search(
+        &self,
+        query: &SearchQuery,
+        rooms: Option<&HashSet<Uuid>>,
+    ) -> Vec<Value> {
+        self.search
+            .read()
+            .await
+            .search(query, rooms)
+            .iter()
+            .map(|document| document.to_value())
+            .collect()
+    }
     pub(super) async fn get_room(&self, room_id: &Uuid) -> Option<Arc<WebSocketRoom>> {
         self.rooms.read().await.get(room_id).cloned()
     }
     pub(super) async fn get_client(&self, client_id: &Uuid) -> Option<Arc<WebSocketClient>> {
         self.clients.read().await.get(client_id).cloned()
     }
+    pub(super) async fn get_clients_list(&self) -> Vec<Arc<WebSocketClient>> {
+        self.clients.read().await.values().cloned().collect()
+    }
 
     pub(super) async fn create_client(
         engine: &Arc<ChatEngine>,
-        websocket_sender: SplitSink<WebSocket, Message>,
+        sender: ClientSender,
+        ip: Option<IpAddr>,
     ) -> Arc<WebSocketClient> {
         let mut clients = engine.clients.write().await;
+        let config = engine.config.send_queue.clone();
         let engine = Arc::downgrade(engine);
-        let client = Arc::new(WebSocketClient::new(engine, websocket_sender));
+        let client = Arc::new(WebSocketClient::new(engine, sender, config, ip));
         clients.insert(*client.get_id(), Arc::clone(&client));
         println!("{} websocket engine created client", client.get_id());
         client
     }
+    /// Creates a client whose events are read from the returned receiver,
+    /// the session id is the secret used to post actions on its behalf.
+    pub(super) async fn create_session(
+        engine: &Arc<ChatEngine>,
+        ip: Option<IpAddr>,
+    ) -> (Uuid, Arc<WebSocketClient>, mpsc::Receiver<Arc<Frame>>) {
+        let (sender, receiver) = mpsc::channel(SESSION_QUEUE_SIZE);
+        let client = Self::create_client(engine, ClientSender::EventStream(sender), ip).await;
+        let session_id = Uuid::new_v4();
+        engine
+            .sessions
+            .write()
+            .await
+            .insert(session_id, *client.get_id());
+        (session_id, client, receiver)
+    }
+    pub(super) async fn get_session_client(
+        &self,
+        session_id: &Uuid,
+    ) -> Option<Arc<WebSocketClient>> {
+        let client_id = *self.sessions.read().await.get(session_id)?;
+        self.get_client(&client_id).await
+    }
+    pub(super) async fn remove_session(&self, session_id: &Uuid) {
+        let client_id = self.sessions.write().await.remove(session_id);
+        if let Some(client_id) = client_id {
+            self.remove_client(&client_id).await;
+        }
+    }
     pub(super) async fn remove_client(&self, client_id: &Uuid) {
         if let Some(client) = self.get_client(client_id).await {
             let mut clients = self.clients.write().await;
@@ -54,19 +252,43 @@ impl ChatEngine {
             clients.remove(client_id);
             for room_id in rooms {
                 if let Some(room) = self.get_room(&room_id).await {
-                    room.remove_client(client_id).await;
+                    room.disconnect_client(client_id).await;
                 }
             }
             println!("{} websocket engine removed client", client_id);
         }
     }
-    pub(super) async fn get_listener(&self) -> Receiver<Value> {
+    pub(super) async fn get_listener(&self) -> Receiver<Arc<Frame>> {
         self.sender.lock().await.subscribe()
     }
     pub(super) async fn get_rooms_list(&self) -> Vec<Uuid> {
         self.rooms.read().await.keys().cloned().collect()
     }
+    pub(super) async fn count_rooms_created_by(&self, client_id: &Uuid) -> usize {
+        self.rooms
+            .read()
+            .await
+            .values()
+            .filter(|room| room.get_creator() == client_id)
+            .count()
+    }
     pub(super) async fn room_add(&self, room: &Arc<WebSocketRoom>) {
+        let message = self.room_insert(room).await;
+        let creator = room.get_creator();
+        let actor = if creator.is_nil() {
+            SERVER_ACTOR.to_string()
+        } else {
+            creator.to_string()
+        };
+        let mut entry = AuditEntry::new(actor, "ROOM_CREATION", Some(*room.get_id()), None);
+        entry.details = message["event"].clone();
+        self.audit(entry);
+        self.webhooks.dispatch(room.get_id(), &message).await;
+        self.publish(room_created_event(room).await).await;
+    }
+    /// Registers a room and tells the local subscribers, without notifying
+    /// webhooks or other nodes, which is done by the node creating it.
+    pub(super) async fn room_insert(&self, room: &Arc<WebSocketRoom>) -> Value {
         {
             let mut rooms = self.rooms.write().await;
             rooms.insert(*room.get_id(), Arc::clone(room));
@@ -76,20 +298,58 @@ impl ChatEngine {
             "event": {
                 "type": "ROOM_CREATION",
                 "room_id": room.get_id().to_string(),
-                "creator_id": room.get_creator().to_string()
+                "creator_id": room.get_creator().to_string(),
+                "name": room.get_name(),
+                "persistent": room.is_persistent(),
+                "password_protected": room.is_password_protected().await
             }
         });
         //TODO: Avoid lof error if no one is listening
-        self.sender.lock().await.send(message).unwrap_or_else(|e| {
+        let frame = Arc::new(Frame::new(message.clone()));
+        self.sender.lock().await.send(frame).unwrap_or_else(|e| {
             println!("{} engine room add broadcast error {}", room.get_id(), e);
             0
         });
+        message
     }
     pub(super) async fn room_remove(&self, room_id: &Uuid) {
+        let message = self.room_drop(room_id).await;
+        self.audit(AuditEntry::new(
+            SERVER_ACTOR.to_string(),
+            "ROOM_REMOVAL",
+            Some(*room_id),
+            None,
+        ));
+        self.webhooks.dispatch(room_id, &message).await;
+        self.publish(ClusterEvent::RoomRemoved { room_id: *room_id })
+            .await;
+    }
+    /// Removes a room with its attachments and search documents, the local
+    /// counterpart of `room_insert`.
+    pub(super) async fn room_drop(&self, room_id: &Uuid) -> Value {
         {
             let mut rooms = self.rooms.write().await;
             rooms.remove(room_id);
         }
+        let attachments: Vec<Uuid> = {
+            let mut attachments = self.attachments.write().await;
+            let removed = attachments
+                .values()
+                .filter(|attachment| &attachment.room_id == room_id)
+                .map(|attachment| attachment.id)
+                .collect();
+            attachments.retain(|_, attachment| &attachment.room_id != room_id);
+            removed
+        };
+        self.search.write().await.remove_room(room_id);
+        for attachment_id in attachments {
+            self.blob_store
+                .delete(&attachment_id)
+                .await
+                .unwrap_or_else(|e| {
+                    println!("{} engine attachment removal error {}", attachment_id, e)
+                });
+        }
         let message = json!({
             "type": "EVENT",
             "event": {
@@ -97,9 +357,202 @@ impl ChatEngine {
                 "room_id": room_id.to_string()
             }
         });
-        self.sender.lock().await.send(message).unwrap_or_else(|e| {
+        let frame = Arc::new(Frame::new(message.clone()));
+        self.sender.lock().await.send(frame).unwrap_or_else(|e| {
             println!("{} engine room remove broadcast error {}", room_id, e);
             0
         });
+        message
+    }
+
+    pub(super) fn is_clustered(&self) -> bool {
+        self.backplane.is_some()
+    }
+    pub(super) async fn publish(&self, event: ClusterEvent) {
+        if let Some(backplane) = &self.backplane {
+            let message = BackplaneMessage {
+                node_id: self.node_id,
+                event,
+            };
+            backplane.publish(&message).await.unwrap_or_else(|e| {
+                println!("{} backplane publish error {}", self.node_id, e);
+            });
+        }
+    }
+    /// Follows the events of the other nodes, subscribing again whenever
+    /// the backplane connection is lost.
+    pub(super) fn start_backplane(engine: &Arc<ChatEngine>) {
+        let backplane = match &engine.backplane {
+            Some(backplane) => Arc::clone(backplane),
+            None => return,
+        };
+        let node_id = engine.node_id;
+        let engine = Arc::downgrade(engine);
+        spawn(async move {
+            loop {
+                match backplane.subscribe().await {
+                    Ok(mut messages) => {
+                        println!("{} backplane subscribed", node_id);
+                        match engine.upgrade() {
+                            Some(engine) => engine.publish(ClusterEvent::NodeUp).await,
+                            None => break,
+                        }
+                        while let Some(message) = messages.next().await {
+                            match engine.upgrade() {
+                                Some(engine) => {
+                                    ChatEngine::handle_cluster_message(&engine, message).await
+                                }
+                                None => return,
+                            }
+                        }
+                        println!("{} backplane subscription closed", node_id);
+                    }
+                    Err(e) => println!("{} backplane subscribe error {}", node_id, e),
+                }
+                sleep(BACKPLANE_RETRY_DELAY).await;
+            }
+        });
+    }
+    async fn handle_cluster_message(engine: &Arc<ChatEngine>, message: BackplaneMessage) {
+        if message.node_id == engine.node_id {
+            return;
+        }
+        match message.event {
+            ClusterEvent::NodeUp => {
+                let rooms: Vec<Arc<WebSocketRoom>> =
+                    engine.rooms.read().await.values().cloned().collect();
+                for room in rooms {
+                    engine.publish(room_created_event(&room).await).await;
+                    for client_id in room.get_local_clients_list().await {
+                        engine
+                            .publish(ClusterEvent::MemberJoined {
+                                room_id: *room.get_id(),
+                                client_id,
+                            })
+                            .await;
+                    }
+                    for message in room.get_replicas().await {
+                        engine
+                            .publish(ClusterEvent::MessageStored { message })
+                            .await;
+                    }
+                }
+            }
+            ClusterEvent::RoomCreated {
+                room_id,
+                creator,
+                name,
+                persistent,
+                read_receipts,
+                max_members,
+                password,
+            } => {
+                if engine.get_room(&room_id).await.is_none() {
+                    let options = RoomOptions {
+                        id: Some(room_id),
+                        name,
+                        persistent,
+                        read_receipts,
+                        max_members,
+                        password,
+                    };
+                    WebSocketRoom::create_replica(engine, &creator, options).await;
+                    println!("{} room replicated from {}", room_id, message.node_id);
+                }
+            }
+            ClusterEvent::RoleChanged {
+                room_id,
+                client_id,
+                role,
+            } => {
+                if let Some(room) = engine.get_room(&room_id).await {
+                    room.set_role(&client_id, role).await;
+                }
+            }
+            ClusterEvent::RoomPasswordChanged { room_id, password } => {
+                if let Some(room) = engine.get_room(&room_id).await {
+                    room.set_password(password).await;
+                }
+            }
+            ClusterEvent::RoomRemoved { room_id } => {
+                if let Some(room) = engine.get_room(&room_id).await {
+                    room.close_replica().await;
+                }
+            }
+            ClusterEvent::MemberJoined { room_id, client_id } => {
+                if let Some(room) = engine.get_room(&room_id).await {
+                    room.remote_add(&client_id, &message.node_id).await;
+                }
+            }
+            ClusterEvent::MemberLeft { room_id, client_id } => {
+                if let Some(room) = engine.get_room(&room_id).await {
+                    room.remote_remove(&client_id).await;
+                }
+            }
+            ClusterEvent::RoomBroadcast { room_id, value } => {
+                if let Some(room) = engine.get_room(&room_id).await {
+                    room.deliver(Arc::new(Frame::new(value))).await;
+                }
+            }
+            ClusterEvent::MessageStored { message } => {
+                if let Some(room) = engine.get_room(&message.room_id).await {
+                    room.store_replica(message).await;
+                }
+            }
+        }
+    }
+
+    pub(super) async fn load_snapshot(&self) -> Option<Snapshot> {
+        let snapshot_store = self.snapshot_store.as_ref()?;
+        match snapshot_store.load().await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                println!("engine snapshot load error {}", e);
+                None
+            }
+        }
+    }
+    pub(super) async fn save_snapshot(&self) {
+        if let Some(snapshot_store) = &self.snapshot_store {
+            let rooms: Vec<Arc<WebSocketRoom>> =
+                self.rooms.read().await.values().cloned().collect();
+            let mut snapshots = Vec::with_capacity(rooms.len());
+            for room in rooms {
+                snapshots.push(room.to_snapshot().await);
+            }
+            let snapshot = Snapshot::new(now_millis(), snapshots);
+            match snapshot_store.save(&snapshot).await {
+                Ok(()) => println!("engine snapshot saved {} rooms", snapshot.rooms.len()),
+                Err(e) => println!("engine snapshot save error {}", e),
+            }
+        }
+    }
+    pub(super) fn start_snapshots(engine: &Arc<ChatEngine>) {
+        let interval = engine.config.snapshot.interval();
+        if engine.snapshot_store.is_none() || interval.is_zero() {
+            return;
+        }
+        let engine = Arc::downgrade(engine);
+        spawn(async move {
+            loop {
+                sleep(interval).await;
+                match engine.upgrade() {
+                    Some(engine) => engine.save_snapshot().await,
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+async fn room_created_event(room: &WebSocketRoom) -> ClusterEvent {
+    ClusterEvent::RoomCreated {
+        room_id: *room.get_id(),
+        creator: *room.get_creator(),
+        name: room.get_name().map(str::to_string),
+        persistent: room.is_persistent(),
+        read_receipts: room.has_read_receipts(),
+        max_members: room.get_max_members_override(),
+        password: room.get_password().await,
     }
 }
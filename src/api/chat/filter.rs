@@ -0,0 +1,519 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+};
+
+use futures_util::future::{self, BoxFuture};
+use regex::Regex;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::config::{FilterMode, ModerationConfig};
+
+use super::message::now_millis;
+
+const REDACTED: &str = "***";
+
+/// A message about to be delivered in a room.
+pub struct FilterInput<'a> {
+    pub room_id: &'a Uuid,
+    pub sender: &'a Uuid,
+    pub text: &'a str,
+}
+
+pub enum FilterVerdict {
+    Pass,
+    Reject {
+        reason: String,
+    },
+    /// Delivers `text` in place of the original message
+    Redact {
+        text: String,
+        reason: String,
+    },
+    Flag {
+        reason: String,
+    },
+}
+
+/// A check run on the text of every message sent by a client, in the order
+/// the filters were added. A rejection stops the chain, later filters see
+/// the redacted text.
+pub trait MessageFilter: Send + Sync {
+    fn name(&self) -> &str;
+    fn check<'a>(&'a self, input: FilterInput<'a>) -> BoxFuture<'a, FilterVerdict>;
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ModerationRecord {
+    pub id: Uuid,
+    pub created_at: u64,
+    pub room_id: Uuid,
+    pub client_id: Uuid,
+    pub filter: String,
+    pub action: FilterMode,
+    pub reason: String,
+    /// The text as sent, before any redaction
+    pub text: String,
+}
+
+/// What is left of a message once it passed every filter.
+pub struct FilterOutcome {
+    /// The redacted text, `None` when the message is unchanged
+    pub text: Option<String>,
+    pub flags: Vec<String>,
+}
+
+pub struct FilterChain {
+    filters: RwLock<Vec<Arc<dyn MessageFilter>>>,
+    records: RwLock<VecDeque<ModerationRecord>>,
+    log_limit: usize,
+}
+
+impl FilterChain {
+    pub fn new(config: &ModerationConfig) -> Self {
+        let mut filters: Vec<Arc<dyn MessageFilter>> = Vec::new();
+        if config.max_length > 0 {
+            filters.push(Arc::new(LengthFilter {
+                max_length: config.max_length,
+            }));
+        }
+        if !config.profanity.is_empty() {
+            filters.push(Arc::new(WordFilter {
+                words: config
+                    .profanity
+                    .iter()
+                    .map(|word| word.to_lowercase())
+                    .collect(),
+                mode: config.profanity_action,
+            }));
+        }
+        for rule in &config.rules {
+            match Regex::new(&rule.pattern) {
+                Ok(regex) => filters.push(Arc::new(RegexFilter {
+                    name: rule.name.clone(),
+                    regex,
+                    mode: rule.action,
+                })),
+                Err(e) => println!("moderation rule {} ignored {}", rule.name, e),
+            }
+        }
+        if let Some(mode) = config.links {
+            filters.push(Arc::new(LinkFilter::new(mode, &config.allowed_domains)));
+        }
+        Self {
+            filters: RwLock::new(filters),
+            records: RwLock::new(VecDeque::new()),
+            log_limit: config.log_limit,
+        }
+    }
+    /// Adds a filter run after the configured ones.
+    pub async fn add(&self, filter: Arc<dyn MessageFilter>) {
+        self.filters.write().await.push(filter);
+    }
+    /// Runs every filter on `text`, failing with the record of the filter
+    /// that rejected it.
+    pub async fn run(
+        &self,
+        room_id: &Uuid,
+        sender: &Uuid,
+        text: &str,
+    ) -> Result<FilterOutcome, ModerationRecord> {
+        let filters = self.filters.read().await.clone();
+        let mut outcome = FilterOutcome {
+            text: None,
+            flags: Vec::new(),
+        };
+        for filter in filters {
+            let current = outcome.text.as_deref().unwrap_or(text);
+            let input = FilterInput {
+                room_id,
+                sender,
+                text: current,
+            };
+            let (action, reason) = match filter.check(input).await {
+                FilterVerdict::Pass => continue,
+                FilterVerdict::Reject { reason } => (FilterMode::Reject, reason),
+                FilterVerdict::Redact {
+                    text: redacted,
+                    reason,
+                } => {
+                    outcome.text = Some(redacted);
+                    (FilterMode::Redact, reason)
+                }
+                FilterVerdict::Flag { reason } => {
+                    outcome.flags.push(reason.clone());
+                    (FilterMode::Flag, reason)
+                }
+            };
+            println!(
+                "{} filter {} {:?} message from {} ({})",
+                room_id,
+                filter.name(),
+                action,
+                sender,
+                reason
+            );
+            let record = ModerationRecord {
+                id: Uuid::new_v4(),
+                created_at: now_millis(),
+                room_id: *room_id,
+                client_id: *sender,
+                filter: filter.name().to_string(),
+                action,
+                reason,
+                text: text.to_string(),
+            };
+            self.record(record.clone()).await;
+            if action == FilterMode::Reject {
+                return Err(record);
+            }
+        }
+        Ok(outcome)
+    }
+    async fn record(&self, record: ModerationRecord) {
+        if self.log_limit == 0 {
+            return;
+        }
+        let mut records = self.records.write().await;
+        while records.len() >= self.log_limit {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+    /// Filtered messages, most recent first.
+    pub async fn get_records(&self) -> Vec<ModerationRecord> {
+        self.records.read().await.iter().rev().cloned().collect()
+    }
+}
+
+/// Applies `mode` to a match, `redacted` being the text with the match masked.
+fn verdict(mode: FilterMode, redacted: impl FnOnce() -> String, reason: String) -> FilterVerdict {
+    match mode {
+        FilterMode::Reject => FilterVerdict::Reject { reason },
+        FilterMode::Redact => FilterVerdict::Redact {
+            text: redacted(),
+            reason,
+        },
+        FilterMode::Flag => FilterVerdict::Flag { reason },
+    }
+}
+
+struct LengthFilter {
+    max_length: usize,
+}
+
+impl MessageFilter for LengthFilter {
+    fn name(&self) -> &str {
+        "max_length"
+    }
+    fn check<'a>(&'a self, input: FilterInput<'a>) -> BoxFuture<'a, FilterVerdict> {
+        let length = input.text.chars().count();
+        Box::pin(future::ready(if length > self.max_length {
+            FilterVerdict::Reject {
+                reason: format!("{} characters over {}", length, self.max_length),
+            }
+        } else {
+            FilterVerdict::Pass
+        }))
+    }
+}
+
+struct WordFilter {
+    words: HashSet<String>,
+    mode: FilterMode,
+}
+
+impl WordFilter {
+    /// Whole words of `text`, with their byte offset.
+    fn words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(move |word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+    }
+}
+
+impl MessageFilter for WordFilter {
+    fn name(&self) -> &str {
+        "profanity"
+    }
+    fn check<'a>(&'a self, input: FilterInput<'a>) -> BoxFuture<'a, FilterVerdict> {
+        let text = input.text;
+        let matches: Vec<(usize, &str)> = Self::words(text)
+            .filter(|(_, word)| self.words.contains(&word.to_lowercase()))
+            .collect();
+        let result = match matches.first() {
+            None => FilterVerdict::Pass,
+            Some(_) => verdict(
+                self.mode,
+                || {
+                    let mut redacted = text.to_string();
+                    for (start, word) in matches.iter().rev() {
+                        let mask = "*".repeat(word.chars().count());
+                        redacted.replace_range(*start..start + word.len(), &mask);
+                    }
+                    redacted
+                },
+                format!("{} listed words", matches.len()),
+            ),
+        };
+        Box::pin(future::ready(result))
+    }
+}
+
+struct RegexFilter {
+    name: String,
+    regex: Regex,
+    mode: FilterMode,
+}
+
+impl MessageFilter for RegexFilter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn check<'a>(&'a self, input: FilterInput<'a>) -> BoxFuture<'a, FilterVerdict> {
+        let result = if self.regex.is_match(input.text) {
+            verdict(
+                self.mode,
+                || self.regex.replace_all(input.text, REDACTED).into_owned(),
+                format!("matched rule {}", self.name),
+            )
+        } else {
+            FilterVerdict::Pass
+        };
+        Box::pin(future::ready(result))
+    }
+}
+
+struct LinkFilter {
+    regex: Regex,
+    mode: FilterMode,
+    allowed_domains: Vec<String>,
+}
+
+impl LinkFilter {
+    fn new(mode: FilterMode, allowed_domains: &[String]) -> Self {
+        Self {
+            regex: Regex::new(r"(?i)\b(?:https?://|www\.)[^\s<>]+").expect("valid link pattern"),
+            mode,
+            allowed_domains: allowed_domains
+                .iter()
+                .map(|domain| domain.trim_start_matches('.').to_lowercase())
+                .collect(),
+        }
+    }
+    fn is_allowed(&self, link: &str) -> bool {
+        let lower = link.to_lowercase();
+        let host = lower
+            .trim_start_matches("http://")
+            .trim_start_matches("https://")
+            .split(['/', '?', '#'])
+            .next()
+            .unwrap_or_default();
+        let host = host.rsplit('@').next().unwrap_or_default();
+        let host = host.split(':').next().unwrap_or_default();
+        self.allowed_domains.iter().any(|domain| {
+            host == domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+    }
+}
+
+impl MessageFilter for LinkFilter {
+    fn name(&self) -> &str {
+        "links"
+    }
+    fn check<'a>(&'a self, input: FilterInput<'a>) -> BoxFuture<'a, FilterVerdict> {
+        let blocked = self
+            .regex
+            .find_iter(input.text)
+            .filter(|link| !self.is_allowed(link.as_str()))
+            .count();
+        let result = if blocked == 0 {
+            FilterVerdict::Pass
+        } else {
+            verdict(
+                self.mode,
+                || {
+                    self.regex
+                        .replace_all(input.text, |captures: &regex::Captures| {
+                            let link = &captures[0];
+                            if self.is_allowed(link) {
+                                link.to_string()
+                            } else {
+                                REDACTED.to_string()
+                            }
+                        })
+                        .into_owned()
+                },
+                format!("{} links", blocked),
+            )
+        };
+        Box::pin(future::ready(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::RegexRuleConfig;
+
+    use super::*;
+
+    fn profanity() -> ModerationConfig {
+        ModerationConfig {
+            profanity: vec!["Darn".to_string()],
+            ..Default::default()
+        }
+    }
+
+    async fn run(config: &ModerationConfig, text: &str) -> Result<FilterOutcome, ModerationRecord> {
+        FilterChain::new(config)
+            .run(&Uuid::new_v4(), &Uuid::new_v4(), text)
+            .await
+    }
+
+    fn rule(pattern: &str, action: FilterMode) -> RegexRuleConfig {
+        RegexRuleConfig {
+            name: "rule".to_string(),
+            pattern: pattern.to_string(),
+            action,
+        }
+    }
+
+    #[tokio::test]
+    async fn long_messages_are_rejected() {
+        let config = ModerationConfig {
+            max_length: 5,
+            ..profanity()
+        };
+        assert!(run(&config, "héllo").await.is_ok());
+        let record = run(&config, "hello!").await.err().expect("rejected");
+        assert_eq!(record.filter, "max_length");
+        assert_eq!(record.action, FilterMode::Reject);
+    }
+
+    #[tokio::test]
+    async fn listed_words_follow_the_profanity_action() {
+        let mut config = profanity();
+        let outcome = run(&config, "darn it, DARN").await.unwrap();
+        assert_eq!(outcome.text.as_deref(), Some("**** it, ****"));
+        // Whole words only
+        let outcome = run(&config, "darned").await.unwrap();
+        assert!(outcome.text.is_none());
+
+        config.profanity_action = FilterMode::Flag;
+        let outcome = run(&config, "darn").await.unwrap();
+        assert!(outcome.text.is_none());
+        assert_eq!(outcome.flags, ["1 listed words"]);
+
+        config.profanity_action = FilterMode::Reject;
+        let record = run(&config, "darn").await.err().expect("rejected");
+        assert_eq!(record.filter, "profanity");
+    }
+
+    #[tokio::test]
+    async fn rules_match_regular_expressions() {
+        let mut config = ModerationConfig {
+            rules: vec![rule(r"\d{4}-\d{4}", FilterMode::Redact)],
+            ..profanity()
+        };
+        let outcome = run(&config, "card 1234-5678 please").await.unwrap();
+        assert_eq!(outcome.text.as_deref(), Some("card *** please"));
+
+        config.rules = vec![rule(r"\d{4}-\d{4}", FilterMode::Flag)];
+        let outcome = run(&config, "card 1234-5678").await.unwrap();
+        assert_eq!(outcome.flags, ["matched rule rule"]);
+
+        config.rules = vec![rule(r"\d{4}-\d{4}", FilterMode::Reject)];
+        let record = run(&config, "card 1234-5678")
+            .await
+            .err()
+            .expect("rejected");
+        assert_eq!(record.filter, "rule");
+        assert!(run(&config, "card 1234").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn links_outside_allowed_domains_are_filtered() {
+        let mut config = ModerationConfig {
+            links: Some(FilterMode::Redact),
+            allowed_domains: vec!["example.com".to_string()],
+            ..profanity()
+        };
+        let outcome = run(
+            &config,
+            "see https://docs.example.com/a and www.evil.com or http://notexample.com",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            outcome.text.as_deref(),
+            Some("see https://docs.example.com/a and *** or ***")
+        );
+        let outcome = run(&config, "https://user@example.com:8080/")
+            .await
+            .unwrap();
+        assert!(outcome.text.is_none());
+
+        config.links = Some(FilterMode::Flag);
+        let outcome = run(&config, "www.evil.com").await.unwrap();
+        assert_eq!(outcome.flags, ["1 links"]);
+
+        config.links = Some(FilterMode::Reject);
+        let record = run(&config, "www.evil.com").await.err().expect("rejected");
+        assert_eq!(record.filter, "links");
+    }
+
+    #[tokio::test]
+    async fn rejections_stop_the_chain() {
+        // The word is redacted first, the rule still rejects the message
+        let config = ModerationConfig {
+            rules: vec![rule("(?i)buy now", FilterMode::Reject)],
+            ..profanity()
+        };
+        let chain = FilterChain::new(&config);
+        let room_id = Uuid::new_v4();
+        let sender = Uuid::new_v4();
+        let record = chain
+            .run(&room_id, &sender, "darn, buy now")
+            .await
+            .err()
+            .expect("rejected");
+        assert_eq!(record.filter, "rule");
+        assert_eq!(record.text, "darn, buy now");
+        let records = chain.get_records().await;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].action, FilterMode::Reject);
+        assert_eq!(records[1].action, FilterMode::Redact);
+
+        // Later filters see the redacted text
+        let config = ModerationConfig {
+            rules: vec![rule(r"\*\*\*\*", FilterMode::Flag)],
+            ..profanity()
+        };
+        let outcome = run(&config, "darn").await.unwrap();
+        assert_eq!(outcome.text.as_deref(), Some("****"));
+        assert_eq!(outcome.flags, ["matched rule rule"]);
+    }
+
+    #[tokio::test]
+    async fn records_are_limited() {
+        let config = ModerationConfig {
+            log_limit: 1,
+            ..profanity()
+        };
+        let chain = FilterChain::new(&config);
+        for text in ["darn", "darn darn"] {
+            chain
+                .run(&Uuid::new_v4(), &Uuid::new_v4(), text)
+                .await
+                .unwrap();
+        }
+        let records = chain.get_records().await;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].text, "darn darn");
+    }
+}
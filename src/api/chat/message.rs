@@ -0,0 +1,232 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use super::attachment::Attachment;
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Keys of a delivered message assigned by the server, see
+/// [`RoomMessage::to_value`].
+const SERVER_KEYS: [&str; 12] = [
+    "sender",
+    "room",
+    "id",
+    "seq",
+    "timestamp",
+    "reply_to",
+    "thread_id",
+    "reply_count",
+    "edited_at",
+    "deleted",
+    "reactions",
+    "attachments",
+];
+
+/// Removes the server assigned keys from message data sent by a client or a
+/// webhook, which could otherwise pass for another sender or a deleted,
+/// threaded or reacted to message where the server leaves them unset.
+pub fn strip_server_keys(data: &mut Value) {
+    if let Some(data) = data.as_object_mut() {
+        for key in SERVER_KEYS {
+            data.remove(key);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomMessage {
+    pub id: Uuid,
+    /// Position of the message in its room, assigned by [`MessageHistory::push`]
+    pub seq: u64,
+    pub room_id: Uuid,
+    pub sender: Option<Uuid>,
+    pub timestamp: u64,
+    pub data: Value,
+    pub attachments: Vec<Attachment>,
+    pub reply_to: Option<Uuid>,
+    pub thread_id: Option<Uuid>,
+    pub reply_count: usize,
+    pub edited_at: Option<u64>,
+    pub deleted: bool,
+    pub reactions: BTreeMap<String, BTreeSet<Uuid>>,
+}
+
+impl RoomMessage {
+    pub fn new(room_id: &Uuid, sender: Option<&Uuid>, mut data: Value) -> Self {
+        strip_server_keys(&mut data);
+        Self {
+            id: Uuid::new_v4(),
+            seq: 0,
+            room_id: *room_id,
+            sender: sender.copied(),
+            timestamp: now_millis(),
+            data,
+            attachments: Vec::new(),
+            reply_to: None,
+            thread_id: None,
+            reply_count: 0,
+            edited_at: None,
+            deleted: false,
+            reactions: BTreeMap::new(),
+        }
+    }
+    /// The message as delivered to clients: the original `data` object with
+    /// the server assigned fields merged in.
+    pub fn to_value(&self) -> Value {
+        let mut value = if self.deleted {
+            json!({ "type": self.data["type"] })
+        } else {
+            self.data.clone()
+        };
+        if !self.attachments.is_empty() && !self.deleted {
+            value["attachments"] = self
+                .attachments
+                .iter()
+                .map(|attachment| attachment.to_value())
+                .collect();
+        }
+        if let Some(sender) = self.sender {
+            value["sender"] = json!(sender.to_string());
+        }
+        value["room"] = json!(self.room_id.to_string());
+        value["id"] = json!(self.id.to_string());
+        value["seq"] = json!(self.seq);
+        value["timestamp"] = json!(self.timestamp);
+        if let Some(reply_to) = self.reply_to {
+            value["reply_to"] = json!(reply_to.to_string());
+        }
+        if let Some(thread_id) = self.thread_id {
+            value["thread_id"] = json!(thread_id.to_string());
+        }
+        if self.reply_count > 0 {
+            value["reply_count"] = json!(self.reply_count);
+        }
+        if let Some(edited_at) = self.edited_at {
+            value["edited_at"] = json!(edited_at);
+        }
+        if self.deleted {
+            value["deleted"] = json!(true);
+        }
+        if !self.reactions.is_empty() {
+            value["reactions"] = self
+                .reactions
+                .iter()
+                .map(|(reaction, clients)| {
+                    let clients: Vec<String> = clients.iter().map(|id| id.to_string()).collect();
+                    (reaction.clone(), json!(clients))
+                })
+                .collect();
+        }
+        value
+    }
+}
+
+/// Bounded in memory history of a room, oldest messages are dropped first.
+pub struct MessageHistory {
+    messages: VecDeque<RoomMessage>,
+    limit: usize,
+    last_seq: u64,
+}
+
+impl MessageHistory {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            limit,
+            last_seq: 0,
+        }
+    }
+    /// Assigns the next sequence number to the message, stores it and
+    /// returns it as delivered to clients.
+    pub fn push(&mut self, mut message: RoomMessage) -> Value {
+        self.last_seq += 1;
+        message.seq = self.last_seq;
+        let value = message.to_value();
+        if self.limit == 0 {
+            return value;
+        }
+        while self.messages.len() >= self.limit {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+        value
+    }
+    /// Stores the copy of a message sent to another node, replacing the
+    /// previous copy if any. The message keeps the sequence number assigned
+    /// by its node, returns whether it was not stored yet.
+    pub fn upsert(&mut self, message: RoomMessage) -> bool {
+        if let Some(stored) = self.get_mut(&message.id) {
+            *stored = message;
+            return false;
+        }
+        self.last_seq = self.last_seq.max(message.seq);
+        if self.limit == 0 {
+            return true;
+        }
+        let position = self
+            .messages
+            .iter()
+            .rposition(|stored| stored.seq <= message.seq)
+            .map_or(0, |position| position + 1);
+        self.messages.insert(position, message);
+        while self.messages.len() > self.limit {
+            self.messages.pop_front();
+        }
+        true
+    }
+    pub fn messages(&self) -> impl Iterator<Item = &RoomMessage> {
+        self.messages.iter()
+    }
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+    /// Messages after `last_read` not sent by `client_id`, counting messages
+    /// already dropped from the history as unread.
+    pub fn unread_count(&self, last_read: u64, client_id: &Uuid) -> u64 {
+        let first_seq = self
+            .messages
+            .front()
+            .map_or(self.last_seq + 1, |message| message.seq);
+        let dropped = first_seq.saturating_sub(last_read + 1);
+        let stored = self
+            .messages
+            .iter()
+            .filter(|message| {
+                message.seq > last_read
+                    && !message.deleted
+                    && message.sender.as_ref() != Some(client_id)
+            })
+            .count() as u64;
+        dropped + stored
+    }
+    pub fn get(&self, message_id: &Uuid) -> Option<&RoomMessage> {
+        self.messages
+            .iter()
+            .find(|message| &message.id == message_id)
+    }
+    pub fn get_mut(&mut self, message_id: &Uuid) -> Option<&mut RoomMessage> {
+        self.messages
+            .iter_mut()
+            .find(|message| &message.id == message_id)
+    }
+    /// The root message of a thread followed by its replies, oldest first.
+    pub fn thread(&self, thread_id: &Uuid) -> Vec<&RoomMessage> {
+        self.messages
+            .iter()
+            .filter(|message| {
+                &message.id == thread_id || message.thread_id.as_ref() == Some(thread_id)
+            })
+            .collect()
+    }
+}
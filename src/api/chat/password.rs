@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use tokio::task::spawn_blocking;
+use uuid::Uuid;
+
+use super::room::RoomError;
+
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// Hashes a room password with argon2, off the async runtime since hashing
+/// is deliberately slow.
+pub(super) async fn hash_password(password: String) -> Result<String, RoomError> {
+    if password.is_empty() || password.chars().count() > MAX_PASSWORD_LENGTH {
+        return Err(RoomError::InvalidRequest);
+    }
+    spawn_blocking(move || {
+        let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+            .map_err(|_| RoomError::StorageFailure)?;
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| {
+                println!("password hash error {}", e);
+                RoomError::StorageFailure
+            })
+    })
+    .await
+    .map_err(|_| RoomError::StorageFailure)?
+}
+
+pub(super) async fn verify_password(hash: String, password: String) -> bool {
+    spawn_blocking(move || match PasswordHash::new(&hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            println!("password hash invalid {}", e);
+            false
+        }
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Who failed attempts are counted for: the address of the client, so that
+/// opening a new connection does not reset them, or the client itself when
+/// its address is unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum AttemptKey {
+    Ip(IpAddr),
+    Client(Uuid),
+}
+
+impl AttemptKey {
+    pub(super) fn new(client_id: &Uuid, ip: Option<IpAddr>) -> Self {
+        match ip {
+            Some(ip) => AttemptKey::Ip(ip),
+            None => AttemptKey::Client(*client_id),
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failed password attempts of a room, locking an address out of the room
+/// after `max_attempts` failures in a row.
+pub(super) struct PasswordAttempts {
+    failures: HashMap<AttemptKey, Failures>,
+}
+
+impl PasswordAttempts {
+    pub(super) fn new() -> Self {
+        Self {
+            failures: HashMap::new(),
+        }
+    }
+    pub(super) fn is_locked(&self, key: &AttemptKey) -> bool {
+        self.failures
+            .get(key)
+            .and_then(|failures| failures.locked_until)
+            .is_some_and(|locked_until| locked_until > Instant::now())
+    }
+    /// Counts a failure, returning whether `key` is now locked out.
+    /// Failures older than `lockout` are forgotten.
+    pub(super) fn fail(&mut self, key: AttemptKey, max_attempts: u32, lockout: Duration) -> bool {
+        let now = Instant::now();
+        self.failures
+            .retain(|_, failures| now.duration_since(failures.last) < lockout);
+        let failures = self.failures.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        failures.count += 1;
+        failures.last = now;
+        if max_attempts > 0 && failures.count >= max_attempts {
+            failures.count = 0;
+            failures.locked_until = Some(now + lockout);
+            true
+        } else {
+            false
+        }
+    }
+    pub(super) fn succeed(&mut self, key: &AttemptKey) {
+        self.failures.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn passwords_verify_against_their_hash() {
+        let hash = hash_password("hunter2".to_string()).await.unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(!hash.contains("hunter2"));
+        assert!(verify_password(hash.clone(), "hunter2".to_string()).await);
+        assert!(!verify_password(hash.clone(), "hunter3".to_string()).await);
+        assert!(!verify_password("not a hash".to_string(), "hunter2".to_string()).await);
+        // Salted, the same password never hashes the same
+        assert_ne!(hash_password("hunter2".to_string()).await.unwrap(), hash);
+    }
+
+    #[tokio::test]
+    async fn empty_and_long_passwords_are_rejected() {
+        assert!(matches!(
+            hash_password(String::new()).await,
+            Err(RoomError::InvalidRequest)
+        ));
+        assert!(matches!(
+            hash_password("x".repeat(MAX_PASSWORD_LENGTH + 1)).await,
+            Err(RoomError::InvalidRequest)
+        ));
+    }
+
+    #[test]
+    fn failures_in_a_row_lock_out() {
+        let lockout = Duration::from_millis(50);
+        let mut attempts = PasswordAttempts::new();
+        let key = AttemptKey::new(&Uuid::new_v4(), Some("10.0.0.1".parse().unwrap()));
+        let other = AttemptKey::new(&Uuid::new_v4(), None);
+        assert!(!attempts.fail(key, 3, lockout));
+        assert!(!attempts.fail(key, 3, lockout));
+        assert!(!attempts.is_locked(&key));
+        assert!(attempts.fail(key, 3, lockout));
+        assert!(attempts.is_locked(&key));
+        assert!(!attempts.is_locked(&other));
+
+        sleep(lockout);
+        assert!(!attempts.is_locked(&key));
+        // A success starts the count over
+        assert!(!attempts.fail(key, 3, lockout));
+        attempts.succeed(&key);
+        assert!(!attempts.fail(key, 3, lockout));
+        assert!(!attempts.fail(key, 3, lockout));
+        assert!(!attempts.is_locked(&key));
+    }
+
+    #[test]
+    fn no_lockout_without_a_limit() {
+        let mut attempts = PasswordAttempts::new();
+        let key = AttemptKey::new(&Uuid::new_v4(), None);
+        for _ in 0..10 {
+            assert!(!attempts.fail(key, 0, Duration::from_secs(60)));
+        }
+        assert!(!attempts.is_locked(&key));
+    }
+}
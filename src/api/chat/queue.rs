@@ -0,0 +1,237 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+
+use crate::config::{OverflowPolicy, SendQueueConfig};
+
+use super::wire::Frame;
+
+/// Advisory events a client can miss without its view of a room going
+/// wrong, the next one supersedes them. Events changing membership, rooms or
+/// messages are never dropped.
+const NON_ESSENTIAL_EVENTS: [&str; 2] = ["ROOM_UNREAD", "READ_RECEIPT"];
+
+fn is_essential(frame: &Frame) -> bool {
+    let value = frame.value();
+    value["type"] != "EVENT"
+        || !NON_ESSENTIAL_EVENTS
+            .iter()
+            .any(|event_type| value["event"]["type"] == *event_type)
+}
+
+#[derive(Default)]
+struct SendQueueState {
+    frames: VecDeque<Arc<Frame>>,
+    closed: bool,
+    max_depth: usize,
+    dropped: u64,
+}
+
+/// Bounded queue of frames waiting for the writer task of a client.
+pub struct SendQueue {
+    state: StdMutex<SendQueueState>,
+    notify: Notify,
+    config: SendQueueConfig,
+}
+
+impl SendQueue {
+    pub fn new(config: SendQueueConfig) -> Self {
+        Self {
+            state: StdMutex::new(SendQueueState::default()),
+            notify: Notify::new(),
+            config,
+        }
+    }
+    /// Queues a frame, applying the overflow policy when the queue is full.
+    /// Returns `false` when the queue is closed, the client then has to be
+    /// disconnected.
+    pub fn push(&self, frame: Arc<Frame>) -> bool {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return false,
+        };
+        if state.closed {
+            return false;
+        }
+        if state.frames.len() >= self.config.size.max(1) {
+            match self.config.overflow {
+                OverflowPolicy::DropOldest => {
+                    state.frames.pop_front();
+                }
+                OverflowPolicy::DropEvents => {
+                    if !is_essential(&frame) {
+                        state.dropped += 1;
+                        return true;
+                    }
+                    match state.frames.iter().position(|frame| !is_essential(frame)) {
+                        Some(index) => {
+                            state.frames.remove(index);
+                        }
+                        None => {
+                            state.closed = true;
+                            self.notify.notify_one();
+                            return false;
+                        }
+                    }
+                }
+                OverflowPolicy::Disconnect => {
+                    state.closed = true;
+                    self.notify.notify_one();
+                    return false;
+                }
+            }
+            state.dropped += 1;
+        }
+        state.frames.push_back(frame);
+        state.max_depth = state.max_depth.max(state.frames.len());
+        self.notify.notify_one();
+        true
+    }
+    /// Waits for the next frame, `None` once the queue is closed. Frames still
+    /// queued when closing are discarded.
+    pub async fn pop(&self) -> Option<Arc<Frame>> {
+        loop {
+            {
+                let mut state = self.state.lock().ok()?;
+                if state.closed {
+                    return None;
+                }
+                if let Some(frame) = state.frames.pop_front() {
+                    return Some(frame);
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+    pub fn close(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.closed = true;
+        }
+        self.notify.notify_one();
+    }
+    pub fn to_value(&self) -> Value {
+        match self.state.lock() {
+            Ok(state) => json!({
+                "depth": state.frames.len(),
+                "max_depth": state.max_depth,
+                "dropped": state.dropped,
+                "capacity": self.config.size,
+                "closed": state.closed
+            }),
+            Err(_) => Value::Null,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(size: usize, overflow: OverflowPolicy) -> SendQueue {
+        SendQueue::new(SendQueueConfig { size, overflow })
+    }
+
+    fn message(text: &str) -> Arc<Frame> {
+        Arc::new(Frame::new(json!({ "type": "MESSAGE", "message": text })))
+    }
+
+    fn event(event_type: &str) -> Arc<Frame> {
+        Arc::new(Frame::new(
+            json!({ "type": "EVENT", "event": { "type": event_type } }),
+        ))
+    }
+
+    async fn drain(queue: &SendQueue) -> Vec<String> {
+        let mut kinds = Vec::new();
+        while queue.to_value()["depth"] != 0 {
+            let frame = queue.pop().await.unwrap();
+            kinds.push(
+                frame.value()["message"]
+                    .as_str()
+                    .unwrap_or(frame.kind())
+                    .to_string(),
+            );
+        }
+        kinds
+    }
+
+    #[test]
+    fn only_advisory_events_are_non_essential() {
+        assert!(!is_essential(&event("READ_RECEIPT")));
+        assert!(!is_essential(&event("ROOM_UNREAD")));
+        for event_type in [
+            "ROOM_JOIN",
+            "ROOM_EXIT",
+            "ROOM_REMOVAL",
+            "MESSAGE_REACT",
+            "MESSAGE_UNREACT",
+            "MESSAGE_EDIT",
+        ] {
+            assert!(is_essential(&event(event_type)), "{}", event_type);
+        }
+        assert!(is_essential(&message("hi")));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room_for_new_frames() {
+        let queue = queue(2, OverflowPolicy::DropOldest);
+        for text in ["one", "two", "three"] {
+            assert!(queue.push(message(text)));
+        }
+        assert_eq!(queue.to_value()["dropped"], 1);
+        assert_eq!(drain(&queue).await, ["two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn drop_events_keeps_messages() {
+        let queue = queue(2, OverflowPolicy::DropEvents);
+        assert!(queue.push(event("READ_RECEIPT")));
+        assert!(queue.push(message("one")));
+        // The queued receipt makes room for the message
+        assert!(queue.push(message("two")));
+        // A new receipt is dropped rather than a message
+        assert!(queue.push(event("ROOM_UNREAD")));
+        assert_eq!(queue.to_value()["dropped"], 2);
+        assert_eq!(drain(&queue).await, ["one", "two"]);
+
+        // Essential frames only, the client is disconnected
+        assert!(queue.push(message("three")));
+        assert!(queue.push(event("MESSAGE_REACT")));
+        assert!(!queue.push(message("four")));
+        assert_eq!(queue.to_value()["closed"], true);
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_queue() {
+        let queue = queue(1, OverflowPolicy::Disconnect);
+        assert!(queue.push(message("one")));
+        assert!(!queue.push(event("READ_RECEIPT")));
+        assert!(!queue.push(message("two")));
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stats_track_the_queue_depth() {
+        let queue = queue(4, OverflowPolicy::DropOldest);
+        assert_eq!(
+            queue.to_value(),
+            json!({ "depth": 0, "max_depth": 0, "dropped": 0, "capacity": 4, "closed": false })
+        );
+        for text in ["one", "two", "three"] {
+            queue.push(message(text));
+        }
+        queue.pop().await.unwrap();
+        let stats = queue.to_value();
+        assert_eq!(stats["depth"], 2);
+        assert_eq!(stats["max_depth"], 3);
+        assert_eq!(stats["dropped"], 0);
+        queue.close();
+        assert_eq!(queue.to_value()["closed"], true);
+        assert!(!queue.push(message("four")));
+    }
+}
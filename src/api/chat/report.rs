@@ -0,0 +1,313 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::{message::now_millis, room::RoomError};
+
+const MAX_REASON_LENGTH: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ReportKind {
+    Message,
+    Client,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ReportStatus {
+    Open,
+    Resolved,
+    Dismissed,
+}
+
+/// What a moderator did about a report.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ReportAction {
+    Dismiss,
+    /// Closes the report without acting on the room
+    Resolve,
+    DeleteMessage,
+    /// Bans the reported client from the room
+    Ban,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReportQuery {
+    pub status: Option<ReportStatus>,
+}
+
+/// How a moderator closes a report through the admin API.
+#[derive(Deserialize, Debug)]
+pub struct ReportResolution {
+    pub action: ReportAction,
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Report {
+    pub id: Uuid,
+    pub created_at: u64,
+    pub room_id: Uuid,
+    pub reporter_id: Uuid,
+    pub kind: ReportKind,
+    /// The reported client, the sender of a reported message
+    pub client_id: Option<Uuid>,
+    pub message_id: Option<Uuid>,
+    /// The reported message as it was when reported
+    pub message: Option<Value>,
+    pub reason: String,
+    pub status: ReportStatus,
+    pub resolved_at: Option<u64>,
+    pub action: Option<ReportAction>,
+    pub note: Option<String>,
+}
+
+impl Report {
+    pub fn new(room_id: &Uuid, reporter_id: &Uuid, kind: ReportKind, reason: &str) -> Option<Self> {
+        let reason = reason.trim();
+        if reason.is_empty() || reason.chars().count() > MAX_REASON_LENGTH {
+            return None;
+        }
+        Some(Self {
+            id: Uuid::new_v4(),
+            created_at: now_millis(),
+            room_id: *room_id,
+            reporter_id: *reporter_id,
+            kind,
+            client_id: None,
+            message_id: None,
+            message: None,
+            reason: reason.to_string(),
+            status: ReportStatus::Open,
+            resolved_at: None,
+            action: None,
+            note: None,
+        })
+    }
+    fn is_duplicate(&self, other: &Report) -> bool {
+        self.status == ReportStatus::Open
+            && self.reporter_id == other.reporter_id
+            && self.room_id == other.room_id
+            && self.kind == other.kind
+            && self.client_id == other.client_id
+            && self.message_id == other.message_id
+    }
+}
+
+/// Reports waiting for a moderator, and the latest handled ones. Once full,
+/// the oldest handled report is dropped first, open reports are never
+/// dropped.
+pub struct ReportQueue {
+    reports: VecDeque<Report>,
+    limit: usize,
+    max_open_reports: usize,
+}
+
+impl ReportQueue {
+    pub fn new(limit: usize, max_open_reports: usize) -> Self {
+        Self {
+            reports: VecDeque::new(),
+            limit,
+            max_open_reports,
+        }
+    }
+    /// Adds a report, returning the open report of the same reporter about
+    /// the same target instead when there is one. Fails with
+    /// `RoomError::TooManyReports` when the reporter already has
+    /// `max_open_reports` open reports, or when the queue is full of open
+    /// reports.
+    pub fn file(&mut self, report: Report) -> Result<(Report, bool), RoomError> {
+        if let Some(existing) = self.reports.iter().find(|r| r.is_duplicate(&report)) {
+            return Ok((existing.clone(), false));
+        }
+        let open_reports = self
+            .reports
+            .iter()
+            .filter(|r| r.status == ReportStatus::Open && r.reporter_id == report.reporter_id)
+            .count();
+        if self.max_open_reports > 0 && open_reports >= self.max_open_reports {
+            return Err(RoomError::TooManyReports);
+        }
+        if self.limit > 0 && self.reports.len() >= self.limit {
+            let index = self
+                .reports
+                .iter()
+                .position(|r| r.status != ReportStatus::Open)
+                .ok_or(RoomError::TooManyReports)?;
+            self.reports.remove(index);
+        }
+        self.reports.push_back(report.clone());
+        Ok((report, true))
+    }
+    pub fn get(&self, report_id: &Uuid) -> Option<&Report> {
+        self.reports.iter().find(|r| &r.id == report_id)
+    }
+    /// Reports with the given status, all of them when unset, most recent first.
+    pub fn list(&self, status: Option<ReportStatus>) -> Vec<Report> {
+        self.reports
+            .iter()
+            .rev()
+            .filter(|r| status.is_none_or(|status| r.status == status))
+            .cloned()
+            .collect()
+    }
+    pub fn close(
+        &mut self,
+        report_id: &Uuid,
+        action: ReportAction,
+        note: Option<String>,
+    ) -> Option<Report> {
+        let report = self.reports.iter_mut().find(|r| &r.id == report_id)?;
+        report.status = match action {
+            ReportAction::Dismiss => ReportStatus::Dismissed,
+            _ => ReportStatus::Resolved,
+        };
+        report.resolved_at = Some(now_millis());
+        report.action = Some(action);
+        report.note = note;
+        Some(report.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(room_id: &Uuid, reporter_id: &Uuid, client_id: Uuid) -> Report {
+        let mut report = Report::new(room_id, reporter_id, ReportKind::Client, "spam").unwrap();
+        report.client_id = Some(client_id);
+        report
+    }
+
+    fn assert_too_many(result: Result<(Report, bool), RoomError>) {
+        match result {
+            Err(error) => assert_eq!(error.code(), RoomError::TooManyReports.code()),
+            Ok((report, _)) => panic!("filed report {}", report.id),
+        }
+    }
+
+    #[test]
+    fn reasons_are_required_and_limited() {
+        let (room_id, reporter_id) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(Report::new(&room_id, &reporter_id, ReportKind::Client, "  ").is_none());
+        let long = "x".repeat(MAX_REASON_LENGTH + 1);
+        assert!(Report::new(&room_id, &reporter_id, ReportKind::Client, &long).is_none());
+        let report = Report::new(&room_id, &reporter_id, ReportKind::Client, " spam ").unwrap();
+        assert_eq!(report.reason, "spam");
+    }
+
+    #[test]
+    fn open_duplicates_return_the_existing_report() {
+        let mut queue = ReportQueue::new(10, 0);
+        let (room_id, reporter_id, client_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (first, created) = queue
+            .file(report(&room_id, &reporter_id, client_id))
+            .unwrap();
+        assert!(created);
+        let (second, created) = queue
+            .file(report(&room_id, &reporter_id, client_id))
+            .unwrap();
+        assert!(!created);
+        assert_eq!(second.id, first.id);
+        // Another target, reporter or room is another report
+        assert!(
+            queue
+                .file(report(&room_id, &reporter_id, Uuid::new_v4()))
+                .unwrap()
+                .1
+        );
+        assert!(
+            queue
+                .file(report(&room_id, &Uuid::new_v4(), client_id))
+                .unwrap()
+                .1
+        );
+        assert!(
+            queue
+                .file(report(&Uuid::new_v4(), &reporter_id, client_id))
+                .unwrap()
+                .1
+        );
+
+        // Once handled, the same report can be filed again
+        queue.close(&first.id, ReportAction::Resolve, None);
+        assert!(
+            queue
+                .file(report(&room_id, &reporter_id, client_id))
+                .unwrap()
+                .1
+        );
+    }
+
+    #[test]
+    fn reporters_are_limited_in_open_reports() {
+        let mut queue = ReportQueue::new(10, 2);
+        let (room_id, reporter_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let (first, _) = queue
+            .file(report(&room_id, &reporter_id, Uuid::new_v4()))
+            .unwrap();
+        queue
+            .file(report(&room_id, &reporter_id, Uuid::new_v4()))
+            .unwrap();
+        assert_too_many(queue.file(report(&room_id, &reporter_id, Uuid::new_v4())));
+        // Other reporters are not affected
+        assert!(queue
+            .file(report(&room_id, &Uuid::new_v4(), Uuid::new_v4()))
+            .is_ok());
+
+        queue.close(&first.id, ReportAction::Dismiss, None);
+        assert!(queue
+            .file(report(&room_id, &reporter_id, Uuid::new_v4()))
+            .is_ok());
+    }
+
+    #[test]
+    fn full_queues_drop_handled_reports_only() {
+        let mut queue = ReportQueue::new(2, 0);
+        let room_id = Uuid::new_v4();
+        let file =
+            |queue: &mut ReportQueue| queue.file(report(&room_id, &Uuid::new_v4(), Uuid::new_v4()));
+        let (first, _) = file(&mut queue).unwrap();
+        let (second, _) = file(&mut queue).unwrap();
+        assert_too_many(file(&mut queue));
+
+        queue.close(&second.id, ReportAction::Resolve, None);
+        let (third, _) = file(&mut queue).unwrap();
+        assert!(queue.get(&second.id).is_none());
+        let listed: Vec<Uuid> = queue.list(None).iter().map(|r| r.id).collect();
+        assert_eq!(listed, [third.id, first.id]);
+        assert_too_many(file(&mut queue));
+    }
+
+    #[test]
+    fn closing_sets_the_status_from_the_action() {
+        let mut queue = ReportQueue::new(10, 0);
+        let room_id = Uuid::new_v4();
+        for (action, status) in [
+            (ReportAction::Dismiss, ReportStatus::Dismissed),
+            (ReportAction::Resolve, ReportStatus::Resolved),
+            (ReportAction::DeleteMessage, ReportStatus::Resolved),
+            (ReportAction::Ban, ReportStatus::Resolved),
+        ] {
+            let (report, _) = queue
+                .file(report(&room_id, &Uuid::new_v4(), Uuid::new_v4()))
+                .unwrap();
+            let closed = queue
+                .close(&report.id, action, Some("done".to_string()))
+                .unwrap();
+            assert_eq!(closed.status, status);
+            assert_eq!(closed.action, Some(action));
+            assert_eq!(closed.note.as_deref(), Some("done"));
+            assert!(closed.resolved_at.is_some());
+            assert_eq!(queue.list(Some(status))[0].id, report.id);
+        }
+        assert!(queue.list(Some(ReportStatus::Open)).is_empty());
+        assert!(queue
+            .close(&Uuid::new_v4(), ReportAction::Resolve, None)
+            .is_none());
+    }
+}
@@ -1,106 +1,1196 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    str::FromStr,
     sync::{Arc, Weak},
+    time::{Duration, Instant},
 };
 
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use tokio::sync::{
-    broadcast::{self, Receiver, Sender},
-    Mutex, RwLock,
-};
+use tokio::{spawn, sync::RwLock, time::sleep};
 use uuid::Uuid;
 
-use super::engine::ChatEngine;
+use super::{
+    attachment::Attachment,
+    audit::{AuditEntry, ADMIN_ACTOR},
+    backplane::ClusterEvent,
+    client::WebSocketClient,
+    engine::ChatEngine,
+    message::{now_millis, strip_server_keys, MessageHistory, RoomMessage},
+    password::{hash_password, verify_password, AttemptKey, PasswordAttempts},
+    report::{Report, ReportKind},
+    search::{message_text, SearchDocument},
+    snapshot::RoomSnapshot,
+    wire::Frame,
+};
+
+/// How long the read positions of a lost connection can be resumed.
+const RESUME_WINDOW: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomState {
+    Open,
+    Closing,
+    Closed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomError {
+    NotFound,
+    Closed,
+    NotMember,
+    Forbidden,
+    MessageNotFound,
+    AttachmentNotFound,
+    InvalidRequest,
+    UnknownCommand,
+    StorageFailure,
+    Full,
+    TooManyRooms,
+    PasswordRequired,
+    InvalidPassword,
+    PasswordLocked,
+    MessageRejected,
+    Banned,
+    ReportNotFound,
+    TooManyReports,
+}
+
+impl RoomError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RoomError::NotFound => "ROOM_NOT_FOUND",
+            RoomError::Closed => "ROOM_CLOSED",
+            RoomError::NotMember => "NOT_ROOM_MEMBER",
+            RoomError::Forbidden => "FORBIDDEN",
+            RoomError::MessageNotFound => "MESSAGE_NOT_FOUND",
+            RoomError::AttachmentNotFound => "ATTACHMENT_NOT_FOUND",
+            RoomError::InvalidRequest => "INVALID_REQUEST",
+            RoomError::UnknownCommand => "UNKNOWN_COMMAND",
+            RoomError::StorageFailure => "STORAGE_FAILURE",
+            RoomError::Full => "ROOM_FULL",
+            RoomError::TooManyRooms => "TOO_MANY_ROOMS",
+            RoomError::PasswordRequired => "PASSWORD_REQUIRED",
+            RoomError::InvalidPassword => "INVALID_PASSWORD",
+            RoomError::PasswordLocked => "PASSWORD_LOCKED",
+            RoomError::MessageRejected => "MESSAGE_REJECTED",
+            RoomError::Banned => "BANNED",
+            RoomError::ReportNotFound => "REPORT_NOT_FOUND",
+            RoomError::TooManyReports => "TOO_MANY_REPORTS",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RoomRole {
+    Owner,
+    Moderator,
+}
+
+/// Membership and lifecycle share one lock so a join can never land in a room
+/// that its last member is concurrently closing. Broadcasts are pushed
+/// straight into the send queue of every member.
+struct RoomClients {
+    state: RoomState,
+    members: HashMap<Uuid, Weak<WebSocketClient>>,
+    // Members connected to other nodes of the cluster, with their node id
+    remote: HashMap<Uuid, Uuid>,
+    idle_since: Option<Instant>,
+}
+
+impl RoomClients {
+    fn is_empty(&self) -> bool {
+        self.members.is_empty() && self.remote.is_empty()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RoomOptions {
+    pub id: Option<Uuid>,
+    pub name: Option<String>,
+    /// Persistent rooms are kept by the engine even when they have no members
+    pub persistent: bool,
+    /// Whether members are notified when someone marks messages as read
+    pub read_receipts: bool,
+    /// Overrides the configured member limit, 0 for no limit
+    pub max_members: Option<usize>,
+    /// Argon2 hash of the password required to join
+    pub password: Option<String>,
+}
+
+impl Default for RoomOptions {
+    fn default() -> Self {
+        Self {
+            id: None,
+            name: None,
+            persistent: false,
+            read_receipts: true,
+            max_members: None,
+            password: None,
+        }
+    }
+}
 
 pub struct WebSocketRoom {
     id: Uuid,
-    clients: RwLock<HashSet<Uuid>>,
-    sender: Mutex<Sender<Value>>,
+    clients: RwLock<RoomClients>,
     engine: Weak<ChatEngine>,
     creator: Uuid,
+    name: Option<String>,
+    topic: RwLock<Option<String>>,
+    persistent: bool,
+    idle_ttl: Duration,
+    roles: RwLock<HashMap<Uuid, RoomRole>>,
+    history: RwLock<MessageHistory>,
+    read_receipts: bool,
+    last_read: RwLock<HashMap<Uuid, u64>>,
+    // Read positions of clients whose connection was lost, until they resume
+    departed: RwLock<HashMap<Uuid, (u64, Instant)>>,
+    max_members: Option<usize>,
+    password: RwLock<Option<String>>,
+    password_attempts: RwLock<PasswordAttempts>,
+    banned: RwLock<HashSet<Uuid>>,
 }
 
 impl WebSocketRoom {
-    fn new(engine: &Arc<ChatEngine>, creator: &Uuid) -> Self {
-        let (sender, _) = broadcast::channel(10); //TODO: Should be 1? Handle Lagged in client loop
+    fn new(engine: &Arc<ChatEngine>, creator: &Uuid, options: RoomOptions) -> Self {
+        let idle_ttl = engine.get_config().room_idle_ttl();
+        let history = MessageHistory::new(engine.get_config().history_limit);
         let engine = Arc::downgrade(engine);
+        let mut roles = HashMap::new();
+        if !creator.is_nil() {
+            roles.insert(*creator, RoomRole::Owner);
+        }
         Self {
-            id: Uuid::new_v4(),
-            clients: RwLock::new(HashSet::new()),
-            sender: Mutex::new(sender),
+            id: options.id.unwrap_or_else(Uuid::new_v4),
+            clients: RwLock::new(RoomClients {
+                state: RoomState::Open,
+                members: HashMap::new(),
+                remote: HashMap::new(),
+                idle_since: None,
+            }),
             engine,
             creator: *creator,
+            name: options.name,
+            topic: RwLock::new(None),
+            persistent: options.persistent,
+            idle_ttl,
+            roles: RwLock::new(roles),
+            history: RwLock::new(history),
+            read_receipts: options.read_receipts,
+            last_read: RwLock::new(HashMap::new()),
+            departed: RwLock::new(HashMap::new()),
+            max_members: options.max_members,
+            password: RwLock::new(options.password),
+            password_attempts: RwLock::new(PasswordAttempts::new()),
+            banned: RwLock::new(HashSet::new()),
         }
     }
-    pub(super) async fn client_add(&self, client_id: &Uuid) {
-        self.clients.write().await.insert(*client_id);
+    /// Fails with `RoomError::Full` once the members on every node reach the
+    /// member limit, members joining again are always accepted.
+    pub(super) async fn client_add(&self, client: &Arc<WebSocketClient>) -> Result<(), RoomError> {
+        let client_id = client.get_id();
+        let max_members = self.get_max_members();
+        if self.is_banned(client_id).await {
+            return Err(RoomError::Banned);
+        }
+        {
+            let mut clients = self.clients.write().await;
+            if clients.state != RoomState::Open {
+                return Err(RoomError::Closed);
+            }
+            if max_members > 0
+                && !clients.members.contains_key(client_id)
+                && clients.members.len() + clients.remote.len() >= max_members
+            {
+                return Err(RoomError::Full);
+            }
+            clients.members.insert(*client_id, Arc::downgrade(client));
+            clients.idle_since = None;
+        }
+        if let Some(engine) = self.engine.upgrade() {
+            engine
+                .publish(ClusterEvent::MemberJoined {
+                    room_id: self.id,
+                    client_id: *client_id,
+                })
+                .await;
+        }
+        // New members start with everything sent before they joined read
+        let last_seq = self.history.read().await.last_seq();
+        self.last_read
+            .write()
+            .await
+            .entry(*client_id)
+            .or_insert(last_seq);
+        Ok(())
     }
     pub(super) async fn create_room(
         engine: &Arc<ChatEngine>,
         creator: &Uuid,
+        options: RoomOptions,
     ) -> Arc<WebSocketRoom> {
-        let websocket_room = Arc::new(WebSocketRoom::new(engine, creator));
+        let websocket_room = Arc::new(WebSocketRoom::new(engine, creator, options));
         {
             engine.room_add(&websocket_room).await;
         }
         websocket_room
     }
+    /// Creates the local copy of a room created by another node.
+    pub(super) async fn create_replica(
+        engine: &Arc<ChatEngine>,
+        creator: &Uuid,
+        options: RoomOptions,
+    ) -> Arc<WebSocketRoom> {
+        let websocket_room = Arc::new(WebSocketRoom::new(engine, creator, options));
+        engine.room_insert(&websocket_room).await;
+        websocket_room
+    }
     pub fn get_creator(&self) -> &Uuid {
         &self.creator
     }
     pub fn get_id(&self) -> &Uuid {
         &self.id
     }
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    pub async fn get_topic(&self) -> Option<String> {
+        self.topic.read().await.clone()
+    }
+    pub async fn set_topic(&self, topic: Option<String>) {
+        *self.topic.write().await = topic;
+    }
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+    pub fn has_read_receipts(&self) -> bool {
+        self.read_receipts
+    }
+    /// Member limit of the room, 0 for no limit.
+    pub fn get_max_members(&self) -> usize {
+        match (self.max_members, self.engine.upgrade()) {
+            (Some(max_members), _) => max_members,
+            (None, Some(engine)) => engine.get_config().limits.max_members,
+            (None, None) => 0,
+        }
+    }
+    pub(super) fn get_max_members_override(&self) -> Option<usize> {
+        self.max_members
+    }
+    pub async fn is_password_protected(&self) -> bool {
+        self.password.read().await.is_some()
+    }
+    pub(super) async fn get_password(&self) -> Option<String> {
+        self.password.read().await.clone()
+    }
+    pub(super) async fn set_password(&self, password: Option<String>) {
+        *self.password.write().await = password;
+    }
+    /// Lets members, owners and moderators in, anyone else has to give the
+    /// room password. Wrong passwords in a row lock the client's address out
+    /// for `chat.limits.password_lockout` seconds.
+    pub(super) async fn check_password(
+        &self,
+        client: &WebSocketClient,
+        password: Option<&str>,
+    ) -> Result<(), RoomError> {
+        let client_id = client.get_id();
+        let hash = match self.get_password().await {
+            Some(hash) => hash,
+            None => return Ok(()),
+        };
+        if self.has_client(client_id).await
+            || self.is_moderator(client_id).await
+            || self.last_read.read().await.contains_key(client_id)
+        {
+            // Including clients resuming the membership of a lost connection
+            return Ok(());
+        }
+        let key = AttemptKey::new(client_id, client.get_ip());
+        if self.password_attempts.read().await.is_locked(&key) {
+            return Err(RoomError::PasswordLocked);
+        }
+        let password = password.ok_or(RoomError::PasswordRequired)?;
+        if verify_password(hash, password.to_string()).await {
+            self.password_attempts.write().await.succeed(&key);
+            return Ok(());
+        }
+        let engine = self.engine.upgrade().ok_or(RoomError::NotFound)?;
+        let limits = &engine.get_config().limits;
+        let locked = self.password_attempts.write().await.fail(
+            key,
+            limits.max_password_attempts,
+            limits.password_lockout(),
+        );
+        if locked {
+            println!("{} client {} locked out ({:?})", self.id, client_id, key);
+        }
+        Err(RoomError::InvalidPassword)
+    }
+    pub async fn get_unread(&self, client_id: &Uuid) -> Option<u64> {
+        let last_read = *self.last_read.read().await.get(client_id)?;
+        Some(self.history.read().await.unread_count(last_read, client_id))
+    }
+    pub async fn get_state(&self) -> RoomState {
+        self.clients.read().await.state
+    }
+    /// Members on every node of the cluster.
     pub async fn get_clients_list(&self) -> Vec<Uuid> {
-        self.clients.read().await.iter().cloned().collect()
+        let clients = self.clients.read().await;
+        clients
+            .members
+            .keys()
+            .chain(clients.remote.keys())
+            .cloned()
+            .collect()
     }
-    pub async fn get_listener(&self) -> Receiver<Value> {
-        self.sender.lock().await.subscribe()
+    pub(super) async fn get_local_clients_list(&self) -> Vec<Uuid> {
+        self.clients.read().await.members.keys().cloned().collect()
     }
     pub async fn has_client(&self, client_id: &Uuid) -> bool {
-        self.clients.read().await.contains(client_id)
+        self.clients.read().await.members.contains_key(client_id)
+    }
+    pub async fn get_role(&self, client_id: &Uuid) -> Option<RoomRole> {
+        self.roles.read().await.get(client_id).copied()
+    }
+    /// Gives `client_id` a role, or takes its role away when `None`.
+    pub(super) async fn set_role(&self, client_id: &Uuid, role: Option<RoomRole>) {
+        let mut roles = self.roles.write().await;
+        match role {
+            Some(role) => roles.insert(*client_id, role),
+            None => roles.remove(client_id),
+        };
+    }
+    pub async fn is_moderator(&self, client_id: &Uuid) -> bool {
+        self.get_role(client_id).await.is_some()
+    }
+    pub(super) async fn broadcast(&self, value: Value) {
+        if let Some(engine) = self.engine.upgrade() {
+            engine.get_webhooks().dispatch(self.get_id(), &value).await;
+            if engine.is_clustered() {
+                engine
+                    .publish(ClusterEvent::RoomBroadcast {
+                        room_id: self.id,
+                        value: value.clone(),
+                    })
+                    .await;
+            }
+        }
+        self.deliver(Arc::new(Frame::new(value))).await;
+    }
+    fn is_clustered(&self) -> bool {
+        self.engine
+            .upgrade()
+            .is_some_and(|engine| engine.is_clustered())
+    }
+    /// Copies of the messages `message_ids` for the other nodes, none when
+    /// the engine is not part of a cluster.
+    fn replicas(&self, history: &MessageHistory, message_ids: &[Option<Uuid>]) -> Vec<RoomMessage> {
+        if !self.is_clustered() {
+            return Vec::new();
+        }
+        message_ids
+            .iter()
+            .flatten()
+            .filter_map(|message_id| history.get(message_id))
+            .cloned()
+            .collect()
+    }
+    async fn replicate(&self, messages: Vec<RoomMessage>) {
+        if let Some(engine) = self.engine.upgrade() {
+            for message in messages {
+                engine
+                    .publish(ClusterEvent::MessageStored { message })
+                    .await;
+            }
+        }
+    }
+    /// The stored messages, sent to the nodes joining the cluster.
+    pub(super) async fn get_replicas(&self) -> Vec<RoomMessage> {
+        self.history.read().await.messages().cloned().collect()
+    }
+    /// Stores a message sent to another node, members were already sent its
+    /// events by that node.
+    pub(super) async fn store_replica(&self, message: RoomMessage) {
+        let message_id = message.id;
+        let deleted = message.deleted;
+        let document = SearchDocument::new(&message);
+        let attachments = message.attachments.clone();
+        let created = self.history.write().await.upsert(message);
+        if let Some(engine) = self.engine.upgrade() {
+            for attachment in attachments {
+                engine.attachment_add(attachment).await;
+            }
+            if deleted {
+                engine.index_remove(&message_id).await;
+            } else if created {
+                engine.index_message(document).await;
+            } else {
+                engine.index_update(&message_id, document.text).await;
+            }
+        }
+    }
+    /// Sends a frame to the members connected to this node.
+    pub(super) async fn deliver(&self, frame: Arc<Frame>) {
+        let members: Vec<Weak<WebSocketClient>> = self
+            .clients
+            .read()
+            .await
+            .members
+            .values()
+            .cloned()
+            .collect();
+        for member in members {
+            if let Some(client) = member.upgrade() {
+                client.send_frame(&frame).await;
+            }
+        }
     }
     pub(super) async fn remove_client(&self, client_id: &Uuid) {
-        {
+        // The room is marked as closing while the membership lock is held, but
+        // the engine is only touched after releasing it, keeping the lock order
+        // engine -> room and never room -> engine.
+        let closing = {
             let mut clients = self.clients.write().await;
-            clients.remove(client_id);
-            if clients.is_empty() {
-                if let Some(engine) = self.engine.upgrade() {
-                    engine.room_remove(&self.id).await;
-                }
+            if clients.members.remove(client_id).is_none() {
+                return;
             }
+            self.mark_if_empty(&mut clients)
+        };
+        if let Some(engine) = self.engine.upgrade() {
+            engine
+                .publish(ClusterEvent::MemberLeft {
+                    room_id: self.id,
+                    client_id: *client_id,
+                })
+                .await;
+            engine.audit(AuditEntry::new(
+                client_id.to_string(),
+                "ROOM_EXIT",
+                Some(self.id),
+                Some(client_id.to_string()),
+            ));
+        }
+        self.last_read.write().await.remove(client_id);
+        if closing {
+            self.close().await;
         }
-        let value = json!({
+        self.broadcast(json!({
             "type": "EVENT",
             "event": {
                 "type": "ROOM_EXIT",
                 "client_id": client_id.to_string(),
                 "room_id": self.get_id().to_string()
             }
+        }))
+        .await;
+    }
+    /// Removes a client whose connection was lost, keeping its read position
+    /// for `RESUME_WINDOW` in case it comes back with `CLIENT_RESUME`.
+    pub(super) async fn disconnect_client(&self, client_id: &Uuid) {
+        let last_read = self.last_read.read().await.get(client_id).copied();
+        if let Some(last_read) = last_read {
+            let mut departed = self.departed.write().await;
+            departed.retain(|_, (_, since)| since.elapsed() < RESUME_WINDOW);
+            departed.insert(*client_id, (last_read, Instant::now()));
+        }
+        self.remove_client(client_id).await;
+    }
+    /// Gives `client_id` the read position `previous` had when its connection
+    /// was lost, returns whether there was one to resume.
+    pub(super) async fn resume_read(&self, previous: &Uuid, client_id: &Uuid) -> bool {
+        let departed = self
+            .departed
+            .write()
+            .await
+            .remove(previous)
+            .filter(|(_, since)| since.elapsed() < RESUME_WINDOW);
+        match departed {
+            Some((last_read, _)) => {
+                self.last_read.write().await.insert(*client_id, last_read);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Drops the read position of a client which is not a member.
+    pub(super) async fn forget_read(&self, client_id: &Uuid) {
+        if !self.has_client(client_id).await {
+            self.last_read.write().await.remove(client_id);
+        }
+    }
+    /// Marks an empty room as closing, or idle when it is kept for a while,
+    /// returning whether it has to be closed right away.
+    fn mark_if_empty(&self, clients: &mut RoomClients) -> bool {
+        if clients.is_empty() && clients.state == RoomState::Open && !self.persistent {
+            if self.idle_ttl.is_zero() {
+                clients.state = RoomState::Closing;
+                true
+            } else {
+                let since = Instant::now();
+                clients.idle_since = Some(since);
+                self.schedule_idle_close(since, self.idle_ttl);
+                false
+            }
+        } else {
+            false
+        }
+    }
+    /// Closes a room nobody joined, such as one its creator failed to join,
+    /// whatever its idle time to live.
+    pub(super) async fn close_if_unused(&self) {
+        {
+            let mut clients = self.clients.write().await;
+            if !clients.is_empty() || clients.state != RoomState::Open {
+                return;
+            }
+            clients.state = RoomState::Closing;
+        }
+        self.close().await;
+    }
+    pub(super) async fn remote_add(&self, client_id: &Uuid, node_id: &Uuid) {
+        let mut clients = self.clients.write().await;
+        if clients.state == RoomState::Open {
+            clients.remote.insert(*client_id, *node_id);
+            clients.idle_since = None;
+        }
+    }
+    pub(super) async fn remote_remove(&self, client_id: &Uuid) {
+        let closing = {
+            let mut clients = self.clients.write().await;
+            if clients.remote.remove(client_id).is_none() {
+                return;
+            }
+            self.mark_if_empty(&mut clients)
+        };
+        if closing {
+            self.close().await;
+        }
+    }
+    fn schedule_idle_close(&self, since: Instant, delay: Duration) {
+        let engine = Weak::clone(&self.engine);
+        let room_id = self.id;
+        spawn(async move {
+            sleep(delay).await;
+            if let Some(engine) = engine.upgrade() {
+                if let Some(room) = engine.get_room(&room_id).await {
+                    room.close_if_idle(since).await;
+                }
+            }
         });
-        self.sender.lock().await.send(value).unwrap_or_else(|e| {
-            println!("{} room remove client broadcast error {}", self.get_id(), e);
-            0
+    }
+    async fn close_if_idle(&self, since: Instant) {
+        let closing = {
+            let mut clients = self.clients.write().await;
+            if clients.is_empty()
+                && clients.state == RoomState::Open
+                && clients.idle_since == Some(since)
+            {
+                clients.state = RoomState::Closing;
+                true
+            } else {
+                false
+            }
+        };
+        if closing {
+            self.close().await;
+        }
+    }
+    async fn close(&self) {
+        if let Some(engine) = self.engine.upgrade() {
+            engine.room_remove(&self.id).await;
+        }
+        self.clients.write().await.state = RoomState::Closed;
+        println!("{} room closed", self.get_id());
+    }
+    pub(super) async fn to_snapshot(&self) -> RoomSnapshot {
+        RoomSnapshot {
+            id: self.id,
+            creator: self.creator,
+            name: self.name.clone(),
+            topic: self.get_topic().await,
+            persistent: self.persistent,
+            read_receipts: self.read_receipts,
+            roles: self.roles.read().await.clone(),
+            max_members: self.max_members,
+            password: self.get_password().await,
+            bans: self.banned.read().await.clone(),
+        }
+    }
+    /// Applies the topic and roles of a snapshot to the room created from it.
+    /// A non persistent room is removed after `grace` unless someone joins.
+    pub(super) async fn restore(&self, snapshot: &RoomSnapshot, grace: Duration) {
+        self.set_topic(snapshot.topic.clone()).await;
+        *self.roles.write().await = snapshot.roles.clone();
+        *self.banned.write().await = snapshot.bans.clone();
+        if self.persistent {
+            return;
+        }
+        let mut clients = self.clients.write().await;
+        if clients.is_empty() && clients.state == RoomState::Open {
+            let since = Instant::now();
+            clients.idle_since = Some(since);
+            self.schedule_idle_close(since, grace);
+        }
+    }
+    /// Closes the local copy of a room removed by another node.
+    pub(super) async fn close_replica(&self) {
+        {
+            let mut clients = self.clients.write().await;
+            if clients.state != RoomState::Open {
+                return;
+            }
+            clients.state = RoomState::Closing;
+        }
+        if let Some(engine) = self.engine.upgrade() {
+            engine.room_drop(&self.id).await;
+        }
+        self.clients.write().await.state = RoomState::Closed;
+        println!("{} room replica closed", self.get_id());
+    }
+    async fn send_to(&self, client_id: &Uuid, value: Value) {
+        if let Some(engine) = self.engine.upgrade() {
+            if let Some(client) = engine.get_client(client_id).await {
+                client.send(value).await;
+            }
+        }
+    }
+    pub(super) async fn send_unread(&self, client_id: &Uuid) {
+        if let Some(unread) = self.get_unread(client_id).await {
+            let last_seq = self.history.read().await.last_seq();
+            self.send_to(
+                client_id,
+                json!({
+                    "type": "EVENT",
+                    "event": {
+                        "type": "ROOM_UNREAD",
+                        "room_id": self.get_id().to_string(),
+                        "unread": unread,
+                        "last_seq": last_seq
+                    }
+                }),
+            )
+            .await;
+        }
+    }
+    async fn send_thread_update(&self, thread_id: &Uuid, reply_count: usize) {
+        self.broadcast(json!({
+            "type": "EVENT",
+            "event": {
+                "type": "THREAD_UPDATE",
+                "room_id": self.get_id().to_string(),
+                "thread_id": thread_id.to_string(),
+                "reply_count": reply_count
+            }
+        }))
+        .await;
+    }
+    /// Attachments are referenced by id and must have been uploaded to this room.
+    async fn resolve_attachments(&self, value: &Value) -> Result<Vec<Attachment>, RoomError> {
+        let ids = match value {
+            Value::Null => return Ok(Vec::new()),
+            Value::Array(ids) => ids,
+            _ => return Err(RoomError::InvalidRequest),
+        };
+        let engine = self.engine.upgrade().ok_or(RoomError::NotFound)?;
+        let mut attachments = Vec::with_capacity(ids.len());
+        for id in ids {
+            let id = id
+                .as_str()
+                .and_then(|s| Uuid::from_str(s).ok())
+                .ok_or(RoomError::InvalidRequest)?;
+            let attachment = engine
+                .get_attachment(&id)
+                .await
+                .filter(|attachment| &attachment.room_id == self.get_id())
+                .ok_or(RoomError::AttachmentNotFound)?;
+            attachments.push(attachment);
+        }
+        Ok(attachments)
+    }
+    /// Runs the message filters on the text of `data`, redacting it in place
+    /// and returning the reasons it was flagged for.
+    async fn moderate(&self, data: &mut Value, sender: &Uuid) -> Result<Vec<String>, RoomError> {
+        let engine = match self.engine.upgrade() {
+            Some(engine) => engine,
+            None => return Ok(Vec::new()),
+        };
+        let text = match data["message"].as_str() {
+            Some(text) => text,
+            None => return Ok(Vec::new()),
+        };
+        let outcome = match engine.get_filters().run(&self.id, sender, text).await {
+            Ok(outcome) => outcome,
+            Err(record) => {
+                let mut entry = AuditEntry::new(
+                    sender.to_string(),
+                    "MESSAGE_REJECTED",
+                    Some(self.id),
+                    Some(sender.to_string()),
+                );
+                entry.details = json!({ "filter": record.filter, "reason": record.reason });
+                engine.audit(entry);
+                return Err(RoomError::MessageRejected);
+            }
+        };
+        if let Some(text) = outcome.text {
+            data["message"] = json!(text);
+            data["redacted"] = json!(true);
+        }
+        Ok(outcome.flags)
+    }
+    /// Tells the owner and moderators connected to this node about a message
+    /// a filter flagged.
+    async fn send_flagged(&self, message_id: &Uuid, sender: &Uuid, reasons: Vec<String>) {
+        if reasons.is_empty() {
+            return;
+        }
+        let event = json!({
+            "type": "EVENT",
+            "event": {
+                "type": "MESSAGE_FLAGGED",
+                "room_id": self.get_id().to_string(),
+                "message_id": message_id.to_string(),
+                "client_id": sender.to_string(),
+                "reasons": reasons
+            }
         });
+        self.send_to_moderators(event).await;
+    }
+    /// Sends `value` to the owner and moderators connected to this node.
+    pub(super) async fn send_to_moderators(&self, value: Value) {
+        let moderators: Vec<Uuid> = self.roles.read().await.keys().copied().collect();
+        for moderator in moderators {
+            self.send_to(&moderator, value.clone()).await;
+        }
+    }
+    pub async fn is_banned(&self, client_id: &Uuid) -> bool {
+        self.banned.read().await.contains(client_id)
+    }
+    /// Bans a client from the room, removing it if it is a member.
+    /// `moderator_id` is `None` when banned through the admin API.
+    pub(super) async fn ban(&self, client_id: &Uuid, moderator_id: Option<&Uuid>) {
+        if !self.banned.write().await.insert(*client_id) {
+            return;
+        }
+        println!("{} client {} banned", self.id, client_id);
+        if let Some(engine) = self.engine.upgrade() {
+            engine.audit(AuditEntry::new(
+                moderator_id.map_or(ADMIN_ACTOR.to_string(), Uuid::to_string),
+                "ROOM_BAN",
+                Some(self.id),
+                Some(client_id.to_string()),
+            ));
+        }
+        self.broadcast(json!({
+            "type": "EVENT",
+            "event": {
+                "type": "ROOM_BAN",
+                "room_id": self.get_id().to_string(),
+                "client_id": client_id.to_string(),
+                "moderator_id": moderator_id.map(|id| id.to_string())
+            }
+        }))
+        .await;
+        let client = match self.engine.upgrade() {
+            Some(engine) => engine.get_client(client_id).await,
+            None => None,
+        };
+        match client {
+            Some(client) => client.leave_room(self.get_id()).await,
+            None => self.remove_client(client_id).await,
+        }
+    }
+    /// Deletes a message for everyone, `client_id` has to be its sender or a
+    /// moderator, `None` deletes it on behalf of the server.
+    pub(super) async fn delete_message(
+        &self,
+        message_id: &Uuid,
+        client_id: Option<&Uuid>,
+    ) -> Result<(), RoomError> {
+        let is_moderator = match client_id {
+            Some(client_id) => self.is_moderator(client_id).await,
+            None => true,
+        };
+        let (thread_update, replicas) = {
+            let mut history = self.history.write().await;
+            let message = history
+                .get_mut(message_id)
+                .filter(|message| !message.deleted)
+                .ok_or(RoomError::MessageNotFound)?;
+            if message.sender.as_ref() != client_id && !is_moderator {
+                return Err(RoomError::Forbidden);
+            }
+            message.deleted = true;
+            message.reactions.clear();
+            let thread_id = message.thread_id;
+            let thread_update = thread_id.and_then(|thread_id| {
+                history.get_mut(&thread_id).map(|root| {
+                    root.reply_count = root.reply_count.saturating_sub(1);
+                    (thread_id, root.reply_count)
+                })
+            });
+            let replicas = self.replicas(&history, &[Some(*message_id), thread_id]);
+            (thread_update, replicas)
+        };
+        if let Some(engine) = self.engine.upgrade() {
+            engine.index_remove(message_id).await;
+        }
+        self.replicate(replicas).await;
+        self.broadcast(json!({
+            "type": "EVENT",
+            "event": {
+                "type": "MESSAGE_DELETE",
+                "room_id": self.get_id().to_string(),
+                "message_id": message_id.to_string(),
+                "client_id": client_id.map(|id| id.to_string())
+            }
+        }))
+        .await;
+        if let Some((thread_id, reply_count)) = thread_update {
+            self.send_thread_update(&thread_id, reply_count).await;
+        }
+        Ok(())
+    }
+    /// Files a report from `reporter_id` and tells the moderators about it.
+    async fn file_report(&self, reporter_id: &Uuid, report: Report) -> Result<(), RoomError> {
+        let engine = self.engine.upgrade().ok_or(RoomError::NotFound)?;
+        let (report, created) = engine.file_report(report).await?;
+        if created {
+            println!(
+                "{} client {} filed report {}",
+                self.id, reporter_id, report.id
+            );
+            self.send_to_moderators(json!({
+                "type": "EVENT",
+                "event": {
+                    "type": "REPORT_CREATED",
+                    "room_id": self.get_id().to_string(),
+                    "report": report
+                }
+            }))
+            .await;
+        }
+        self.send_to(
+            reporter_id,
+            json!({
+                "type": "EVENT",
+                "event": {
+                    "type": "REPORT_FILED",
+                    "room_id": self.get_id().to_string(),
+                    "report_id": report.id.to_string()
+                }
+            }),
+        )
+        .await;
+        Ok(())
+    }
+    async fn check_member<'a>(&self, sender: Option<&'a Uuid>) -> Result<&'a Uuid, RoomError> {
+        let sender = sender.ok_or(RoomError::Forbidden)?;
+        if self.has_client(sender).await {
+            Ok(sender)
+        } else {
+            Err(RoomError::NotMember)
+        }
     }
-    pub async fn exec(&self, value: &Value, sender: Option<&Uuid>) {
+    pub async fn exec(&self, value: &Value, sender: Option<&Uuid>) -> Result<(), RoomError> {
         if value["action"] == "BROADCAST" {
-            let mut value = value["data"].clone();
+            let mut data = value["data"].clone();
+            // Messages from clients go through the filters, webhooks and
+            // commands posting on behalf of the server do not
+            let flags = match sender {
+                Some(sender) => {
+                    self.check_member(Some(sender)).await?;
+                    self.moderate(&mut data, sender).await?
+                }
+                None => Vec::new(),
+            };
+            let mut message = RoomMessage::new(self.get_id(), sender, data);
+            let message_id = message.id;
+            message.attachments = self
+                .resolve_attachments(&value["data"]["attachments"])
+                .await?;
+            // The sequence number is assigned under the history lock, the
+            // message is delivered and indexed after releasing it so a slow
+            // webhook or backplane does not hold up the room. Clients order
+            // messages by `seq` rather than by arrival.
+            let (value, document, thread_update, replicas) = {
+                let mut history = self.history.write().await;
+                let thread_update = match value.get("reply_to") {
+                    Some(reply_to) => {
+                        let reply_to = reply_to
+                            .as_str()
+                            .and_then(|s| Uuid::from_str(s).ok())
+                            .ok_or(RoomError::InvalidRequest)?;
+                        let parent = history.get(&reply_to).ok_or(RoomError::MessageNotFound)?;
+                        let thread_id = parent.thread_id.unwrap_or(parent.id);
+                        message.reply_to = Some(reply_to);
+                        message.thread_id = Some(thread_id);
+                        history.get_mut(&thread_id).map(|root| {
+                            root.reply_count += 1;
+                            (thread_id, root.reply_count)
+                        })
+                    }
+                    None => None,
+                };
+                let document = SearchDocument::new(&message);
+                let thread_id = message.thread_id;
+                let value = history.push(message);
+                let replicas = self.replicas(&history, &[Some(message_id), thread_id]);
+                (value, document, thread_update, replicas)
+            };
+            self.replicate(replicas).await;
+            self.broadcast(value).await;
+            if let Some(engine) = self.engine.upgrade() {
+                engine.index_message(document).await;
+            }
+            if let Some((thread_id, reply_count)) = thread_update {
+                self.send_thread_update(&thread_id, reply_count).await;
+            }
             if let Some(sender) = sender {
-                value["sender"] = json!(sender.to_string());
+                self.send_flagged(&message_id, sender, flags).await;
             }
-            value["room"] = json!(self.get_id().to_string());
-            self.sender.lock().await.send(value).unwrap_or_else(|e| {
-                println!("{} room exec broadcast error {}", self.get_id(), e);
-                0
-            });
+        } else if value["action"] == "MARK_READ" {
+            let sender = self.check_member(sender).await?;
+            let last_seq = self.history.read().await.last_seq();
+            let seq = match value.get("seq") {
+                Some(seq) => seq.as_u64().ok_or(RoomError::InvalidRequest)?.min(last_seq),
+                None => last_seq,
+            };
+            let updated = {
+                let mut last_read = self.last_read.write().await;
+                let last_read = last_read.entry(*sender).or_default();
+                if seq > *last_read {
+                    *last_read = seq;
+                    true
+                } else {
+                    false
+                }
+            };
+            if updated && self.read_receipts {
+                let receipt = json!({
+                    "type": "EVENT",
+                    "event": {
+                        "type": "READ_RECEIPT",
+                        "room_id": self.get_id().to_string(),
+                        "client_id": sender.to_string(),
+                        "seq": seq
+                    }
+                });
+                for client_id in self.get_clients_list().await {
+                    if &client_id != sender {
+                        self.send_to(&client_id, receipt.clone()).await;
+                    }
+                }
+            }
+            self.send_unread(sender).await;
+        } else if value["action"] == "THREAD_HISTORY" {
+            let sender = self.check_member(sender).await?;
+            let thread_id = value["thread_id"]
+                .as_str()
+                .and_then(|s| Uuid::from_str(s).ok())
+                .ok_or(RoomError::InvalidRequest)?;
+            let messages: Vec<Value> = {
+                let history = self.history.read().await;
+                let thread = history.thread(&thread_id);
+                if thread.is_empty() {
+                    return Err(RoomError::MessageNotFound);
+                }
+                thread.iter().map(|message| message.to_value()).collect()
+            };
+            self.send_to(
+                sender,
+                json!({
+                    "type": "EVENT",
+                    "event": {
+                        "type": "THREAD_HISTORY",
+                        "room_id": self.get_id().to_string(),
+                        "thread_id": thread_id.to_string(),
+                        "messages": messages
+                    }
+                }),
+            )
+            .await;
+        } else if value["action"] == "MESSAGE_EDIT" {
+            let sender = self.check_member(sender).await?;
+            let message_id = parse_message_id(value)?;
+            let mut data = value["data"].clone();
+            if !data.is_object() {
+                return Err(RoomError::InvalidRequest);
+            }
+            let flags = self.moderate(&mut data, sender).await?;
+            let (event, text, replicas) = {
+                let mut history = self.history.write().await;
+                let message = history
+                    .get_mut(&message_id)
+                    .filter(|message| !message.deleted)
+                    .ok_or(RoomError::MessageNotFound)?;
+                if message.sender.as_ref() != Some(sender) {
+                    return Err(RoomError::Forbidden);
+                }
+                strip_server_keys(&mut data);
+                data["type"] = message.data["type"].clone();
+                message.data = data;
+                message.edited_at = Some(now_millis());
+                let event = json!({
+                    "type": "EVENT",
+                    "event": {
+                        "type": "MESSAGE_EDIT",
+                        "room_id": self.get_id().to_string(),
+                        "message_id": message_id.to_string(),
+                        "data": message.to_value()
+                    }
+                });
+                let text = message_text(&message.data);
+                (event, text, self.replicas(&history, &[Some(message_id)]))
+            };
+            if let Some(engine) = self.engine.upgrade() {
+                engine.index_update(&message_id, text).await;
+            }
+            self.replicate(replicas).await;
+            self.broadcast(event).await;
+            self.send_flagged(&message_id, sender, flags).await;
+        } else if value["action"] == "MESSAGE_DELETE" {
+            let sender = self.check_member(sender).await?;
+            let message_id = parse_message_id(value)?;
+            self.delete_message(&message_id, Some(sender)).await?;
+        } else if value["action"] == "REPORT_MESSAGE" {
+            let sender = self.check_member(sender).await?;
+            let message_id = parse_message_id(value)?;
+            let reason = value["reason"].as_str().unwrap_or_default();
+            let mut report = Report::new(self.get_id(), sender, ReportKind::Message, reason)
+                .ok_or(RoomError::InvalidRequest)?;
+            {
+                let history = self.history.read().await;
+                let message = history
+                    .get(&message_id)
+                    .filter(|message| !message.deleted)
+                    .ok_or(RoomError::MessageNotFound)?;
+                report.client_id = message.sender;
+                report.message_id = Some(message_id);
+                report.message = Some(message.to_value());
+            }
+            self.file_report(sender, report).await?;
+        } else if value["action"] == "REPORT_CLIENT" {
+            let sender = self.check_member(sender).await?;
+            let client_id = value["client_id"]
+                .as_str()
+                .and_then(|s| Uuid::from_str(s).ok())
+                .filter(|client_id| client_id != sender)
+                .ok_or(RoomError::InvalidRequest)?;
+            if !self.get_clients_list().await.contains(&client_id) {
+                return Err(RoomError::NotMember);
+            }
+            let reason = value["reason"].as_str().unwrap_or_default();
+            let mut report = Report::new(self.get_id(), sender, ReportKind::Client, reason)
+                .ok_or(RoomError::InvalidRequest)?;
+            report.client_id = Some(client_id);
+            self.file_report(sender, report).await?;
+        } else if value["action"] == "MESSAGE_REACT" || value["action"] == "MESSAGE_UNREACT" {
+            let sender = self.check_member(sender).await?;
+            let message_id = parse_message_id(value)?;
+            let reaction = value["reaction"]
+                .as_str()
+                .filter(|reaction| !reaction.is_empty() && reaction.chars().count() <= 32)
+                .ok_or(RoomError::InvalidRequest)?;
+            let react = value["action"] == "MESSAGE_REACT";
+            let (count, replicas) = {
+                let mut history = self.history.write().await;
+                let message = history
+                    .get_mut(&message_id)
+                    .filter(|message| !message.deleted)
+                    .ok_or(RoomError::MessageNotFound)?;
+                let changed = if react {
+                    message
+                        .reactions
+                        .entry(reaction.to_string())
+                        .or_default()
+                        .insert(*sender)
+                } else {
+                    message
+                        .reactions
+                        .get_mut(reaction)
+                        .map(|clients| clients.remove(sender))
+                        .unwrap_or(false)
+                };
+                if !changed {
+                    return Ok(());
+                }
+                let count = message.reactions.get(reaction).map_or(0, |c| c.len());
+                if count == 0 {
+                    message.reactions.remove(reaction);
+                }
+                (count, self.replicas(&history, &[Some(message_id)]))
+            };
+            self.replicate(replicas).await;
+            self.broadcast(json!({
+                "type": "EVENT",
+                "event": {
+                    "type": value["action"],
+                    "room_id": self.get_id().to_string(),
+                    "message_id": message_id.to_string(),
+                    "client_id": sender.to_string(),
+                    "reaction": reaction,
+                    "count": count
+                }
+            }))
+            .await;
+        } else if value["action"] == "ROOM_PASSWORD" {
+            let sender = self.check_member(sender).await?;
+            if self.get_role(sender).await != Some(RoomRole::Owner) {
+                return Err(RoomError::Forbidden);
+            }
+            let password = match &value["password"] {
+                Value::Null => None,
+                Value::String(password) => Some(hash_password(password.clone()).await?),
+                _ => return Err(RoomError::InvalidRequest),
+            };
+            let protected = password.is_some();
+            self.set_password(password.clone()).await;
+            println!("{} room password changed by {}", self.id, sender);
+            if let Some(engine) = self.engine.upgrade() {
+                let mut entry =
+                    AuditEntry::new(sender.to_string(), "ROOM_PASSWORD", Some(self.id), None);
+                entry.details = json!({ "password_protected": protected });
+                engine.audit(entry);
+                engine
+                    .publish(ClusterEvent::RoomPasswordChanged {
+                        room_id: self.id,
+                        password,
+                    })
+                    .await;
+            }
+            self.broadcast(json!({
+                "type": "EVENT",
+                "event": {
+                    "type": "ROOM_PASSWORD",
+                    "room_id": self.get_id().to_string(),
+                    "client_id": sender.to_string(),
+                    "password_protected": protected
+                }
+            }))
+            .await;
         } else if value["action"] == "ROOM_EXIT" {
-            self.remove_client(sender.unwrap()).await;
+            if let (Some(sender), Some(engine)) = (sender, self.engine.upgrade()) {
+                if let Some(client) = engine.get_client(sender).await {
+                    client.leave_room(self.get_id()).await;
+                }
+            }
         } else if value["action"] == "SUBSCRIBE_ROOMS" {
         }
+        Ok(())
     }
 }
 
+fn parse_message_id(value: &Value) -> Result<Uuid, RoomError> {
+    value["message_id"]
+        .as_str()
+        .and_then(|s| Uuid::from_str(s).ok())
+        .ok_or(RoomError::InvalidRequest)
+}
+
 impl Drop for WebSocketRoom {
     fn drop(&mut self) {
         println!("{} room dropped", self.get_id());
@@ -0,0 +1,290 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use super::message::RoomMessage;
+
+const DEFAULT_RESULTS: usize = 20;
+const MAX_RESULTS: usize = 100;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SearchQuery {
+    /// Words that must all appear in the message text
+    pub query: Option<String>,
+    pub sender: Option<Uuid>,
+    pub room_id: Option<Uuid>,
+    /// Inclusive lower bound in milliseconds since the epoch
+    pub from: Option<u64>,
+    /// Inclusive upper bound in milliseconds since the epoch
+    pub to: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SearchDocument {
+    pub message_id: Uuid,
+    pub room_id: Uuid,
+    pub sender: Option<Uuid>,
+    pub timestamp: u64,
+    pub text: String,
+}
+
+impl SearchDocument {
+    pub fn new(message: &RoomMessage) -> Self {
+        Self {
+            message_id: message.id,
+            room_id: message.room_id,
+            sender: message.sender,
+            timestamp: message.timestamp,
+            text: message_text(&message.data),
+        }
+    }
+    pub fn to_value(&self) -> Value {
+        json!({
+            "message_id": self.message_id.to_string(),
+            "room_id": self.room_id.to_string(),
+            "sender": self.sender.map(|sender| sender.to_string()),
+            "timestamp": self.timestamp,
+            "text": self.text
+        })
+    }
+}
+
+pub fn message_text(data: &Value) -> String {
+    data["message"].as_str().unwrap_or_default().to_string()
+}
+
+fn tokenize(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Embedded inverted index over room messages, keeping the most recent
+/// `limit` documents.
+pub struct SearchIndex {
+    documents: HashMap<Uuid, SearchDocument>,
+    postings: HashMap<String, HashSet<Uuid>>,
+    order: VecDeque<Uuid>,
+    limit: usize,
+}
+
+impl SearchIndex {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            documents: HashMap::new(),
+            postings: HashMap::new(),
+            order: VecDeque::new(),
+            limit,
+        }
+    }
+    pub fn insert(&mut self, document: SearchDocument) {
+        if self.limit == 0 {
+            return;
+        }
+        self.remove(&document.message_id);
+        while self.documents.len() >= self.limit {
+            match self.order.pop_front() {
+                Some(message_id) => self.remove(&message_id),
+                None => break,
+            }
+        }
+        for token in tokenize(&document.text) {
+            self.postings
+                .entry(token)
+                .or_default()
+                .insert(document.message_id);
+        }
+        self.order.push_back(document.message_id);
+        self.documents.insert(document.message_id, document);
+    }
+    pub fn update_text(&mut self, message_id: &Uuid, text: String) {
+        if let Some(mut document) = self.documents.get(message_id).cloned() {
+            document.text = text;
+            self.insert(document);
+        }
+    }
+    pub fn remove(&mut self, message_id: &Uuid) {
+        if let Some(document) = self.documents.remove(message_id) {
+            for token in tokenize(&document.text) {
+                if let Some(postings) = self.postings.get_mut(&token) {
+                    postings.remove(message_id);
+                    if postings.is_empty() {
+                        self.postings.remove(&token);
+                    }
+                }
+            }
+            // Stale ids are skipped on eviction, only compact once they pile up
+            if self.order.len() > self.documents.len() * 2 + 16 {
+                let documents = &self.documents;
+                self.order
+                    .retain(|message_id| documents.contains_key(message_id));
+            }
+        }
+    }
+    pub fn remove_room(&mut self, room_id: &Uuid) {
+        let message_ids: Vec<Uuid> = self
+            .documents
+            .values()
+            .filter(|document| &document.room_id == room_id)
+            .map(|document| document.message_id)
+            .collect();
+        for message_id in message_ids {
+            self.remove(&message_id);
+        }
+    }
+    /// Newest matches first, restricted to `rooms` when given.
+    pub fn search(
+        &self,
+        query: &SearchQuery,
+        rooms: Option<&HashSet<Uuid>>,
+    ) -> Vec<&SearchDocument> {
+        let tokens = tokenize(query.query.as_deref().unwrap_or_default());
+        let mut candidates: Vec<&SearchDocument> = if tokens.is_empty() {
+            self.documents.values().collect()
+        } else {
+            let mut postings: Vec<&HashSet<Uuid>> = Vec::with_capacity(tokens.len());
+            for token in &tokens {
+                match self.postings.get(token) {
+                    Some(ids) => postings.push(ids),
+                    None => return Vec::new(),
+                }
+            }
+            postings.sort_by_key(|ids| ids.len());
+            let (first, rest) = postings.split_first().expect("tokens is not empty");
+            first
+                .iter()
+                .filter(|message_id| rest.iter().all(|ids| ids.contains(message_id)))
+                .filter_map(|message_id| self.documents.get(message_id))
+                .collect()
+        };
+        candidates.retain(|document| {
+            rooms.is_none_or(|rooms| rooms.contains(&document.room_id))
+                && query
+                    .room_id
+                    .is_none_or(|room_id| document.room_id == room_id)
+                && query
+                    .sender
+                    .is_none_or(|sender| document.sender == Some(sender))
+                && query.from.is_none_or(|from| document.timestamp >= from)
+                && query.to.is_none_or(|to| document.timestamp <= to)
+        });
+        candidates.sort_by_key(|document| Reverse(document.timestamp));
+        candidates.truncate(query.limit.unwrap_or(DEFAULT_RESULTS).min(MAX_RESULTS));
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(room_id: &Uuid, timestamp: u64, text: &str) -> SearchDocument {
+        SearchDocument {
+            message_id: Uuid::new_v4(),
+            room_id: *room_id,
+            sender: None,
+            timestamp,
+            text: text.to_string(),
+        }
+    }
+
+    fn query(text: &str) -> SearchQuery {
+        SearchQuery {
+            query: Some(text.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn texts(results: Vec<&SearchDocument>) -> Vec<&str> {
+        results
+            .into_iter()
+            .map(|document| document.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn tokens_are_lowercase_words() {
+        let tokens = tokenize("Hello, WORLD! it's 2024-06 héllo");
+        let expected: HashSet<String> = ["hello", "world", "it", "s", "2024", "06", "héllo"]
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(tokens, expected);
+        assert!(tokenize(" ,.; ").is_empty());
+    }
+
+    #[test]
+    fn every_word_must_match() {
+        let room_id = Uuid::new_v4();
+        let mut index = SearchIndex::new(10);
+        index.insert(document(&room_id, 1, "Deploy the backend"));
+        index.insert(document(&room_id, 2, "deploy FAILED"));
+        assert_eq!(
+            texts(index.search(&query("DEPLOY"), None)),
+            ["deploy FAILED", "Deploy the backend"]
+        );
+        assert_eq!(
+            texts(index.search(&query("backend, deploy"), None)),
+            ["Deploy the backend"]
+        );
+        assert!(index.search(&query("deploy frontend"), None).is_empty());
+        // Words match whole, not as prefixes
+        assert!(index.search(&query("dep"), None).is_empty());
+    }
+
+    #[test]
+    fn results_are_scoped_to_rooms() {
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let mut index = SearchIndex::new(10);
+        index.insert(document(&first, 1, "hello first"));
+        index.insert(document(&second, 2, "hello second"));
+
+        let member_of = HashSet::from([first]);
+        assert_eq!(
+            texts(index.search(&query("hello"), Some(&member_of))),
+            ["hello first"]
+        );
+        let mut in_room = query("hello");
+        in_room.room_id = Some(second);
+        assert_eq!(texts(index.search(&in_room, None)), ["hello second"]);
+        assert!(index.search(&in_room, Some(&member_of)).is_empty());
+
+        index.remove_room(&first);
+        assert_eq!(texts(index.search(&query("hello"), None)), ["hello second"]);
+    }
+
+    #[test]
+    fn removed_and_edited_messages_are_unindexed() {
+        let room_id = Uuid::new_v4();
+        let mut index = SearchIndex::new(2);
+        let removed = document(&room_id, 1, "first draft");
+        let removed_id = removed.message_id;
+        index.insert(removed);
+        let edited = document(&room_id, 2, "second draft");
+        let edited_id = edited.message_id;
+        index.insert(edited);
+
+        index.remove(&removed_id);
+        index.update_text(&edited_id, "final version".to_string());
+        assert!(index.search(&query("draft"), None).is_empty());
+        assert_eq!(
+            texts(index.search(&query("final"), None)),
+            ["final version"]
+        );
+        assert!(!index.postings.contains_key("first"));
+
+        // The oldest documents make room for new ones
+        index.insert(document(&room_id, 3, "third"));
+        index.insert(document(&room_id, 4, "fourth"));
+        assert!(index.search(&query("final"), None).is_empty());
+        assert_eq!(index.documents.len(), 2);
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::PathBuf,
+};
+
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::Mutex};
+use uuid::Uuid;
+
+use super::room::RoomRole;
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Durable part of a room, members and messages are not kept across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomSnapshot {
+    pub id: Uuid,
+    pub creator: Uuid,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub persistent: bool,
+    pub read_receipts: bool,
+    pub roles: HashMap<Uuid, RoomRole>,
+    #[serde(default)]
+    pub max_members: Option<usize>,
+    /// Argon2 hash of the room password
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub bans: HashSet<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub version: u32,
+    pub created_at: u64,
+    pub rooms: Vec<RoomSnapshot>,
+}
+
+impl Snapshot {
+    pub fn new(created_at: u64, rooms: Vec<RoomSnapshot>) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            created_at,
+            rooms,
+        }
+    }
+}
+
+/// Storage backend for engine snapshots, only the latest one is kept.
+pub trait SnapshotStore: Send + Sync {
+    fn save<'a>(&'a self, snapshot: &'a Snapshot) -> BoxFuture<'a, io::Result<()>>;
+    /// `None` when nothing was saved yet.
+    fn load(&self) -> BoxFuture<'_, io::Result<Option<Snapshot>>>;
+}
+
+/// Stores the snapshot as a JSON file, replaced atomically on every save.
+pub struct FileSnapshotStore {
+    path: PathBuf,
+    // Saves share the temporary file, the periodic one can run on shutdown
+    saving: Mutex<()>,
+}
+
+impl FileSnapshotStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            saving: Mutex::new(()),
+        }
+    }
+}
+
+impl SnapshotStore for FileSnapshotStore {
+    fn save<'a>(&'a self, snapshot: &'a Snapshot) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let data = serde_json::to_vec_pretty(snapshot).map_err(io::Error::other)?;
+            let _saving = self.saving.lock().await;
+            let temporary = self.path.with_extension("tmp");
+            fs::write(&temporary, data).await?;
+            fs::rename(&temporary, &self.path).await
+        })
+    }
+    fn load(&self) -> BoxFuture<'_, io::Result<Option<Snapshot>>> {
+        Box::pin(async move {
+            let data = match fs::read(&self.path).await {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let snapshot: Snapshot = serde_json::from_slice(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if snapshot.version != SNAPSHOT_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported snapshot version {}", snapshot.version),
+                ));
+            }
+            Ok(Some(snapshot))
+        })
+    }
+}
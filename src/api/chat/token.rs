@@ -0,0 +1,39 @@
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Tokens proving an HTTP request is made on behalf of a connected client.
+/// Client ids are public, they appear in every message, so each connection
+/// gets a token signed with a key only this process knows in its
+/// `CLIENT_JOIN` event.
+pub(super) struct ClientTokens {
+    key: [u8; 32],
+}
+
+impl ClientTokens {
+    pub(super) fn new() -> Self {
+        Self {
+            key: rand::random(),
+        }
+    }
+    fn mac(&self, client_id: &Uuid) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac accepts any key size");
+        mac.update(client_id.as_bytes());
+        mac
+    }
+    pub(super) fn issue(&self, client_id: &Uuid) -> String {
+        let signature = self.mac(client_id).finalize().into_bytes();
+        format!("{}.{}", client_id.simple(), hex::encode(signature))
+    }
+    /// The client the token was issued to, `None` when it is not a valid
+    /// token. The signature is compared in constant time.
+    pub(super) fn verify(&self, token: &str) -> Option<Uuid> {
+        let (client_id, signature) = token.split_once('.')?;
+        let client_id = Uuid::from_str(client_id).ok()?;
+        let signature = hex::decode(signature).ok()?;
+        self.mac(&client_id).verify_slice(&signature).ok()?;
+        Some(client_id)
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use hyper::{client::HttpConnector, Body, Client, Method, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::{
+    spawn,
+    sync::{
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+        RwLock, Semaphore,
+    },
+    time::{sleep, timeout},
+};
+use uuid::Uuid;
+
+use crate::config::WebhookConfig;
+
+use super::message::now_millis;
+
+const MAX_CONCURRENT_DELIVERIES: usize = 8;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutgoingWebhook {
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+    pub url: String,
+    /// Rooms whose events are delivered, every room when unset
+    pub rooms: Option<HashSet<Uuid>>,
+    /// Event types delivered (`MESSAGE`, `ROOM_JOIN`...), every event when unset
+    pub events: Option<HashSet<String>>,
+    /// Key of the HMAC-SHA256 signature sent in `X-Chat-Signature`
+    pub secret: String,
+}
+
+impl OutgoingWebhook {
+    fn matches(&self, room_id: &Uuid, event_type: &str) -> bool {
+        self.rooms
+            .as_ref()
+            .is_none_or(|rooms| rooms.contains(room_id))
+            && self
+                .events
+                .as_ref()
+                .is_none_or(|events| events.contains(event_type))
+    }
+    pub fn to_value(&self) -> Value {
+        json!({
+            "id": self.id.to_string(),
+            "url": self.url,
+            "rooms": self.rooms,
+            "events": self.events
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct IncomingWebhook {
+    /// Secret part of the `/hooks/{token}` url
+    #[serde(default = "random_token")]
+    pub token: String,
+    pub room_id: Uuid,
+    pub name: String,
+}
+
+impl IncomingWebhook {
+    pub fn to_value(&self) -> Value {
+        json!({
+            "token": self.token,
+            "room_id": self.room_id.to_string(),
+            "name": self.name,
+            "url": format!("/hooks/{}", self.token)
+        })
+    }
+}
+
+fn random_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+struct Delivery {
+    id: Uuid,
+    webhook: OutgoingWebhook,
+    event_type: String,
+    body: String,
+}
+
+type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+/// Delivers room events to outgoing webhooks through a bounded queue, and
+/// keeps the registry of incoming webhooks.
+pub struct WebhookDispatcher {
+    outgoing: RwLock<HashMap<Uuid, OutgoingWebhook>>,
+    incoming: RwLock<HashMap<String, IncomingWebhook>>,
+    queue: Sender<Delivery>,
+    // Taken by the first dispatch, so the worker starts inside the runtime
+    receiver: StdMutex<Option<Receiver<Delivery>>>,
+    config: WebhookConfig,
+}
+
+impl WebhookDispatcher {
+    pub fn new(config: WebhookConfig) -> Self {
+        let (queue, receiver) = mpsc::channel(config.queue_size.max(1));
+        let outgoing = config
+            .outgoing
+            .iter()
+            .map(|webhook| (webhook.id, webhook.clone()))
+            .collect();
+        let incoming = config
+            .incoming
+            .iter()
+            .map(|webhook| (webhook.token.clone(), webhook.clone()))
+            .collect();
+        Self {
+            outgoing: RwLock::new(outgoing),
+            incoming: RwLock::new(incoming),
+            queue,
+            receiver: StdMutex::new(Some(receiver)),
+            config,
+        }
+    }
+    pub async fn add_outgoing(&self, webhook: OutgoingWebhook) {
+        self.outgoing.write().await.insert(webhook.id, webhook);
+    }
+    pub async fn remove_outgoing(&self, id: &Uuid) -> bool {
+        self.outgoing.write().await.remove(id).is_some()
+    }
+    pub async fn get_outgoing_list(&self) -> Vec<OutgoingWebhook> {
+        self.outgoing.read().await.values().cloned().collect()
+    }
+    pub async fn add_incoming(&self, webhook: IncomingWebhook) {
+        self.incoming
+            .write()
+            .await
+            .insert(webhook.token.clone(), webhook);
+    }
+    pub async fn remove_incoming(&self, token: &str) -> bool {
+        self.incoming.write().await.remove(token).is_some()
+    }
+    pub async fn get_incoming(&self, token: &str) -> Option<IncomingWebhook> {
+        self.incoming.read().await.get(token).cloned()
+    }
+    pub async fn get_incoming_list(&self) -> Vec<IncomingWebhook> {
+        self.incoming.read().await.values().cloned().collect()
+    }
+    /// Queues `value` for every matching webhook, dropping deliveries when
+    /// the queue is full rather than slowing down the room.
+    pub async fn dispatch(&self, room_id: &Uuid, value: &Value) {
+        let event_type = match value["type"].as_str() {
+            Some("EVENT") => value["event"]["type"].as_str(),
+            event_type => event_type,
+        }
+        .unwrap_or_default();
+        let webhooks: Vec<OutgoingWebhook> = self
+            .outgoing
+            .read()
+            .await
+            .values()
+            .filter(|webhook| webhook.matches(room_id, event_type))
+            .cloned()
+            .collect();
+        if webhooks.is_empty() {
+            return;
+        }
+        self.start_worker();
+        for webhook in webhooks {
+            let id = Uuid::new_v4();
+            let body = json!({
+                "id": id.to_string(),
+                "webhook_id": webhook.id.to_string(),
+                "timestamp": now_millis(),
+                "room_id": room_id.to_string(),
+                "event": value
+            })
+            .to_string();
+            let delivery = Delivery {
+                id,
+                webhook,
+                event_type: event_type.to_string(),
+                body,
+            };
+            match self.queue.try_send(delivery) {
+                Ok(()) => {}
+                Err(TrySendError::Full(delivery)) => {
+                    println!("{} webhook queue full, dropping delivery", delivery.id)
+                }
+                Err(TrySendError::Closed(delivery)) => {
+                    println!("{} webhook queue closed, dropping delivery", delivery.id)
+                }
+            }
+        }
+    }
+    fn start_worker(&self) {
+        let receiver = match self.receiver.lock() {
+            Ok(mut receiver) => receiver.take(),
+            Err(_) => None,
+        };
+        if let Some(mut receiver) = receiver {
+            let config = self.config.clone();
+            spawn(async move {
+                let connector = HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build();
+                let client: HttpClient = Client::builder().build(connector);
+                let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES));
+                while let Some(delivery) = receiver.recv().await {
+                    let permit = match Arc::clone(&semaphore).acquire_owned().await {
+                        Ok(permit) => permit,
+                        Err(_) => break,
+                    };
+                    let client = client.clone();
+                    let config = config.clone();
+                    spawn(async move {
+                        deliver(&client, &config, delivery).await;
+                        drop(permit);
+                    });
+                }
+                println!("webhook delivery worker exit");
+            });
+        }
+    }
+}
+
+pub fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn deliver(client: &HttpClient, config: &WebhookConfig, delivery: Delivery) {
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=config.max_attempts.max(1) {
+        let timestamp = now_millis();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&delivery.webhook.url)
+            .header("content-type", "application/json")
+            .header("x-chat-event", &delivery.event_type)
+            .header("x-chat-delivery", delivery.id.to_string())
+            .header("x-chat-timestamp", timestamp.to_string())
+            .header(
+                "x-chat-signature",
+                sign(&delivery.webhook.secret, timestamp, &delivery.body),
+            )
+            .body(Body::from(delivery.body.clone()));
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                println!("{} webhook invalid request {}", delivery.id, e);
+                return;
+            }
+        };
+        match timeout(config.timeout(), client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => return,
+            Ok(Ok(response)) => println!(
+                "{} webhook {} attempt {} failed with status {}",
+                delivery.id,
+                delivery.webhook.url,
+                attempt,
+                response.status()
+            ),
+            Ok(Err(e)) => println!(
+                "{} webhook {} attempt {} failed {}",
+                delivery.id, delivery.webhook.url, attempt, e
+            ),
+            Err(_) => println!(
+                "{} webhook {} attempt {} timed out",
+                delivery.id, delivery.webhook.url, attempt
+            ),
+        }
+        if attempt < config.max_attempts {
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    println!("{} webhook delivery abandoned", delivery.id);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use hyper::{body::Bytes, HeaderMap, StatusCode};
+    use tokio::sync::mpsc::UnboundedReceiver;
+    use warp::Filter;
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    struct Received {
+        headers: HeaderMap,
+        body: Bytes,
+        at: Instant,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> &str {
+            self.headers[name].to_str().unwrap()
+        }
+    }
+
+    /// Webhook endpoint on a local port answering every request with `status`.
+    fn endpoint(status: StatusCode) -> (String, UnboundedReceiver<Received>) {
+        let (sender, requests) = mpsc::unbounded_channel();
+        let route = warp::post()
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .map(move |headers, body| {
+                let _ = sender.send(Received {
+                    headers,
+                    body,
+                    at: Instant::now(),
+                });
+                warp::reply::with_status(warp::reply(), status)
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        spawn(server);
+        (format!("http://{}/hook", address), requests)
+    }
+
+    fn webhook(url: &str) -> OutgoingWebhook {
+        OutgoingWebhook {
+            id: Uuid::new_v4(),
+            url: url.to_string(),
+            rooms: None,
+            events: None,
+            secret: "secret".to_string(),
+        }
+    }
+
+    async fn next(requests: &mut UnboundedReceiver<Received>) -> Received {
+        timeout(WAIT, requests.recv())
+            .await
+            .expect("no webhook request")
+            .unwrap()
+    }
+
+    async fn assert_idle(requests: &mut UnboundedReceiver<Received>) {
+        let request = timeout(Duration::from_millis(300), requests.recv()).await;
+        assert!(request.is_err(), "unexpected webhook request");
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed() {
+        let (url, mut requests) = endpoint(StatusCode::OK);
+        let dispatcher = WebhookDispatcher::new(WebhookConfig {
+            outgoing: vec![webhook(&url)],
+            ..Default::default()
+        });
+        let room_id = Uuid::new_v4();
+        dispatcher
+            .dispatch(&room_id, &json!({ "type": "MESSAGE", "message": "hi" }))
+            .await;
+        let request = next(&mut requests).await;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(format!("{}.", request.header("x-chat-timestamp")).as_bytes());
+        mac.update(&request.body);
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        assert_eq!(request.header("x-chat-signature"), signature);
+        assert_eq!(request.header("x-chat-event"), "MESSAGE");
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["id"], request.header("x-chat-delivery"));
+        assert_eq!(body["room_id"], room_id.to_string());
+        assert_eq!(body["event"]["message"], "hi");
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried_with_backoff() {
+        let (url, mut requests) = endpoint(StatusCode::INTERNAL_SERVER_ERROR);
+        let dispatcher = WebhookDispatcher::new(WebhookConfig {
+            max_attempts: 3,
+            outgoing: vec![webhook(&url)],
+            ..Default::default()
+        });
+        dispatcher
+            .dispatch(&Uuid::new_v4(), &json!({ "type": "MESSAGE" }))
+            .await;
+        let first = next(&mut requests).await;
+        let second = next(&mut requests).await;
+        let third = next(&mut requests).await;
+        assert_idle(&mut requests).await;
+
+        assert!(second.at - first.at >= Duration::from_secs(1));
+        assert!(third.at - second.at >= Duration::from_secs(2));
+        let delivery = first.header("x-chat-delivery");
+        assert_eq!(second.header("x-chat-delivery"), delivery);
+        assert_eq!(third.header("x-chat-delivery"), delivery);
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_dropped_when_the_queue_is_full() {
+        let (url, mut requests) = endpoint(StatusCode::OK);
+        let dispatcher = WebhookDispatcher::new(WebhookConfig {
+            queue_size: 1,
+            outgoing: vec![webhook(&url), webhook(&url), webhook(&url)],
+            ..Default::default()
+        });
+        // The worker only starts taking deliveries once the dispatch yields
+        dispatcher
+            .dispatch(&Uuid::new_v4(), &json!({ "type": "MESSAGE" }))
+            .await;
+        next(&mut requests).await;
+        assert_idle(&mut requests).await;
+    }
+
+    #[tokio::test]
+    async fn deliveries_match_rooms_and_events() {
+        let (url, mut requests) = endpoint(StatusCode::OK);
+        let room_id = Uuid::new_v4();
+        let mut filtered = webhook(&url);
+        filtered.rooms = Some(HashSet::from([room_id]));
+        filtered.events = Some(HashSet::from(["ROOM_JOIN".to_string()]));
+        let dispatcher = WebhookDispatcher::new(WebhookConfig {
+            outgoing: vec![filtered],
+            ..Default::default()
+        });
+        let join = json!({ "type": "EVENT", "event": { "type": "ROOM_JOIN" } });
+        dispatcher.dispatch(&Uuid::new_v4(), &join).await;
+        dispatcher
+            .dispatch(&room_id, &json!({ "type": "MESSAGE" }))
+            .await;
+        dispatcher.dispatch(&room_id, &join).await;
+        let request = next(&mut requests).await;
+        assert_eq!(request.header("x-chat-event"), "ROOM_JOIN");
+        assert_idle(&mut requests).await;
+    }
+}
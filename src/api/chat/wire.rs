@@ -0,0 +1,208 @@
+use std::{
+    fmt,
+    sync::{Arc, OnceLock},
+};
+
+use serde_json::Value;
+use warp::filters::ws::Message;
+
+/// Serialization of the protocol on a websocket, picked per connection with
+/// the `Sec-WebSocket-Protocol` header. Text frames are always JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+#[derive(Debug)]
+pub enum WireError {
+    Json(serde_json::Error),
+    MessagePackEncode(rmp_serde::encode::Error),
+    MessagePackDecode(rmp_serde::decode::Error),
+    /// Ping, pong and close frames carry no protocol message
+    NotData,
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Json(e) => write!(f, "invalid JSON {}", e),
+            WireError::MessagePackEncode(e) => write!(f, "MessagePack encoding failed {}", e),
+            WireError::MessagePackDecode(e) => write!(f, "invalid MessagePack {}", e),
+            WireError::NotData => write!(f, "not a data frame"),
+        }
+    }
+}
+
+impl WireFormat {
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            WireFormat::Json => "chat.json",
+            WireFormat::MessagePack => "chat.msgpack",
+        }
+    }
+    fn from_protocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            "chat.json" => Some(WireFormat::Json),
+            "chat.msgpack" => Some(WireFormat::MessagePack),
+            _ => None,
+        }
+    }
+    /// Picks the first supported format of a `Sec-WebSocket-Protocol` list,
+    /// in the client's order of preference.
+    pub fn negotiate(protocols: &str) -> Option<Self> {
+        protocols
+            .split(',')
+            .find_map(|protocol| Self::from_protocol(protocol.trim()))
+    }
+    pub fn decode(&self, message: &Message) -> Result<Value, WireError> {
+        if message.is_text() {
+            serde_json::from_slice(message.as_bytes()).map_err(WireError::Json)
+        } else if message.is_binary() {
+            match self {
+                WireFormat::Json => {
+                    serde_json::from_slice(message.as_bytes()).map_err(WireError::Json)
+                }
+                WireFormat::MessagePack => {
+                    rmp_serde::from_slice(message.as_bytes()).map_err(WireError::MessagePackDecode)
+                }
+            }
+        } else {
+            Err(WireError::NotData)
+        }
+    }
+}
+
+/// A protocol message shared by every recipient of a broadcast, serialized
+/// at most once per wire format whatever the number of recipients.
+pub struct Frame {
+    value: Value,
+    json: OnceLock<Arc<str>>,
+    message_pack: OnceLock<Arc<[u8]>>,
+}
+
+impl Frame {
+    pub fn new(value: Value) -> Self {
+        Self {
+            value,
+            json: OnceLock::new(),
+            message_pack: OnceLock::new(),
+        }
+    }
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+    /// The event or message type, what logs show of a frame since frames
+    /// such as `CLIENT_JOIN` carry credentials.
+    pub fn kind(&self) -> &str {
+        self.value["event"]["type"]
+            .as_str()
+            .or(self.value["type"].as_str())
+            .unwrap_or_default()
+    }
+    pub fn json(&self) -> Arc<str> {
+        Arc::clone(self.json.get_or_init(|| self.value.to_string().into()))
+    }
+    pub fn message_pack(&self) -> Result<Arc<[u8]>, WireError> {
+        if let Some(bytes) = self.message_pack.get() {
+            return Ok(Arc::clone(bytes));
+        }
+        let bytes: Arc<[u8]> = rmp_serde::to_vec_named(&self.value)
+            .map_err(WireError::MessagePackEncode)?
+            .into();
+        Ok(Arc::clone(self.message_pack.get_or_init(|| bytes)))
+    }
+    /// The websocket message of one recipient. Serialization is shared, but
+    /// warp messages own their payload as a `String` or `Vec<u8>`, so the
+    /// encoded bytes are still copied once per recipient. See
+    /// `benches/wire.rs` for what this saves over encoding per recipient.
+    pub fn to_message(&self, format: WireFormat) -> Result<Message, WireError> {
+        match format {
+            WireFormat::Json => Ok(Message::text(&*self.json())),
+            WireFormat::MessagePack => Ok(Message::binary(self.message_pack()?.to_vec())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn frames_are_encoded_once_per_format() {
+        let frame = Arc::new(Frame::new(json!({ "type": "MESSAGE", "message": "hi" })));
+        let recipients: Vec<Arc<Frame>> = (0..3).map(|_| Arc::clone(&frame)).collect();
+        let json: Vec<Arc<str>> = recipients.iter().map(|frame| frame.json()).collect();
+        let message_pack: Vec<Arc<[u8]>> = recipients
+            .iter()
+            .map(|frame| frame.message_pack().unwrap())
+            .collect();
+        assert!(json.iter().all(|encoded| Arc::ptr_eq(encoded, &json[0])));
+        assert!(message_pack
+            .iter()
+            .all(|encoded| Arc::ptr_eq(encoded, &message_pack[0])));
+
+        for frame in &recipients {
+            let text = frame.to_message(WireFormat::Json).unwrap();
+            assert_eq!(text.to_str().unwrap(), &*json[0]);
+            let binary = frame.to_message(WireFormat::MessagePack).unwrap();
+            assert_eq!(binary.as_bytes(), &*message_pack[0]);
+        }
+        assert!(Arc::ptr_eq(&frame.json(), &json[0]));
+        assert!(Arc::ptr_eq(
+            &frame.message_pack().unwrap(),
+            &message_pack[0]
+        ));
+    }
+
+    #[test]
+    fn formats_round_trip_to_the_same_value() {
+        let value = json!({
+            "type": "MESSAGE",
+            "message": "héllo",
+            "seq": 42,
+            "ratio": 0.5,
+            "edited": null,
+            "tags": ["a", true, -1],
+            "data": { "nested": { "empty": {} } }
+        });
+        let frame = Frame::new(value.clone());
+        for format in [WireFormat::Json, WireFormat::MessagePack] {
+            let message = frame.to_message(format).unwrap();
+            assert_eq!(format.decode(&message).unwrap(), value, "{:?}", format);
+        }
+        // Text frames are JSON whatever was negotiated
+        let text = Message::text(value.to_string());
+        assert_eq!(WireFormat::MessagePack.decode(&text).unwrap(), value);
+        assert!(matches!(
+            WireFormat::MessagePack.decode(&Message::binary(vec![0xc1])),
+            Err(WireError::MessagePackDecode(_))
+        ));
+        assert!(matches!(
+            WireFormat::Json.decode(&Message::ping(Vec::new())),
+            Err(WireError::NotData)
+        ));
+    }
+
+    #[test]
+    fn negotiation_follows_the_client_preference() {
+        assert_eq!(
+            WireFormat::negotiate("chat.msgpack, chat.json"),
+            Some(WireFormat::MessagePack)
+        );
+        assert_eq!(
+            WireFormat::negotiate("chat.xml,chat.json"),
+            Some(WireFormat::Json)
+        );
+        for unknown in ["chat.xml", "CHAT.MSGPACK", ""] {
+            assert_eq!(WireFormat::negotiate(unknown), None);
+        }
+        assert_eq!(
+            WireFormat::negotiate("chat.xml").unwrap_or_default(),
+            WireFormat::Json
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::{convert::Infallible, sync::Arc};
+
+use futures_util::stream;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::spawn;
+use uuid::Uuid;
+use warp::{
+    http::StatusCode,
+    reject::Rejection,
+    reply::{json, with_status, Reply, Response},
+    sse::{self, Event},
+    Filter,
+};
+
+use super::{
+    admission::{admission_filter, Admission, ConnectionPermit},
+    chat::ChatManager,
+    error_reply,
+};
+
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+#[derive(Deserialize)]
+struct ActionQuery {
+    session_id: Uuid,
+}
+
+/// Removes the client of an event stream once its response is dropped. The
+/// session id authenticates `POST /actions` and is never logged.
+struct Session {
+    id: Uuid,
+    client_id: Uuid,
+    chat_manager: Arc<ChatManager>,
+    _permit: ConnectionPermit,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let id = self.id;
+        let client_id = self.client_id;
+        let chat_manager = Arc::clone(&self.chat_manager);
+        spawn(async move {
+            chat_manager.remove_session(&id).await;
+            println!("{} event stream closed", client_id);
+        });
+    }
+}
+
+/// Fallback transport for clients that cannot upgrade to a websocket: events
+/// are read from `GET /events` and actions sent to
+/// `POST /actions?session_id=...` with the session id of the `CLIENT_JOIN`
+/// event.
+pub fn events_filter(
+    chat_manager: Arc<ChatManager>,
+    admission: Arc<Admission>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let chat_manager_filter = warp::any().map(move || Arc::clone(&chat_manager));
+    let events = warp::path("events")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(admission_filter(admission))
+        .and(chat_manager_filter.clone())
+        .then(events);
+    let actions = warp::path("actions")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::query::<ActionQuery>())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+        .and(chat_manager_filter)
+        .then(actions);
+    events.or(actions).unify()
+}
+
+async fn events(permit: ConnectionPermit, chat_manager: Arc<ChatManager>) -> Response {
+    let (session_id, client, receiver) = chat_manager.create_session(permit.ip()).await;
+    let client_id = *client.get_id();
+    println!("{} event stream opened", client_id);
+    client
+        .send(json!({
+            "type": "EVENT",
+            "event": {
+                "type": "CLIENT_JOIN",
+                "client_id": client_id.to_string(),
+                "session_id": session_id.to_string(),
+                "token": chat_manager.get_client_token(&client_id)
+            }
+        }))
+        .await;
+    drop(client);
+    let session = Session {
+        id: session_id,
+        client_id,
+        chat_manager,
+        _permit: permit,
+    };
+    let events = stream::unfold((receiver, session), |(mut receiver, session)| async move {
+        let frame = receiver.recv().await?;
+        let event = Event::default().data(&*frame.json());
+        Some((Ok::<_, Infallible>(event), (receiver, session)))
+    });
+    sse::reply(sse::keep_alive().stream(events)).into_response()
+}
+
+async fn actions(query: ActionQuery, value: Value, chat_manager: Arc<ChatManager>) -> Response {
+    match chat_manager.get_session_client(&query.session_id).await {
+        Some(client) => {
+            client.exec(&value).await;
+            with_status(json(&json!({ "ok": true })), StatusCode::ACCEPTED).into_response()
+        }
+        None => error_reply("SESSION_NOT_FOUND", StatusCode::NOT_FOUND),
+    }
+}
@@ -0,0 +1,230 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use warp::{
+    cors::Builder,
+    http::{header::HeaderName, Method},
+    reject::{self, Reject, Rejection},
+    Filter,
+};
+
+use crate::config::CorsConfig;
+
+#[derive(Debug)]
+pub struct ForbiddenOrigin;
+
+impl Reject for ForbiddenOrigin {}
+
+/// Rejects requests whose `Origin` is neither the host they were sent to nor
+/// one of the allowed origins. WebSocket upgrades, on which browsers always
+/// send it, are rejected without an `Origin`; other requests without one are
+/// same-origin page loads or come from outside a browser, and are accepted.
+pub fn origin_filter(config: &CorsConfig) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let allowed = Arc::new(AllowedOrigins::new(&config.allowed_origins));
+    warp::header::optional::<String>("origin")
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("upgrade"))
+        .and(warp::path::full())
+        .and_then(
+            move |origin: Option<String>,
+                  host: Option<String>,
+                  upgrade: Option<String>,
+                  path: warp::path::FullPath| {
+                let allowed = Arc::clone(&allowed);
+                async move {
+                    let accepted = match &origin {
+                        Some(origin) => allowed.accepts(origin, host.as_deref()),
+                        None => !upgrade
+                            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket")),
+                    };
+                    if accepted {
+                        Ok(())
+                    } else {
+                        println!(
+                            "rejected origin {} for {}",
+                            origin.as_deref().unwrap_or("(none)"),
+                            path.as_str()
+                        );
+                        Err(reject::custom(ForbiddenOrigin))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+struct AllowedOrigins {
+    any: bool,
+    origins: HashSet<String>,
+    /// Scheme and parent domain of the `scheme://*.domain` origins
+    subdomains: Vec<(String, String)>,
+}
+
+impl AllowedOrigins {
+    fn new(origins: &[String]) -> Self {
+        let mut allowed = Self {
+            any: false,
+            origins: HashSet::new(),
+            subdomains: Vec::new(),
+        };
+        for origin in origins.iter().map(|origin| normalize(origin)) {
+            if origin == "*" {
+                allowed.any = true;
+            } else if let Some((scheme, domain)) = origin
+                .split_once("://")
+                .and_then(|(scheme, authority)| Some((scheme, authority.strip_prefix("*.")?)))
+            {
+                allowed
+                    .subdomains
+                    .push((scheme.to_string(), domain.to_string()));
+            } else {
+                allowed.origins.insert(origin);
+            }
+        }
+        allowed
+    }
+    fn accepts(&self, origin: &str, host: Option<&str>) -> bool {
+        let origin = normalize(origin);
+        // Sent by sandboxed documents and local files, whoever they come from
+        if origin == "null" {
+            return false;
+        }
+        if self.any || self.origins.contains(&origin) {
+            return true;
+        }
+        let Some((scheme, authority)) = origin.split_once("://") else {
+            return false;
+        };
+        host.is_some_and(|host| authority == host.to_ascii_lowercase())
+            || self.subdomains.iter().any(|(allowed_scheme, domain)| {
+                allowed_scheme == scheme
+                    && authority
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.'))
+            })
+    }
+}
+
+/// CORS headers for the allowed methods and headers. Origins are left to
+/// `origin_filter`, which runs first and also accepts same-origin requests.
+pub fn cors(config: &CorsConfig) -> Builder {
+    let methods: Vec<Method> = config
+        .allowed_methods
+        .iter()
+        .filter_map(|method| match Method::from_bytes(method.as_bytes()) {
+            Ok(method) => Some(method),
+            Err(_) => {
+                println!("cors ignoring invalid method {}", method);
+                None
+            }
+        })
+        .collect();
+    let headers: Vec<HeaderName> = config
+        .allowed_headers
+        .iter()
+        .filter_map(|header| match HeaderName::from_bytes(header.as_bytes()) {
+            Ok(header) => Some(header),
+            Err(_) => {
+                println!("cors ignoring invalid header {}", header);
+                None
+            }
+        })
+        .collect();
+    warp::cors()
+        .allow_any_origin()
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .max_age(Duration::from_secs(config.max_age))
+}
+
+fn normalize(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allowed_origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: allowed_origins
+                .iter()
+                .map(|origin| origin.to_string())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether `origin_filter` accepts a request sent to chat.local with
+    /// `origin`, as a WebSocket upgrade when `upgrade` is set.
+    async fn accepted(config: &CorsConfig, origin: Option<&str>, upgrade: bool) -> bool {
+        let mut request = warp::test::request()
+            .path("/ws")
+            .header("host", "chat.local");
+        if let Some(origin) = origin {
+            request = request.header("origin", origin);
+        }
+        if upgrade {
+            request = request.header("upgrade", "websocket");
+        }
+        match request.filter(&origin_filter(config)).await {
+            Ok(()) => true,
+            Err(rejection) => {
+                assert!(rejection.find::<ForbiddenOrigin>().is_some());
+                false
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn listed_and_same_origins_are_accepted() {
+        let config = config(&["https://app.example.com/"]);
+        for origin in [
+            "https://app.example.com",
+            "HTTPS://App.Example.com/",
+            "http://chat.local",
+        ] {
+            assert!(accepted(&config, Some(origin), true).await, "{}", origin);
+        }
+        for origin in [
+            "http://app.example.com",
+            "https://app.example.com:8443",
+            "https://evil.com",
+            "https://chat.local.evil.com",
+        ] {
+            assert!(!accepted(&config, Some(origin), true).await, "{}", origin);
+        }
+    }
+
+    #[tokio::test]
+    async fn wildcards_allow_subdomains_or_any_origin() {
+        let subdomains = config(&["https://*.example.com"]);
+        assert!(accepted(&subdomains, Some("https://app.example.com"), true).await);
+        assert!(accepted(&subdomains, Some("https://a.b.example.com"), true).await);
+        for origin in [
+            "https://example.com",
+            "https://.example.com",
+            "https://badexample.com",
+            "http://app.example.com",
+        ] {
+            assert!(
+                !accepted(&subdomains, Some(origin), true).await,
+                "{}",
+                origin
+            );
+        }
+
+        let any = config(&["*"]);
+        assert!(accepted(&any, Some("https://evil.com"), true).await);
+        assert!(!accepted(&any, Some("null"), true).await);
+    }
+
+    #[tokio::test]
+    async fn upgrades_need_an_origin() {
+        let config = config(&["https://app.example.com"]);
+        assert!(!accepted(&config, None, true).await);
+        assert!(!accepted(&config, Some("null"), true).await);
+        assert!(!accepted(&config, Some("null"), false).await);
+        // Page loads and clients outside a browser send none
+        assert!(accepted(&config, None, false).await);
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use futures_util::TryStreamExt;
+use serde::Deserialize;
+use uuid::Uuid;
+use warp::{
+    http::{header, StatusCode},
+    hyper::body::Buf,
+    multipart::FormData,
+    reject::Rejection,
+    reply::{json, with_header, with_status, Reply, Response},
+    Filter,
+};
+
+use super::{chat::ChatManager, error_reply, room_error_reply};
+
+/// Image types displayed inline, anything else is downloaded so that an
+/// uploaded page or SVG cannot run scripts on this origin.
+const INLINE_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// `token` is the one of the `CLIENT_JOIN` event, client ids are public.
+#[derive(Deserialize)]
+struct UploadQuery {
+    token: String,
+    room_id: Uuid,
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    token: String,
+}
+
+pub fn upload_filter(
+    chat_manager: Arc<ChatManager>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let max_size = chat_manager.get_config().uploads.max_size;
+    let chat_manager_filter = warp::any().map(move || Arc::clone(&chat_manager));
+    let upload = warp::path("attachments")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::query::<UploadQuery>())
+        .and(warp::multipart::form().max_length(max_size))
+        .and(chat_manager_filter.clone())
+        .and_then(upload);
+    let download = warp::path!("attachments" / Uuid)
+        .and(warp::get())
+        .and(warp::query::<DownloadQuery>())
+        .and(chat_manager_filter)
+        .and_then(download);
+    upload.or(download)
+}
+
+async fn upload(
+    query: UploadQuery,
+    mut form: FormData,
+    chat_manager: Arc<ChatManager>,
+) -> Result<Response, Rejection> {
+    let client_id = match chat_manager.verify_client_token(&query.token) {
+        Some(client_id) => client_id,
+        None => return Ok(error_reply("INVALID_TOKEN", StatusCode::UNAUTHORIZED)),
+    };
+    loop {
+        let part = match form.try_next().await {
+            Ok(Some(part)) => part,
+            Ok(None) => return Ok(error_reply("MISSING_FILE", StatusCode::BAD_REQUEST)),
+            Err(e) => {
+                println!("{} upload form error {}", client_id, e);
+                return Ok(error_reply("INVALID_FORM", StatusCode::BAD_REQUEST));
+            }
+        };
+        if part.name() != "file" {
+            continue;
+        }
+        let name = part.filename().unwrap_or("attachment").to_string();
+        let content_type = part
+            .content_type()
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let data = part
+            .stream()
+            .try_fold(Vec::new(), |mut data, buf| async move {
+                data.extend_from_slice(buf.chunk());
+                Ok(data)
+            })
+            .await;
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                println!("{} upload read error {}", client_id, e);
+                return Ok(error_reply("INVALID_FORM", StatusCode::BAD_REQUEST));
+            }
+        };
+        return Ok(
+            match chat_manager
+                .upload_attachment(&client_id, &query.room_id, name, content_type, data)
+                .await
+            {
+                Ok(attachment) => {
+                    with_status(json(&attachment.to_value()), StatusCode::CREATED).into_response()
+                }
+                Err(error) => room_error_reply(error),
+            },
+        );
+    }
+}
+
+async fn download(
+    attachment_id: Uuid,
+    query: DownloadQuery,
+    chat_manager: Arc<ChatManager>,
+) -> Result<Response, Rejection> {
+    let client_id = match chat_manager.verify_client_token(&query.token) {
+        Some(client_id) => client_id,
+        None => return Ok(error_reply("INVALID_TOKEN", StatusCode::UNAUTHORIZED)),
+    };
+    Ok(
+        match chat_manager
+            .download_attachment(&client_id, &attachment_id)
+            .await
+        {
+            Ok((attachment, data)) => {
+                let disposition = if INLINE_TYPES.contains(&attachment.content_type.as_str()) {
+                    "inline"
+                } else {
+                    "attachment"
+                };
+                let reply = with_header(data, header::CONTENT_TYPE, attachment.content_type);
+                let reply = with_header(
+                    reply,
+                    header::CONTENT_DISPOSITION,
+                    format!(
+                        "{}; filename=\"{}\"",
+                        disposition,
+                        attachment.name.replace(['"', '\r', '\n'], "")
+                    ),
+                );
+                with_header(reply, header::X_CONTENT_TYPE_OPTIONS, "nosniff").into_response()
+            }
+            Err(error) => room_error_reply(error),
+        },
+    )
+}
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use warp::{
+    http::StatusCode,
+    reject::Rejection,
+    reply::{json, with_status, Reply, Response},
+    Filter,
+};
+
+use super::{chat::ChatManager, room_error_reply};
+
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// Incoming webhooks, `POST /hooks/{token}` with a JSON object posts it as a
+/// message into the room the token was registered for.
+pub fn webhook_filter(
+    chat_manager: Arc<ChatManager>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let chat_manager_filter = warp::any().map(move || Arc::clone(&chat_manager));
+    warp::path!("hooks" / String)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+        .and(chat_manager_filter)
+        .then(incoming)
+}
+
+async fn incoming(token: String, data: Value, chat_manager: Arc<ChatManager>) -> Response {
+    match chat_manager.post_incoming_webhook(&token, data).await {
+        Ok(()) => with_status(json(&json!({ "ok": true })), StatusCode::ACCEPTED).into_response(),
+        Err(error) => room_error_reply(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{sync::mpsc, time::timeout};
+
+    use crate::{
+        api::chat::{client::ClientSender, webhook::IncomingWebhook},
+        config::ChatConfig,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn incoming_webhooks_need_a_registered_token() {
+        let manager = Arc::new(ChatManager::new(ChatConfig::default()).await);
+        let (sender, mut events) = mpsc::channel(16);
+        let client = manager
+            .create_client(ClientSender::EventStream(sender), None)
+            .await;
+        let room = manager
+            .create_room(vec![client.get_id()])
+            .await
+            .expect("room created");
+        manager
+            .get_webhooks()
+            .add_incoming(IncomingWebhook {
+                token: "token".to_string(),
+                room_id: *room.get_id(),
+                name: "ci".to_string(),
+            })
+            .await;
+        let filter = webhook_filter(Arc::clone(&manager));
+        let post = |path: &str, body: Value| {
+            warp::test::request()
+                .method("POST")
+                .path(path)
+                .json(&body)
+                .reply(&filter)
+        };
+
+        let response = post("/hooks/wrong", json!({ "message": "hi" })).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = post("/hooks/token", json!("hi")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = post("/hooks/token", json!({ "message": "hi" })).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let message = timeout(Duration::from_secs(2), async {
+            loop {
+                let frame = events.recv().await.expect("client disconnected");
+                if frame.value()["type"] == "MESSAGE" {
+                    return frame.value().clone();
+                }
+            }
+        })
+        .await
+        .expect("no webhook message");
+        assert_eq!(message["message"], "hi");
+        assert_eq!(message["webhook"], "ci");
+        assert!(message.get("sender").is_none());
+    }
+}
@@ -1,44 +1,69 @@
-use std::{
-    str::FromStr,
-    sync::{Arc, Weak},
-};
+use std::sync::{Arc, Weak};
 
 use futures_util::StreamExt;
-use serde_json::{json, Value};
-use warp::{reject::Rejection, reply::Reply, Filter};
+use serde_json::json;
+use warp::{
+    reject::Rejection,
+    reply::{with_header, Reply},
+    Filter,
+};
 
-use super::chat::{client::WebSocketClient, ChatManager};
+use super::{
+    admission::{admission_filter, Admission, ConnectionPermit},
+    chat::{
+        client::{ClientSender, WebSocketClient},
+        wire::WireFormat,
+        ChatManager,
+    },
+};
 
+/// The wire format is negotiated with `Sec-WebSocket-Protocol`, `chat.json`
+/// or `chat.msgpack`, connections without the header use JSON. Upgrades over
+/// the connection limits are rejected before the handshake.
 pub fn websocket_filter(
     chat_manager: Arc<ChatManager>,
+    admission: Arc<Admission>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let chat_manager_filter = warp::any().map(move || Arc::clone(&chat_manager));
     warp::path("ws")
         .and(warp::ws())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(admission_filter(admission))
         .and(chat_manager_filter.clone())
-        .map(|ws: warp::ws::Ws, chat_manager: Arc<ChatManager>| {
-            ws.on_upgrade(|ws| async move {
-                let (sender, mut websocket_listener) = ws.split();
-                let websocket_client = chat_manager.create_client(sender).await;
-                let client_id = websocket_client.get_id();
-                websocket_client
-                    .send(json!({
-                        "type": "EVENT",
-                        "event": {
-                            "type": "CLIENT_JOIN",
-                            "client_id": client_id.to_string()
-                        }
-                    }))
-                    .await;
-                let websocket_client: Weak<WebSocketClient> = Arc::downgrade(&websocket_client);
-                while let Some(message) = websocket_listener.next().await {
-                    match message {
-                        Ok(message) => match message.to_str() {
+        .map(
+            |ws: warp::ws::Ws,
+             protocols: Option<String>,
+             permit: ConnectionPermit,
+             chat_manager: Arc<ChatManager>| {
+                let protocol = protocols.as_deref().and_then(WireFormat::negotiate);
+                let format = protocol.unwrap_or_default();
+                let reply = ws.on_upgrade(move |ws| async move {
+                    let (sender, mut websocket_listener) = ws.split();
+                    let websocket_client = chat_manager
+                        .create_client(ClientSender::WebSocket(sender, format), permit.ip())
+                        .await;
+                    let client_id = websocket_client.get_id();
+                    println!("{} client using {}", client_id, format.protocol());
+                    websocket_client
+                        .send(json!({
+                            "type": "EVENT",
+                            "event": {
+                                "type": "CLIENT_JOIN",
+                                "client_id": client_id.to_string(),
+                                "token": chat_manager.get_client_token(client_id)
+                            }
+                        }))
+                        .await;
+                    let websocket_client: Weak<WebSocketClient> = Arc::downgrade(&websocket_client);
+                    while let Some(message) = websocket_listener.next().await {
+                        match message {
+                            Ok(message) if message.is_ping() || message.is_pong() => {}
+                            Ok(message) if message.is_close() => break,
                             Ok(message) => match websocket_client.upgrade() {
-                                Some(websocket_client) => match Value::from_str(message) {
+                                Some(websocket_client) => match format.decode(&message) {
                                     Ok(value) => websocket_client.exec(&value).await,
                                     Err(e) => {
-                                        println!("{} error parsing JSON {}", client_id, e)
+                                        println!("{} error decoding message {}", client_id, e)
                                     }
                                 },
                                 None => {
@@ -46,14 +71,55 @@ pub fn websocket_filter(
                                     break;
                                 }
                             },
-                            Err(e) => {
-                                println!("{} error getting string from socket {:?}", client_id, e)
-                            }
-                        },
-                        Err(e) => println!("{} error reading socket {:?}", client_id, e),
+                            Err(e) => println!("{} error reading socket {:?}", client_id, e),
+                        }
                     }
+                    chat_manager.remove_client(client_id).await;
+                    drop(permit);
+                });
+                match protocol {
+                    Some(format) => with_header(reply, "sec-websocket-protocol", format.protocol())
+                        .into_response(),
+                    None => reply.into_response(),
                 }
-                chat_manager.remove_client(client_id).await;
-            })
-        })
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::config::{ChatConfig, ConnectionConfig};
+
+    use super::*;
+
+    /// The first frame sent on a connection negotiated with `protocols`.
+    async fn first_frame(protocols: &str) -> warp::ws::Message {
+        let chat_manager = Arc::new(ChatManager::new(ChatConfig::default()).await);
+        let admission = Arc::new(Admission::new(ConnectionConfig::default()));
+        let mut client = warp::test::ws()
+            .path("/ws")
+            .header("sec-websocket-protocol", protocols)
+            .handshake(websocket_filter(chat_manager, admission))
+            .await
+            .expect("handshake");
+        client.recv().await.expect("client join")
+    }
+
+    #[tokio::test]
+    async fn unknown_protocols_fall_back_to_json() {
+        let message = first_frame("chat.xml").await;
+        assert!(message.is_text());
+        let value: Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(value["event"]["type"], "CLIENT_JOIN");
+    }
+
+    #[tokio::test]
+    async fn message_pack_is_used_when_negotiated() {
+        let message = first_frame("chat.xml, chat.msgpack").await;
+        assert!(message.is_binary());
+        let value: Value = rmp_serde::from_slice(message.as_bytes()).unwrap();
+        assert_eq!(value["event"]["type"], "CLIENT_JOIN");
+    }
 }
@@ -0,0 +1,450 @@
+use std::{
+    error::Error,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::api::chat::webhook::{IncomingWebhook, OutgoingWebhook};
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// Path to a TOML configuration file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub chat: ChatConfig,
+    pub admin: AdminConfig,
+}
+
+impl Config {
+    pub fn load(args: &Args) -> Result<Self, Box<dyn Error>> {
+        match &args.config {
+            Some(path) => Ok(toml::from_str(&fs::read_to_string(path)?)?),
+            None => Ok(Config::default()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: SocketAddr,
+    /// Serves HTTPS on `address` when set
+    pub tls: Option<TlsConfig>,
+    pub cors: CorsConfig,
+    pub connections: ConnectionConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: ([127, 0, 0, 1], 3030).into(),
+            tls: None,
+            cors: CorsConfig::default(),
+            connections: ConnectionConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key, PKCS#8, PKCS#1 or SEC1
+    pub key: PathBuf,
+    /// Plain HTTP listener redirecting every request to HTTPS
+    pub redirect_address: Option<SocketAddr>,
+    /// Seconds between two checks of the files for changes, 0 only reloads
+    /// them on SIGHUP
+    #[serde(default = "default_watch_interval")]
+    pub watch_interval: u64,
+}
+
+impl TlsConfig {
+    pub fn watch_interval(&self) -> Duration {
+        Duration::from_secs(self.watch_interval)
+    }
+}
+
+fn default_watch_interval() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to open a WebSocket or call the HTTP routes besides the
+    /// server's own, `"https://*.example.com"` allows the subdomains of a
+    /// domain and `"*"` any origin
+    pub allowed_origins: Vec<String>,
+    /// Methods allowed in cross-origin requests
+    pub allowed_methods: Vec<String>,
+    /// Headers allowed in cross-origin requests
+    pub allowed_headers: Vec<String>,
+    /// Seconds browsers may cache a preflight response
+    pub max_age: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "DELETE"].map(String::from).to_vec(),
+            allowed_headers: ["authorization", "content-type"].map(String::from).to_vec(),
+            max_age: 600,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    /// WebSocket and event stream connections open at once, 0 for no limit
+    pub max_connections: usize,
+    /// Connections open at once from a single IP address, 0 for no limit
+    pub max_per_ip: usize,
+    /// Connections a single IP address may open per minute, 0 for no limit
+    pub rate_per_minute: u32,
+    /// Reverse proxies whose `X-Forwarded-For` header is trusted to carry the
+    /// client address
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10_000,
+            max_per_ip: 20,
+            rate_per_minute: 60,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// Bearer token required by the admin API, which is disabled when unset
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChatConfig {
+    /// Seconds an empty, non persistent room is kept before being removed
+    pub room_idle_ttl: u64,
+    /// Number of messages kept per room for edits, deletions and reactions
+    pub history_limit: usize,
+    /// Number of messages kept in the search index across all rooms
+    pub search_limit: usize,
+    /// Persistent rooms created at startup
+    pub rooms: Vec<RoomConfig>,
+    pub uploads: UploadConfig,
+    pub webhooks: WebhookConfig,
+    pub send_queue: SendQueueConfig,
+    pub cluster: ClusterConfig,
+    pub snapshot: SnapshotConfig,
+    pub limits: LimitsConfig,
+    pub moderation: ModerationConfig,
+    pub audit: AuditConfig,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            room_idle_ttl: 0,
+            history_limit: 100,
+            search_limit: 10_000,
+            rooms: Vec::new(),
+            uploads: UploadConfig::default(),
+            webhooks: WebhookConfig::default(),
+            send_queue: SendQueueConfig::default(),
+            cluster: ClusterConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            limits: LimitsConfig::default(),
+            moderation: ModerationConfig::default(),
+            audit: AuditConfig::default(),
+        }
+    }
+}
+
+impl ChatConfig {
+    pub fn room_idle_ttl(&self) -> Duration {
+        Duration::from_secs(self.room_idle_ttl)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// Directory used by the local blob store
+    pub directory: PathBuf,
+    /// Maximum size in bytes of an uploaded file
+    pub max_size: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("uploads"),
+            max_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// Deliveries waiting to be sent, further events are dropped
+    pub queue_size: usize,
+    /// Attempts per delivery, retried with exponential backoff
+    pub max_attempts: u32,
+    /// Seconds to wait for a webhook response
+    pub timeout: u64,
+    pub outgoing: Vec<OutgoingWebhook>,
+    pub incoming: Vec<IncomingWebhook>,
+}
+
+impl WebhookConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 1000,
+            max_attempts: 5,
+            timeout: 10,
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued frame to make room for the new one
+    #[default]
+    DropOldest,
+    /// Drop read receipts and unread counts, disconnect when only other frames are queued
+    DropEvents,
+    /// Disconnect the client
+    Disconnect,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SendQueueConfig {
+    /// Frames waiting to be written to a client before the overflow policy applies
+    pub size: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> Self {
+        Self {
+            size: 256,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// `host:port` of the Redis server relaying events between nodes, the
+    /// engine runs as a single node when unset
+    pub redis: Option<String>,
+    /// Redis pub/sub channel shared by the nodes
+    pub channel: String,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            redis: None,
+            channel: "chat-engine".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    /// File rooms are saved to and restored from, disabled when unset
+    pub path: Option<PathBuf>,
+    /// Seconds between two snapshots, 0 only saves on shutdown
+    pub interval: u64,
+    /// Seconds a restored room waits for a member before being removed
+    pub restore_grace: u64,
+}
+
+impl SnapshotConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+    pub fn restore_grace(&self) -> Duration {
+        Duration::from_secs(self.restore_grace)
+    }
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            interval: 60,
+            restore_grace: 300,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Members of a room unless the room sets its own limit, 0 for no limit
+    pub max_members: usize,
+    /// Rooms a client can be a member of at once, 0 for no limit
+    pub max_joined_rooms: usize,
+    /// Open rooms created by a client, 0 for no limit
+    pub max_created_rooms: usize,
+    /// Wrong passwords in a row before a client is locked out of a room, 0
+    /// for no lockout
+    pub max_password_attempts: u32,
+    /// Seconds a client stays locked out of a room
+    pub password_lockout: u64,
+}
+
+impl LimitsConfig {
+    pub fn password_lockout(&self) -> Duration {
+        Duration::from_secs(self.password_lockout)
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_members: 1000,
+            max_joined_rooms: 100,
+            max_created_rooms: 20,
+            max_password_attempts: 5,
+            password_lockout: 300,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterMode {
+    /// Refuse the message, the sender gets a `MESSAGE_REJECTED` error
+    #[default]
+    Reject,
+    /// Mask the offending text and deliver the rest
+    Redact,
+    /// Deliver the message and notify the room moderators
+    Flag,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RegexRuleConfig {
+    pub name: String,
+    pub pattern: String,
+    #[serde(default)]
+    pub action: FilterMode,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ModerationConfig {
+    /// Words matched case-insensitively as whole words
+    pub profanity: Vec<String>,
+    pub profanity_action: FilterMode,
+    pub rules: Vec<RegexRuleConfig>,
+    /// What happens to messages with links, allowed when unset
+    pub links: Option<FilterMode>,
+    /// Domains, and their subdomains, links are always allowed to
+    pub allowed_domains: Vec<String>,
+    /// Characters in a message, longer messages are rejected, 0 for no limit
+    pub max_length: usize,
+    /// Rejected, redacted and flagged messages kept for the admin API
+    pub log_limit: usize,
+    /// Reports kept for the admin API, handled ones are dropped first and new
+    /// ones rejected when all of them are open
+    pub report_limit: usize,
+    /// Open reports a client can have at once, 0 for no limit
+    pub max_open_reports: usize,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            profanity: Vec::new(),
+            profanity_action: FilterMode::Redact,
+            rules: Vec::new(),
+            links: None,
+            allowed_domains: Vec::new(),
+            max_length: 4000,
+            log_limit: 1000,
+            report_limit: 10_000,
+            max_open_reports: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// JSON lines file entries are appended to, kept in memory when unset
+    pub path: Option<PathBuf>,
+    /// Seconds entries are kept, 0 keeps them forever
+    pub retention: u64,
+    /// Entries kept when the log is in memory
+    pub memory_limit: usize,
+    /// Entries waiting to be written, further entries are dropped and counted
+    pub queue_size: usize,
+}
+
+impl AuditConfig {
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(self.retention)
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            retention: 30 * 24 * 3600,
+            memory_limit: 10_000,
+            queue_size: 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RoomConfig {
+    pub name: String,
+    pub id: Option<Uuid>,
+    /// Overrides `chat.limits.max_members` for this room
+    pub max_members: Option<usize>,
+    #[serde(default = "default_true")]
+    pub read_receipts: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
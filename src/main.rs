@@ -1,9 +1,24 @@
 use std::{convert::Infallible, sync::Arc};
 
-use chat_engine::api::{chat::ChatManager, websocket::websocket_filter};
+use chat_engine::{
+    api::{
+        admin::{admin_filter, Unauthorized},
+        admission::{Admission, ConnectionRejected},
+        chat::ChatManager,
+        events::events_filter,
+        origin::{cors, origin_filter, ForbiddenOrigin},
+        upload::upload_filter,
+        webhook::webhook_filter,
+        websocket::websocket_filter,
+    },
+    config::{Args, Config},
+    tls::{self, redirect_filter, CertificateResolver},
+};
+use clap::Parser;
 use rust_embed::RustEmbed;
 use tokio::spawn;
 use warp::{
+    cors::CorsForbidden,
     http::StatusCode,
     reject::Rejection,
     reply::{with_status, Reply},
@@ -17,6 +32,12 @@ struct Static;
 async fn handle_rejection(error: Rejection) -> Result<impl Reply, Infallible> {
     if error.is_not_found() {
         Ok(with_status("NOT FOUND", StatusCode::NOT_FOUND))
+    } else if error.find::<Unauthorized>().is_some() {
+        Ok(with_status("UNAUTHORIZED", StatusCode::UNAUTHORIZED))
+    } else if let Some(rejected) = error.find::<ConnectionRejected>() {
+        Ok(with_status(rejected.code(), rejected.status()))
+    } else if error.find::<ForbiddenOrigin>().is_some() || error.find::<CorsForbidden>().is_some() {
+        Ok(with_status("FORBIDDEN", StatusCode::FORBIDDEN))
     } else {
         Ok(with_status(
             "SERVER ERROR",
@@ -25,26 +46,87 @@ async fn handle_rejection(error: Rejection) -> Result<impl Reply, Infallible> {
     }
 }
 
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<(dyn std::error::Error + 'static)>> {
-    let websocket_manager = Arc::new(ChatManager::default());
+async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let args = Args::parse();
+    let config = Config::load(&args)?;
+    let address = config.server.address;
+
+    let websocket_manager = Arc::new(ChatManager::new(config.chat).await);
 
-    let websocket_api = websocket_filter(Arc::clone(&websocket_manager));
+    let admission = Arc::new(Admission::new(config.server.connections.clone()));
+    let websocket_api = websocket_filter(Arc::clone(&websocket_manager), Arc::clone(&admission));
+    let events_api = events_filter(Arc::clone(&websocket_manager), admission);
+    let upload_api = upload_filter(Arc::clone(&websocket_manager));
+    let webhook_api = webhook_filter(Arc::clone(&websocket_manager));
+    if config.admin.token.is_none() {
+        println!("admin api disabled, no token configured");
+    }
+    let admin_api = admin_filter(Arc::clone(&websocket_manager), config.admin.token);
 
     let static_content = warp::any()
         .and(warp::get())
         .and(warp_embed::embed(&Static))
         .boxed();
 
-    let routes = websocket_api.or(static_content);
+    let routes = websocket_api
+        .or(events_api)
+        .or(upload_api)
+        .or(webhook_api)
+        .or(admin_api)
+        .or(static_content);
 
-    let routes = routes.recover(handle_rejection);
-    let routes = routes.with(warp::cors());
+    // The origin is checked before any route, including the CORS preflight
+    let routes = origin_filter(&config.server.cors)
+        .and(
+            routes
+                .recover(handle_rejection)
+                .with(cors(&config.server.cors)),
+        )
+        .recover(handle_rejection);
 
-    let http_server_handle = spawn(async move {
-        warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-    });
+    let http_server_handle = match config.server.tls {
+        Some(tls) => {
+            if let Some(redirect_address) = tls.redirect_address {
+                println!("redirecting http://{} to https", redirect_address);
+                spawn(warp::serve(redirect_filter(address.port())).run(redirect_address));
+            }
+            let resolver = Arc::new(CertificateResolver::new(tls)?);
+            CertificateResolver::watch(&resolver);
+            spawn(tls::serve(warp::service(routes), address, resolver))
+        }
+        None => spawn(async move {
+            warp::serve(routes).run(address).await;
+            Ok(())
+        }),
+    };
 
-    http_server_handle.await?;
+    tokio::select! {
+        result = http_server_handle => result??,
+        _ = shutdown_signal() => println!("shutting down"),
+    }
+    websocket_manager.save_snapshot().await;
     Ok(())
 }
@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+use chat_engine::{
+    api::chat::{audit::AuditQuery, report::ReportAction, ChatManager},
+    config::{ChatConfig, FilterMode},
+};
+use serde_json::json;
+use tokio::time::{sleep, timeout};
+use uuid::Uuid;
+
+use common::{connect, next_matching, room_action, WAIT};
+
+mod common;
+
+/// Entries of `room_id` recorded with `action`, once the audit worker
+/// stored at least one.
+async fn recorded(manager: &ChatManager, room_id: &Uuid, action: &str) -> usize {
+    let query = AuditQuery {
+        action: Some(action.to_string()),
+        room_id: Some(*room_id),
+        ..Default::default()
+    };
+    timeout(WAIT, async {
+        loop {
+            let entries = manager.query_audit(&query).await.expect("audit query");
+            if !entries.is_empty() {
+                return entries.len();
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("no {} entry", action))
+}
+
+#[tokio::test]
+async fn moderation_actions_are_audited_once() {
+    let mut config = ChatConfig::default();
+    config.moderation.max_length = 10;
+    let manager = ChatManager::new(config).await;
+    let (alice, mut alice_events) = connect(&manager).await;
+    let (bob, _bob_events) = connect(&manager).await;
+    let (carol, _carol_events) = connect(&manager).await;
+    let (dave, _dave_events) = connect(&manager).await;
+    let room = manager
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+    let room_id = *room.get_id();
+    for client in [&bob, &carol, &dave] {
+        client.join_room(&room_id).await.expect("joined");
+    }
+
+    let mut op = room_action(&room_id, "COMMAND");
+    op["name"] = json!("op");
+    op["args"] = json!(bob.get_id().to_string());
+    alice.exec(&op).await;
+    let mut kick = room_action(&room_id, "COMMAND");
+    kick["name"] = json!("kick");
+    kick["args"] = json!(format!("{} spam", carol.get_id()));
+    alice.exec(&kick).await;
+    let mut password = room_action(&room_id, "ROOM_PASSWORD");
+    password["password"] = json!("hunter2");
+    alice.exec(&password).await;
+    let mut report = room_action(&room_id, "REPORT_CLIENT");
+    report["client_id"] = json!(dave.get_id().to_string());
+    report["reason"] = json!("spam");
+    alice.exec(&report).await;
+    next_matching(&mut alice_events, |value| {
+        value["event"]["type"] == "REPORT_CREATED"
+    })
+    .await;
+    let report = manager.get_reports(None).await.remove(0);
+    manager
+        .resolve_report(&report.id, ReportAction::Ban, None)
+        .await
+        .expect("report resolved");
+    let mut message = room_action(&room_id, "BROADCAST");
+    message["data"] = json!({ "type": "MESSAGE", "message": "far too long to be sent" });
+    alice.exec(&message).await;
+
+    for action in [
+        "ROOM_ROLE",
+        "ROOM_KICK",
+        "ROOM_PASSWORD",
+        "ROOM_BAN",
+        "MESSAGE_REJECTED",
+    ] {
+        assert_eq!(recorded(&manager, &room_id, action).await, 1, "{}", action);
+    }
+    let query = AuditQuery {
+        action: Some("MESSAGE_REJECTED".to_string()),
+        ..Default::default()
+    };
+    let rejected = manager.query_audit(&query).await.unwrap().remove(0);
+    assert_eq!(rejected.actor, alice.get_id().to_string());
+    assert_eq!(rejected.details["filter"], "max_length");
+}
+
+#[tokio::test]
+async fn blocked_messages_are_audited_and_not_delivered() {
+    let mut config = ChatConfig::default();
+    config.moderation.profanity = vec!["darn".to_string()];
+    config.moderation.profanity_action = FilterMode::Reject;
+    let manager = ChatManager::new(config).await;
+    let (alice, mut alice_events) = connect(&manager).await;
+    let (bob, mut bob_events) = connect(&manager).await;
+    let room = manager
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+    let room_id = *room.get_id();
+    bob.join_room(&room_id).await.expect("joined");
+
+    for text in ["darn it", "fine"] {
+        let mut message = room_action(&room_id, "BROADCAST");
+        message["data"] = json!({ "type": "MESSAGE", "message": text });
+        alice.exec(&message).await;
+    }
+    let delivered = next_matching(&mut bob_events, |value| value["type"] == "MESSAGE").await;
+    assert_eq!(delivered["message"], "fine");
+    // The sender is told, which `next_matching` does not allow
+    let error = timeout(WAIT, async {
+        loop {
+            let frame = alice_events.recv().await.expect("alice connected");
+            if frame.value()["event"]["type"] == "ERROR" {
+                return frame.value().clone();
+            }
+        }
+    })
+    .await
+    .expect("no error");
+    assert_eq!(error["event"]["error"], "MESSAGE_REJECTED");
+
+    assert_eq!(recorded(&manager, &room_id, "MESSAGE_REJECTED").await, 1);
+    let records = manager.get_moderation_records().await;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].filter, "profanity");
+    assert_eq!(records[0].text, "darn it");
+}
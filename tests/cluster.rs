@@ -0,0 +1,92 @@
+use std::{sync::Arc, time::Duration};
+
+use chat_engine::{
+    api::chat::{backplane::LocalBackplane, search::SearchQuery, ChatManager},
+    config::ChatConfig,
+};
+use serde_json::json;
+use tokio::time::{sleep, timeout};
+
+use common::{connect, next_matching, room_action, WAIT};
+
+mod common;
+
+/// Waits for the other engine to catch up with the backplane.
+async fn until<F: std::future::Future<Output = bool>>(condition: impl Fn() -> F) {
+    timeout(WAIT, async {
+        while !condition().await {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("condition not met");
+}
+
+#[tokio::test]
+async fn message_state_is_shared_between_engines() {
+    let backplane = Arc::new(LocalBackplane::default());
+    let first = ChatManager::with_backplane(ChatConfig::default(), backplane.clone()).await;
+    let second = ChatManager::with_backplane(ChatConfig::default(), backplane).await;
+    // Both engines subscribe from tasks of their own
+    sleep(Duration::from_millis(50)).await;
+
+    let (alice, mut alice_events) = connect(&first).await;
+    let (bob, mut bob_events) = connect(&second).await;
+    let room = first
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+    let room_id = *room.get_id();
+    until(|| async { second.get_room(&room_id).await.is_some() }).await;
+    bob.join_room(&room_id).await.expect("bob joins");
+    until(|| async { room.get_clients_list().await.contains(bob.get_id()) }).await;
+
+    let mut message = room_action(&room_id, "BROADCAST");
+    message["data"] = json!({ "type": "MESSAGE", "message": "hello from the first node" });
+    alice.exec(&message).await;
+    let sent = next_matching(&mut bob_events, |value| value["type"] == "MESSAGE").await;
+    let message_id = sent["id"].as_str().unwrap().to_string();
+
+    // Bob acts on a message sent to the other node
+    let mut reply = room_action(&room_id, "BROADCAST");
+    reply["data"] = json!({ "type": "MESSAGE", "message": "hi" });
+    reply["reply_to"] = json!(message_id);
+    bob.exec(&reply).await;
+    let mut react = room_action(&room_id, "MESSAGE_REACT");
+    react["message_id"] = json!(message_id);
+    react["reaction"] = json!("wave");
+    bob.exec(&react).await;
+    next_matching(&mut alice_events, |value| {
+        value["event"]["type"] == "MESSAGE_REACT" && value["event"]["count"] == 1
+    })
+    .await;
+
+    // Alice edits it on her node and sees Bob's reply and reaction there
+    let mut edit = room_action(&room_id, "MESSAGE_EDIT");
+    edit["message_id"] = json!(message_id);
+    edit["data"] = json!({ "message": "hello again" });
+    alice.exec(&edit).await;
+    next_matching(&mut bob_events, |value| {
+        value["event"]["type"] == "MESSAGE_EDIT"
+            && value["event"]["data"]["reactions"]["wave"][0] == bob.get_id().to_string()
+    })
+    .await;
+    let mut thread = room_action(&room_id, "THREAD_HISTORY");
+    thread["thread_id"] = json!(message_id);
+    alice.exec(&thread).await;
+    let thread = next_matching(&mut alice_events, |value| {
+        value["event"]["type"] == "THREAD_HISTORY"
+    })
+    .await;
+    let messages = thread["event"]["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0]["reply_count"], 1);
+    assert_eq!(messages[1]["message"], "hi");
+
+    // The edit reached the search index of the other node
+    let query = SearchQuery {
+        query: Some("again".to_string()),
+        ..Default::default()
+    };
+    until(|| async { second.search(&query).await.len() == 1 }).await;
+}
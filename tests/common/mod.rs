@@ -0,0 +1,53 @@
+// Each test crate uses its own subset of the helpers
+#![allow(dead_code)]
+
+use std::{sync::Arc, time::Duration};
+
+use chat_engine::api::chat::{
+    client::{ClientSender, WebSocketClient},
+    wire::Frame,
+    ChatManager,
+};
+use serde_json::{json, Value};
+use tokio::{
+    sync::mpsc::{self, Receiver},
+    time::timeout,
+};
+use uuid::Uuid;
+
+pub const WAIT: Duration = Duration::from_secs(2);
+
+/// A client receiving its events on the returned channel, which has to be
+/// kept open for the client to stay connected.
+pub async fn connect(manager: &ChatManager) -> (Arc<WebSocketClient>, Receiver<Arc<Frame>>) {
+    let (sender, receiver) = mpsc::channel(256);
+    let client = manager
+        .create_client(ClientSender::EventStream(sender), None)
+        .await;
+    (client, receiver)
+}
+
+/// The next value sent to a client matching `predicate`, skipping the others.
+pub async fn next_matching(
+    receiver: &mut Receiver<Arc<Frame>>,
+    predicate: impl Fn(&Value) -> bool,
+) -> Value {
+    timeout(WAIT, async {
+        loop {
+            let frame = receiver.recv().await.expect("client disconnected");
+            assert_ne!(frame.value()["event"]["type"], "ERROR", "{}", frame.json());
+            if predicate(frame.value()) {
+                return frame.value().clone();
+            }
+        }
+    })
+    .await
+    .expect("no matching event")
+}
+
+pub fn room_action(room_id: &Uuid, action: &str) -> Value {
+    json!({
+        "action": action,
+        "target": { "type": "ROOM", "id": room_id.to_string() }
+    })
+}
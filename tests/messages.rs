@@ -0,0 +1,177 @@
+use chat_engine::{
+    api::chat::{search::SearchQuery, ChatManager},
+    config::ChatConfig,
+};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use common::{connect, next_matching, room_action};
+
+mod common;
+
+fn assert_unset(value: &Value, keys: &[&str]) {
+    for key in keys {
+        assert!(value.get(key).is_none(), "{} set in {}", key, value);
+    }
+}
+
+#[tokio::test]
+async fn clients_cannot_spoof_server_assigned_keys() {
+    let manager = ChatManager::new(ChatConfig::default()).await;
+    let (alice, _alice_events) = connect(&manager).await;
+    let (bob, mut bob_events) = connect(&manager).await;
+    let room = manager
+        .create_room(vec![alice.get_id(), bob.get_id()])
+        .await
+        .expect("room created");
+    let room_id = *room.get_id();
+
+    let mut message = room_action(&room_id, "BROADCAST");
+    message["data"] = json!({
+        "type": "MESSAGE",
+        "message": "hello",
+        "sender": bob.get_id().to_string(),
+        "deleted": true,
+        "reactions": { "wave": [bob.get_id().to_string()] },
+        "edited_at": 1
+    });
+    alice.exec(&message).await;
+    let sent = next_matching(&mut bob_events, |value| value["type"] == "MESSAGE").await;
+    assert_eq!(sent["message"], "hello");
+    assert_eq!(sent["sender"], alice.get_id().to_string());
+    assert_unset(&sent, &["deleted", "reactions", "edited_at"]);
+
+    let mut edit = room_action(&room_id, "MESSAGE_EDIT");
+    edit["message_id"] = sent["id"].clone();
+    edit["data"] = json!({
+        "message": "hello again",
+        "deleted": true,
+        "reactions": { "wave": [bob.get_id().to_string()] }
+    });
+    alice.exec(&edit).await;
+    let edited = next_matching(&mut bob_events, |value| {
+        value["event"]["type"] == "MESSAGE_EDIT"
+    })
+    .await;
+    let edited = &edited["event"]["data"];
+    assert_eq!(edited["message"], "hello again");
+    assert_eq!(edited["id"], sent["id"]);
+    assert_unset(edited, &["deleted", "reactions"]);
+}
+
+#[tokio::test]
+async fn server_messages_cannot_claim_a_sender() {
+    let manager = ChatManager::new(ChatConfig::default()).await;
+    let (alice, mut alice_events) = connect(&manager).await;
+    let room = manager
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+
+    room.exec(
+        &json!({
+            "action": "BROADCAST",
+            "data": {
+                "type": "MESSAGE",
+                "message": "build passed",
+                "sender": alice.get_id().to_string(),
+                "seq": 42
+            }
+        }),
+        None,
+    )
+    .await
+    .expect("message posted");
+    let sent = next_matching(&mut alice_events, |value| value["type"] == "MESSAGE").await;
+    assert_eq!(sent["message"], "build passed");
+    assert_eq!(sent["seq"], 1);
+    assert_unset(&sent, &["sender"]);
+}
+
+#[tokio::test]
+async fn replies_cannot_spoof_their_thread() {
+    let manager = ChatManager::new(ChatConfig::default()).await;
+    let (alice, mut alice_events) = connect(&manager).await;
+    let (bob, mut bob_events) = connect(&manager).await;
+    let room = manager
+        .create_room(vec![alice.get_id(), bob.get_id()])
+        .await
+        .expect("room created");
+    let room_id = *room.get_id();
+    let elsewhere = Uuid::new_v4().to_string();
+
+    let mut root = room_action(&room_id, "BROADCAST");
+    root["data"] = json!({
+        "type": "MESSAGE",
+        "message": "root",
+        "thread_id": elsewhere,
+        "reply_count": 7
+    });
+    alice.exec(&root).await;
+    let root = next_matching(&mut bob_events, |value| value["type"] == "MESSAGE").await;
+    assert_unset(&root, &["thread_id", "reply_to", "reply_count"]);
+
+    let mut reply = room_action(&room_id, "BROADCAST");
+    reply["reply_to"] = root["id"].clone();
+    reply["data"] = json!({
+        "type": "MESSAGE",
+        "message": "reply",
+        "reply_to": elsewhere,
+        "thread_id": elsewhere,
+        "reply_count": 3
+    });
+    bob.exec(&reply).await;
+    let reply = next_matching(&mut alice_events, |value| value["message"] == "reply").await;
+    assert_eq!(reply["reply_to"], root["id"]);
+    assert_eq!(reply["thread_id"], root["id"]);
+    assert_unset(&reply, &["reply_count"]);
+    let update = next_matching(&mut alice_events, |value| {
+        value["event"]["type"] == "THREAD_UPDATE"
+    })
+    .await;
+    assert_eq!(update["event"]["thread_id"], root["id"]);
+    assert_eq!(update["event"]["reply_count"], 1);
+
+    let mut thread = room_action(&room_id, "THREAD_HISTORY");
+    thread["thread_id"] = root["id"].clone();
+    alice.exec(&thread).await;
+    let thread = next_matching(&mut alice_events, |value| {
+        value["event"]["type"] == "THREAD_HISTORY"
+    })
+    .await;
+    let messages = thread["event"]["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0]["reply_count"], 1);
+    assert_unset(&messages[0], &["thread_id"]);
+    assert_eq!(messages[1]["thread_id"], root["id"]);
+}
+
+#[tokio::test]
+async fn deleted_messages_leave_the_search_index() {
+    let manager = ChatManager::new(ChatConfig::default()).await;
+    let (alice, mut alice_events) = connect(&manager).await;
+    let room = manager
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+    let room_id = *room.get_id();
+    let query = SearchQuery {
+        query: Some("quarterly".to_string()),
+        ..Default::default()
+    };
+
+    let mut message = room_action(&room_id, "BROADCAST");
+    message["data"] = json!({ "type": "MESSAGE", "message": "Quarterly numbers" });
+    alice.exec(&message).await;
+    let sent = next_matching(&mut alice_events, |value| value["type"] == "MESSAGE").await;
+    assert_eq!(manager.search(&query).await.len(), 1);
+
+    let mut delete = room_action(&room_id, "MESSAGE_DELETE");
+    delete["message_id"] = sent["id"].clone();
+    alice.exec(&delete).await;
+    next_matching(&mut alice_events, |value| {
+        value["event"]["type"] == "MESSAGE_DELETE"
+    })
+    .await;
+    assert!(manager.search(&query).await.is_empty());
+}
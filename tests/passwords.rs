@@ -0,0 +1,143 @@
+use std::{sync::Arc, time::Duration};
+
+use chat_engine::{
+    api::chat::{client::WebSocketClient, room::RoomError, wire::Frame, ChatManager},
+    config::ChatConfig,
+};
+use serde_json::json;
+use tokio::{sync::mpsc::Receiver, time::sleep};
+use uuid::Uuid;
+
+use common::{connect, room_action};
+
+mod common;
+
+const PASSWORD: &str = "hunter2";
+
+async fn manager() -> ChatManager {
+    let mut config = ChatConfig::default();
+    config.limits.max_password_attempts = 3;
+    config.limits.password_lockout = 1;
+    ChatManager::new(config).await
+}
+
+/// A room owned by `owner` protected by `PASSWORD`.
+async fn protected_room(manager: &ChatManager, owner: &WebSocketClient) -> Uuid {
+    let room = manager
+        .create_room(vec![owner.get_id()])
+        .await
+        .expect("room created");
+    let mut password = room_action(room.get_id(), "ROOM_PASSWORD");
+    password["password"] = json!(PASSWORD);
+    owner.exec(&password).await;
+    *room.get_id()
+}
+
+fn assert_error(result: Result<(), RoomError>, expected: RoomError) {
+    match result {
+        Err(error) => assert_eq!(error.code(), expected.code()),
+        Ok(()) => panic!("joined, expected {}", expected.code()),
+    }
+}
+
+#[tokio::test]
+async fn wrong_passwords_lock_the_client_out() {
+    let manager = manager().await;
+    let (alice, _alice_events) = connect(&manager).await;
+    let (bob, _bob_events) = connect(&manager).await;
+    let room_id = protected_room(&manager, &alice).await;
+
+    assert_error(bob.join_room(&room_id).await, RoomError::PasswordRequired);
+    for _ in 0..3 {
+        assert_error(
+            bob.join_room_with_password(&room_id, Some("hunter3")).await,
+            RoomError::InvalidPassword,
+        );
+    }
+    assert_error(
+        bob.join_room_with_password(&room_id, Some(PASSWORD)).await,
+        RoomError::PasswordLocked,
+    );
+
+    sleep(Duration::from_millis(1100)).await;
+    bob.join_room_with_password(&room_id, Some(PASSWORD))
+        .await
+        .expect("joined after the lockout");
+    let room = manager.get_room(&room_id).await.unwrap();
+    assert!(room.has_client(bob.get_id()).await);
+}
+
+#[tokio::test]
+async fn members_and_moderators_skip_the_password() {
+    let manager = manager().await;
+    let (alice, _alice_events) = connect(&manager).await;
+    let (bob, _bob_events) = connect(&manager).await;
+    let (carol, _carol_events) = connect(&manager).await;
+    let room_id = protected_room(&manager, &alice).await;
+
+    alice.join_room(&room_id).await.expect("owner rejoins");
+    for client in [&bob, &carol] {
+        client
+            .join_room_with_password(&room_id, Some(PASSWORD))
+            .await
+            .expect("joined with the password");
+        client.join_room(&room_id).await.expect("member rejoins");
+    }
+    let mut op = room_action(&room_id, "COMMAND");
+    op["name"] = json!("op");
+    op["args"] = json!(carol.get_id().to_string());
+    alice.exec(&op).await;
+
+    // Leaving forgets the membership but not the role
+    bob.leave_room(&room_id).await;
+    carol.leave_room(&room_id).await;
+    assert_error(bob.join_room(&room_id).await, RoomError::PasswordRequired);
+    carol.join_room(&room_id).await.expect("moderator rejoins");
+}
+
+#[tokio::test]
+async fn resumed_clients_skip_the_password_of_their_rooms_only() {
+    let manager = manager().await;
+    let (alice, _alice_events) = connect(&manager).await;
+    let protected = protected_room(&manager, &alice).await;
+    let open = manager
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+    let open = *open.get_id();
+
+    let (dave, _dave_events) = connect(&manager).await;
+    dave.join_room_with_password(&protected, Some(PASSWORD))
+        .await
+        .expect("joined with the password");
+    let (frank, _frank_events) = connect(&manager).await;
+    frank.join_room(&open).await.expect("joined the open room");
+    let dave_token = manager.get_client_token(dave.get_id());
+    let frank_token = manager.get_client_token(frank.get_id());
+    manager.remove_client(dave.get_id()).await;
+    manager.remove_client(frank.get_id()).await;
+
+    let (resumed, _resumed_events) = resume(&manager, &dave_token).await;
+    let room = manager.get_room(&protected).await.unwrap();
+    assert!(room.has_client(resumed.get_id()).await);
+
+    // Resuming the open room says nothing about the protected one
+    let (resumed, _resumed_events) = resume(&manager, &frank_token).await;
+    let open = manager.get_room(&open).await.unwrap();
+    assert!(open.has_client(resumed.get_id()).await);
+    assert!(!room.has_client(resumed.get_id()).await);
+    assert_error(
+        resumed.join_room(&protected).await,
+        RoomError::PasswordRequired,
+    );
+}
+
+/// A new connection resuming the one `token` was issued to.
+async fn resume(
+    manager: &ChatManager,
+    token: &str,
+) -> (Arc<WebSocketClient>, Receiver<Arc<Frame>>) {
+    let (client, events) = connect(manager).await;
+    client.resume(token).await.expect("connection resumed");
+    (client, events)
+}
@@ -0,0 +1,128 @@
+use std::{sync::Arc, time::Duration};
+
+use chat_engine::{
+    api::chat::{
+        room::{RoomError, RoomState},
+        ChatManager,
+    },
+    config::{ChatConfig, RoomConfig},
+};
+use tokio::{spawn, time::sleep};
+use uuid::Uuid;
+
+use common::connect;
+
+mod common;
+
+const ROUNDS: usize = 200;
+const JOINERS: usize = 4;
+
+/// The last member leaves, closing the room, while other clients join it: a
+/// join either fails or lands in the room that stays open and registered.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn joins_never_land_in_a_closing_room() {
+    let manager = ChatManager::new(ChatConfig::default()).await;
+    let mut joined = 0;
+    for _ in 0..ROUNDS {
+        let (owner, _owner_events) = connect(&manager).await;
+        let room = manager
+            .create_room(vec![owner.get_id()])
+            .await
+            .expect("room created");
+        let room_id = *room.get_id();
+        let mut joiners = Vec::with_capacity(JOINERS);
+        for _ in 0..JOINERS {
+            joiners.push(connect(&manager).await);
+        }
+
+        let leave = spawn(async move { owner.leave_room(&room_id).await });
+        let joins: Vec<_> = joiners
+            .iter()
+            .map(|(client, _)| {
+                let client = Arc::clone(client);
+                spawn(async move { client.join_room(&room_id).await })
+            })
+            .collect();
+        leave.await.unwrap();
+
+        for ((client, _), join) in joiners.iter().zip(joins) {
+            match join.await.unwrap() {
+                Ok(()) => {
+                    joined += 1;
+                    let current = manager
+                        .get_room(&room_id)
+                        .await
+                        .expect("joined a removed room");
+                    assert!(Arc::ptr_eq(&current, &room));
+                    assert_eq!(room.get_state().await, RoomState::Open);
+                    assert!(room.has_client(client.get_id()).await);
+                }
+                Err(error) => assert!(
+                    matches!(error, RoomError::Closed | RoomError::NotFound),
+                    "{:?}",
+                    error
+                ),
+            }
+        }
+        if room.get_clients_list().await.is_empty() {
+            assert_ne!(room.get_state().await, RoomState::Open);
+            assert!(manager.get_room(&room_id).await.is_none());
+        }
+    }
+    println!("{} of {} joins landed", joined, ROUNDS * JOINERS);
+}
+
+/// Empty rooms are removed `room_idle_ttl` after the last member left,
+/// unless someone joined meanwhile or they are persistent.
+#[tokio::test(start_paused = true)]
+async fn idle_rooms_close_after_the_ttl() {
+    let lobby = Uuid::new_v4();
+    let config = ChatConfig {
+        room_idle_ttl: 60,
+        rooms: vec![RoomConfig {
+            name: "lobby".to_string(),
+            id: Some(lobby),
+            max_members: None,
+            read_receipts: true,
+        }],
+        ..Default::default()
+    };
+    let manager = ChatManager::new(config).await;
+    let (alice, _alice_events) = connect(&manager).await;
+    let (bob, _bob_events) = connect(&manager).await;
+    let idle = manager
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+    let idle = *idle.get_id();
+    let rejoined = manager
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+    let rejoined = *rejoined.get_id();
+    alice.join_room(&lobby).await.expect("joined the lobby");
+
+    for room_id in [&idle, &rejoined, &lobby] {
+        alice.leave_room(room_id).await;
+    }
+    sleep(Duration::from_secs(30)).await;
+    bob.join_room(&rejoined)
+        .await
+        .expect("joined before the ttl");
+    sleep(Duration::from_secs(29)).await;
+    assert!(manager.get_room(&idle).await.is_some());
+    sleep(Duration::from_secs(2)).await;
+    assert!(manager.get_room(&idle).await.is_none());
+    assert!(manager.get_room(&rejoined).await.is_some());
+
+    // The close scheduled by the first leave no longer applies
+    bob.leave_room(&rejoined).await;
+    sleep(Duration::from_secs(59)).await;
+    assert!(manager.get_room(&rejoined).await.is_some());
+    sleep(Duration::from_secs(2)).await;
+    assert!(manager.get_room(&rejoined).await.is_none());
+
+    sleep(Duration::from_secs(3600)).await;
+    let lobby = manager.get_room(&lobby).await.expect("lobby kept");
+    assert_eq!(lobby.get_state().await, RoomState::Open);
+}
@@ -0,0 +1,57 @@
+use chat_engine::{
+    api::chat::{room::RoomState, ChatManager},
+    config::ChatConfig,
+};
+use uuid::Uuid;
+
+use common::{connect, next_matching, room_action};
+
+mod common;
+
+/// Rooms the admin API counts for `client_id`.
+async fn room_count(manager: &ChatManager, client_id: &Uuid) -> u64 {
+    manager
+        .get_clients_stats()
+        .await
+        .iter()
+        .find(|stats| stats["client_id"] == client_id.to_string())
+        .and_then(|stats| stats["rooms"].as_u64())
+        .expect("client listed")
+}
+
+#[tokio::test]
+async fn leaving_forgets_the_membership_and_closes_empty_rooms() {
+    let manager = ChatManager::new(ChatConfig::default()).await;
+    let (alice, mut alice_events) = connect(&manager).await;
+    let (bob, mut bob_events) = connect(&manager).await;
+    let room = manager
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+    let room_id = *room.get_id();
+    bob.join_room(&room_id).await.expect("joined");
+    assert_eq!(room_count(&manager, alice.get_id()).await, 1);
+    assert!(room.get_unread(alice.get_id()).await.is_some());
+
+    alice.exec(&room_action(&room_id, "ROOM_LEAVE")).await;
+    let exit = next_matching(&mut alice_events, |value| {
+        value["event"]["type"] == "ROOM_EXIT"
+    })
+    .await;
+    assert_eq!(exit["event"]["room_id"], room_id.to_string());
+    assert_eq!(room_count(&manager, alice.get_id()).await, 0);
+    assert!(!room.has_client(alice.get_id()).await);
+    assert!(room.get_unread(alice.get_id()).await.is_none());
+    assert_eq!(room.get_clients_list().await, vec![*bob.get_id()]);
+    assert_eq!(room.get_state().await, RoomState::Open);
+
+    // The last member leaving closes the room
+    bob.exec(&room_action(&room_id, "ROOM_LEAVE")).await;
+    next_matching(&mut bob_events, |value| {
+        value["event"]["type"] == "ROOM_EXIT"
+    })
+    .await;
+    assert_eq!(room_count(&manager, bob.get_id()).await, 0);
+    assert_eq!(room.get_state().await, RoomState::Closed);
+    assert!(manager.get_room(&room_id).await.is_none());
+}
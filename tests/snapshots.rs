@@ -0,0 +1,114 @@
+use std::{sync::Arc, time::Duration};
+
+use chat_engine::{
+    api::chat::{
+        report::ReportAction,
+        room::{RoomError, RoomRole},
+        snapshot::{FileSnapshotStore, SnapshotStore},
+        ChatManager,
+    },
+    config::ChatConfig,
+};
+use serde_json::json;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use common::{connect, next_matching, room_action};
+
+mod common;
+
+const PASSWORD: &str = "hunter2";
+
+fn config() -> ChatConfig {
+    let mut config = ChatConfig::default();
+    config.snapshot.interval = 0;
+    config.snapshot.restore_grace = 1;
+    config
+}
+
+#[tokio::test]
+async fn rooms_are_restored_from_the_last_snapshot() {
+    let path = std::env::temp_dir().join(format!("chat-engine-snapshot-{}.json", Uuid::new_v4()));
+    let store = Arc::new(FileSnapshotStore::new(path.clone()));
+
+    let first = ChatManager::with_snapshot_store(config(), store.clone()).await;
+    let (alice, mut alice_events) = connect(&first).await;
+    let (bob, _bob_events) = connect(&first).await;
+    let (carol, _carol_events) = connect(&first).await;
+    let room = first
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+    let room_id = *room.get_id();
+    let idle = first
+        .create_room(vec![alice.get_id()])
+        .await
+        .expect("room created");
+    let idle_id = *idle.get_id();
+    for client in [&bob, &carol] {
+        client.join_room(&room_id).await.expect("joined");
+    }
+    let mut op = room_action(&room_id, "COMMAND");
+    op["name"] = json!("op");
+    op["args"] = json!(bob.get_id().to_string());
+    alice.exec(&op).await;
+    let mut password = room_action(&room_id, "ROOM_PASSWORD");
+    password["password"] = json!(PASSWORD);
+    alice.exec(&password).await;
+    let mut report = room_action(&room_id, "REPORT_CLIENT");
+    report["client_id"] = json!(carol.get_id().to_string());
+    report["reason"] = json!("spam");
+    alice.exec(&report).await;
+    next_matching(&mut alice_events, |value| {
+        value["event"]["type"] == "REPORT_CREATED"
+    })
+    .await;
+    let report = first.get_reports(None).await.remove(0);
+    first
+        .resolve_report(&report.id, ReportAction::Ban, None)
+        .await
+        .expect("carol banned");
+    assert_eq!(room.get_role(bob.get_id()).await, Some(RoomRole::Moderator));
+
+    // A periodic save running along the one on shutdown
+    tokio::join!(first.save_snapshot(), first.save_snapshot());
+    let snapshot = store.load().await.unwrap().expect("snapshot saved");
+    assert_eq!(snapshot.rooms.len(), 2);
+    let saved = snapshot
+        .rooms
+        .iter()
+        .find(|snapshot| snapshot.id == room_id)
+        .unwrap();
+    let hash = saved.password.as_deref().expect("password saved");
+    assert!(hash.starts_with("$argon2"));
+    assert!(!hash.contains(PASSWORD));
+
+    let second = ChatManager::with_snapshot_store(config(), store).await;
+    let restored = second.get_room(&room_id).await.expect("room restored");
+    assert_eq!(
+        restored.get_role(alice.get_id()).await,
+        Some(RoomRole::Owner)
+    );
+    assert_eq!(
+        restored.get_role(bob.get_id()).await,
+        Some(RoomRole::Moderator)
+    );
+    assert!(restored.is_banned(carol.get_id()).await);
+    assert!(restored.get_clients_list().await.is_empty());
+
+    let (dave, _dave_events) = connect(&second).await;
+    match dave.join_room(&room_id).await {
+        Err(error) => assert_eq!(error.code(), RoomError::PasswordRequired.code()),
+        Ok(()) => panic!("joined without the password"),
+    }
+    dave.join_room_with_password(&room_id, Some(PASSWORD))
+        .await
+        .expect("joined with the restored password");
+
+    // Rooms nobody joins within the grace period are removed
+    assert!(second.get_room(&idle_id).await.is_some());
+    sleep(Duration::from_millis(1500)).await;
+    assert!(second.get_room(&idle_id).await.is_none());
+    assert!(second.get_room(&room_id).await.is_some());
+    let _ = std::fs::remove_file(path);
+}
@@ -1,16 +1,95 @@
 let onopen = function (event) {
   console.log("WebSocket is open now.", this);
 };
-let onmessage = function ({ message, sender, room }) {
-  console.log(`message ${message} in room ${room} from ${sender}.`);
+let onmessage = function (data) {
+  let { id, seq, message, sender, room, timestamp, reply_to, thread_id } = data;
+  let attachments = (data.attachments || []).map((attachment) => ({
+    ...attachment,
+    url: this.attachment_url(attachment.id),
+  }));
+  console.log(`message ${id} ${message} in room ${room} from ${sender}.`);
   this.dispatchEvent(
-    new CustomEvent("message", { detail: { message, sender, room } }),
+    new CustomEvent("message", {
+      detail: {
+        id,
+        seq,
+        message,
+        sender,
+        room,
+        timestamp,
+        reply_to,
+        thread_id,
+        attachments,
+      },
+    }),
+  );
+};
+let onthreadhistory = function ({ room_id, thread_id, messages }) {
+  console.log(`thread ${thread_id} history in room ${room_id}`, messages);
+  this.dispatchEvent(
+    new CustomEvent("threadhistory", {
+      detail: { room_id, thread_id, messages },
+    }),
+  );
+};
+let onthreadupdate = function ({ room_id, thread_id, reply_count }) {
+  console.log(`thread ${thread_id} has ${reply_count} replies`);
+  this.dispatchEvent(
+    new CustomEvent("threadupdate", {
+      detail: { room_id, thread_id, reply_count },
+    }),
+  );
+};
+let onmessageedit = function ({ room_id, message_id, data }) {
+  console.log(`message ${message_id} edited in room ${room_id}`);
+  this.dispatchEvent(
+    new CustomEvent("messageedit", { detail: { room_id, message_id, data } }),
+  );
+};
+let onmessagedelete = function ({ room_id, message_id }) {
+  console.log(`message ${message_id} deleted in room ${room_id}`);
+  this.dispatchEvent(
+    new CustomEvent("messagedelete", { detail: { room_id, message_id } }),
+  );
+};
+let onmessagereaction = function (event) {
+  let { type, room_id, message_id, client_id, reaction, count } = event;
+  console.log(`message ${message_id} ${type} ${reaction} by ${client_id}`);
+  this.dispatchEvent(
+    new CustomEvent("messagereaction", {
+      detail: {
+        room_id,
+        message_id,
+        client_id,
+        reaction,
+        count,
+        added: type == "MESSAGE_REACT",
+      },
+    }),
   );
 };
 let onroomslist = function (rooms) {
   console.log("rooms list", rooms);
   this.dispatchEvent(new CustomEvent("roomslist", { detail: rooms }));
 };
+let onroomunread = function ({ room_id, unread, last_seq }) {
+  console.log(`room ${room_id} has ${unread} unread messages`);
+  this.dispatchEvent(
+    new CustomEvent("roomunread", { detail: { room_id, unread, last_seq } }),
+  );
+};
+let onreadreceipt = function ({ room_id, client_id, seq }) {
+  console.log(`client ${client_id} read room ${room_id} up to ${seq}`);
+  this.dispatchEvent(
+    new CustomEvent("readreceipt", { detail: { room_id, client_id, seq } }),
+  );
+};
+let onsearchresults = function ({ query, results }) {
+  console.log(`search ${query} found ${results.length} messages`);
+  this.dispatchEvent(
+    new CustomEvent("searchresults", { detail: { query, results } }),
+  );
+};
 let onroomclientslist = function (clients) {
   console.log("room clients list", clients);
   this.dispatchEvent(
@@ -37,8 +116,26 @@ let onroomexit = function ({ room_id, client_id }) {
     new CustomEvent("roomexit", { detail: { room_id, client_id } }),
   );
 };
+let onerror = function ({ action, error, room_id }) {
+  console.log(`${action} failed with ${error} for room ${room_id}`);
+  this.dispatchEvent(
+    new CustomEvent("error", { detail: { action, error, room_id } }),
+  );
+};
+let oncommandevent = function (event) {
+  console.log(`command event ${event.type} in room ${event.room_id}`, event);
+  this.dispatchEvent(new CustomEvent("command", { detail: event }));
+};
 let onjoin = function (event) {
   let client_id = event.client_id;
+  this.client_id = client_id;
+  this.session_id = event.session_id;
+  this.token = event.token;
+  // Rejoins the rooms of the previous connection of this tab, with their
+  // unread counts
+  let previous = sessionStorage.getItem("chat-token");
+  if (previous) send.call(this, { action: "CLIENT_RESUME", token: previous });
+  sessionStorage.setItem("chat-token", event.token);
   console.log(`your client id is ${client_id}.`);
   this.dispatchEvent(new CustomEvent("join", { detail: { client_id } }));
   // this.create_room();
@@ -54,11 +151,28 @@ let onreceive = function (event) {
         onroomexit.call(this, { room_id, client_id });
         break;
       }
+      case "ERROR": {
+        let { action, error, room_id } = event;
+        onerror.call(this, { action, error, room_id });
+        break;
+      }
       case "CLIENT_JOIN":
         onjoin.call(this, event);
         break;
       case "ROOMS_LIST":
         onroomslist.call(this, event.rooms);
+        for (let [room_id, unread] of Object.entries(event.unread || {})) {
+          onroomunread.call(this, { room_id, unread });
+        }
+        break;
+      case "SEARCH_RESULTS":
+        onsearchresults.call(this, event);
+        break;
+      case "ROOM_UNREAD":
+        onroomunread.call(this, event);
+        break;
+      case "READ_RECEIPT":
+        onreadreceipt.call(this, event);
         break;
       case "ROOM_CLIENTS_LIST":
         onroomclientslist.call(this, event.clients, event.room_id);
@@ -74,26 +188,72 @@ let onreceive = function (event) {
       case "ROOM_CREATION":
         onroomcreation.call(this, event.room_id);
         break;
+      case "THREAD_HISTORY":
+        onthreadhistory.call(this, event);
+        break;
+      case "THREAD_UPDATE":
+        onthreadupdate.call(this, event);
+        break;
+      case "MESSAGE_EDIT":
+        onmessageedit.call(this, event);
+        break;
+      case "MESSAGE_DELETE":
+        onmessagedelete.call(this, event);
+        break;
+      case "MESSAGE_REACT":
+      case "MESSAGE_UNREACT":
+        onmessagereaction.call(this, event);
+        break;
+      case "COMMAND_HELP":
+      case "NICK_CHANGE":
+      case "ROOM_TOPIC":
+      case "ROOM_INVITE":
+      case "ROOM_KICK":
+      case "ROOM_WHO":
+        oncommandevent.call(this, event);
+        break;
     }
   } else if (data.type == "MESSAGE") {
     console.log("DEBUG", data);
-    let { sender, message, room } = data;
-    onmessage.call(this, { sender, message, room });
+    onmessage.call(this, data);
   }
 };
 let send = function (data) {
   if (typeof data == "object") data = JSON.stringify(data);
-  this.socket.send(data);
+  if (this.events) {
+    let url = this.http_url("/actions");
+    url.searchParams.set("session_id", this.session_id);
+    fetch(url, {
+      method: "POST",
+      headers: { "content-type": "application/json" },
+      body: data,
+    });
+  } else {
+    this.socket.send(data);
+  }
 };
 
 export default class Client extends EventTarget {
+  // An http(s) url uses server-sent events instead of a websocket
   constructor(url) {
     super();
     this.url = url;
-    this.socket = new WebSocket(url);
-    this.socket.onopen = onopen.bind(this);
-    this.socket.onmessage = onreceive.bind(this);
+    if (url.startsWith("http")) {
+      this.events = new EventSource(this.http_url("/events"));
+      this.events.onopen = onopen.bind(this);
+      this.events.onmessage = onreceive.bind(this);
+    } else {
+      this.socket = new WebSocket(url);
+      this.socket.onopen = onopen.bind(this);
+      this.socket.onmessage = onreceive.bind(this);
+    }
   }
+  http_url = function (path) {
+    let url = new URL(path, this.url);
+    if (url.protocol == "ws:") url.protocol = "http:";
+    if (url.protocol == "wss:") url.protocol = "https:";
+    return url;
+  };
   create_room = function () {
     send.call(this, {
       action: "ROOM_CREATE",
@@ -105,6 +265,12 @@ export default class Client extends EventTarget {
       room_id: room_id,
     });
   };
+  leave_room = function (room_id) {
+    send.call(this, {
+      action: "ROOM_LEAVE",
+      room_id: room_id,
+    });
+  };
   subscribe_rooms = function () {
     send.call(this, {
       action: "ROOMS_SUBSCRIBE",
@@ -126,7 +292,84 @@ export default class Client extends EventTarget {
       action: "ROOMS_LIST",
     });
   };
-  send_message_to_room = function (room_id, message) {
+  search = function (query, filters = {}) {
+    send.call(this, {
+      action: "SEARCH",
+      query: query,
+      ...filters,
+    });
+  };
+  mark_read = function (room_id, seq) {
+    let value = {
+      action: "MARK_READ",
+      target: { type: "ROOM", id: room_id },
+    };
+    if (seq !== undefined) value.seq = seq;
+    send.call(this, value);
+  };
+  reply_to_message = function (room_id, message_id, message) {
+    send.call(this, {
+      action: "BROADCAST",
+      target: { type: "ROOM", id: room_id },
+      reply_to: message_id,
+      data: { type: "MESSAGE", message: message },
+    });
+  };
+  get_thread_history = function (room_id, thread_id) {
+    send.call(this, {
+      action: "THREAD_HISTORY",
+      target: { type: "ROOM", id: room_id },
+      thread_id: thread_id,
+    });
+  };
+  edit_message = function (room_id, message_id, message) {
+    send.call(this, {
+      action: "MESSAGE_EDIT",
+      target: { type: "ROOM", id: room_id },
+      message_id: message_id,
+      data: { message: message },
+    });
+  };
+  delete_message = function (room_id, message_id) {
+    send.call(this, {
+      action: "MESSAGE_DELETE",
+      target: { type: "ROOM", id: room_id },
+      message_id: message_id,
+    });
+  };
+  react_to_message = function (room_id, message_id, reaction) {
+    send.call(this, {
+      action: "MESSAGE_REACT",
+      target: { type: "ROOM", id: room_id },
+      message_id: message_id,
+      reaction: reaction,
+    });
+  };
+  unreact_to_message = function (room_id, message_id, reaction) {
+    send.call(this, {
+      action: "MESSAGE_UNREACT",
+      target: { type: "ROOM", id: room_id },
+      message_id: message_id,
+      reaction: reaction,
+    });
+  };
+  attachment_url = function (attachment_id) {
+    let url = this.http_url(`/attachments/${attachment_id}`);
+    url.searchParams.set("token", this.token);
+    return url.toString();
+  };
+  upload_attachment = async function (room_id, file) {
+    let url = this.http_url("/attachments");
+    url.searchParams.set("token", this.token);
+    url.searchParams.set("room_id", room_id);
+    let form = new FormData();
+    form.append("file", file);
+    let response = await fetch(url, { method: "POST", body: form });
+    let body = await response.json();
+    if (!response.ok) throw new Error(body.error);
+    return body;
+  };
+  send_message_to_room = function (room_id, message, attachments) {
     let value = {
       action: "BROADCAST",
       target: {
@@ -138,6 +381,9 @@ export default class Client extends EventTarget {
         message: message,
       },
     };
+    if (attachments && attachments.length) {
+      value.data.attachments = attachments.map((attachment) => attachment.id);
+    }
     send.call(this, value);
   };
 }